@@ -19,7 +19,6 @@ use std::{
     time::Instant,
 };
 
-use chrono::prelude::*;
 use chrono::{DateTime, TimeZone, Utc};
 use crypto_market_type::MarketType;
 use crypto_msg_parser::{extract_symbol, extract_timestamp};
@@ -104,10 +103,10 @@ fn validate_line(line: &str) -> bool {
 /// - day `yyyy-MM-dd` string, all messages beyond [day-5min, day+5min] will be dropped
 /// - output_dir Where raw messages will be written to
 /// - splitted_files A HashMap that tracks opened files, key is `msg.symbol`, value is file of
-///  `output_dir/exchange.market_type.msg_type.symbol.hour.csv.gz`. Each `exchange, msg_type, market_type`
-///  has one `splitted_files` HashMap
+///   `output_dir/exchange.market_type.msg_type.symbol.hour.csv.gz`. Each `exchange, msg_type, market_type`
+///   has one `splitted_files` HashMap
 /// - visited A HashSet for deduplication, each `exchange, msg_type, market_type` has one
-///  `visited` Hashset
+///   `visited` Hashset
 fn split_file(
     input_file: PathBuf,
     day: String,
@@ -469,8 +468,7 @@ fn search_files(day: &str, input_dir: &str, suffix: &str) -> Vec<PathBuf> {
                 .unwrap()
                 .timestamp_millis()
                 / 1000;
-            let next_day = NaiveDateTime::from_timestamp_opt(day_timestamp + 24 * 3600, 0).unwrap();
-            let next_day: DateTime<Utc> = DateTime::from_utc(next_day, Utc);
+            let next_day: DateTime<Utc> = Utc.timestamp_opt(day_timestamp + 24 * 3600, 0).unwrap();
             next_day.format("%Y-%m-%d-%H").to_string()
         };
         let glob_pattern = format!("{input_dir}/*.{next_day_first_hour}-??.json.{suffix}");
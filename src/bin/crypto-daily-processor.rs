@@ -1,33 +1,42 @@
 #![allow(clippy::type_complexity)]
 use regex::Regex;
 use std::io::prelude::*;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Duration;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::{
     cmp::Reverse,
     collections::hash_map::DefaultHasher,
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     env,
     hash::{Hash, Hasher},
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     str::FromStr,
     sync::{
         mpsc::{
-            self, {Receiver, Sender},
+            self, {Receiver, RecvTimeoutError, SyncSender},
         },
-        Arc, Mutex,
+        Arc, Condvar, Mutex,
     },
-    time::Instant,
+    time::{Duration, Instant},
 };
 
-use chrono::prelude::*;
-use chrono::{DateTime, TimeZone, Utc};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use crypto_cli_tools::{
+    apply_transforms, builtin_transform, check_combo, discover_combos, encode_symbol,
+    get_real_market_type, input_glob_pattern, is_blocked_market, list_input_files,
+    parse_input_file_name, parse_message, raw_line, reconstruct_l2, truncate_depth,
+    validate_parsed, FileNameParts, InputGranularity, Message, ParsedMsg, Transform,
+    BUILTIN_TRANSFORMS, DEFAULT_INPUT_PATTERN, PARSED_SCHEMA_VERSION, PER_SYMBOL_INPUT_PATTERN,
+};
 use crypto_market_type::MarketType;
-use crypto_msg_parser::{extract_symbol, parse_l2, parse_trade};
+use crypto_msg_parser::{extract_symbol, extract_timestamp};
 use crypto_msg_type::MessageType;
 use dashmap::{DashMap, DashSet};
 use flate2::write::GzEncoder;
-use flate2::{read::GzDecoder, Compression};
+use flate2::{
+    read::{GzDecoder, MultiGzDecoder},
+    Compression,
+};
 use glob::glob;
 use log::*;
 use rand::Rng;
@@ -35,7 +44,6 @@ use rlimit::{getrlimit, setrlimit, Resource};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use threadpool::ThreadPool;
-use urlencoding::encode;
 
 const MAX_PIXZ: usize = 2;
 // exchanges in exempted list will suceed even if error ratio is greater than threshold
@@ -43,959 +51,8573 @@ const EXEMPTED_EXCHANGES: &[&str] = &["bitget"];
 
 const MAX_OPEN_FILES: u64 = 131072;
 
-#[derive(Serialize, Deserialize)]
-pub struct Message {
-    /// The exchange name, unique for each exchage
-    pub exchange: String,
-    /// Market type
-    pub market_type: MarketType,
-    /// Message type
-    pub msg_type: MessageType,
-    /// Unix timestamp in milliseconds
-    pub received_at: u64,
-    /// the original message
-    pub json: String,
+// File descriptors reserved for input files, pipes, etc., which are not splitted files
+const RESERVED_OPEN_FILES: usize = 1024;
+
+// Each result in the channel takes less than 64 bytes, so a full channel takes less than 64KB
+const DEFAULT_CHANNEL_CAPACITY: usize = 1024;
+
+// Memory of an open gzip stream and its buffer, accounted by --memory-limit
+const OPEN_FILE_BYTES: u64 = 256 * 1024;
+// Buffer of the spill file of an input file held back by --quarantine-dir, and bytes of lines
+// copied from it into splitted files at once, both accounted by --memory-limit
+const PENDING_BUFFER_BYTES: usize = 64 * 1024;
+const PENDING_BATCH_BYTES: usize = 1024 * 1024;
+
+// Memory of a hash in the visited set, including the overhead of the hash table
+const VISITED_ENTRY_BYTES: u64 = 16;
+
+// Splitted files are decompressed to about this many times of their sizes
+const ESTIMATED_GZIP_RATIO: u64 = 8;
+
+// Exit codes, a panic exits with 101
+// Processing failed, e.g., too many malformed lines, a retry may succeed
+const EXIT_PROCESSING_ERROR: i32 = 1;
+// Invalid arguments or options, a retry won't help
+const EXIT_USAGE: i32 = 2;
+// input_dir or the list of --files-from does not exist
+const EXIT_INPUT_NOT_FOUND: i32 = 3;
+// Some days or combos succeeded and the others failed
+const EXIT_PARTIAL_SUCCESS: i32 = 4;
+// A stage exceeded --max-stage-secs, a rerun continues where it stopped
+const EXIT_TIMEOUT: i32 = 5;
+// Outputs reached --max-output-bytes, a rerun continues after freeing disk space
+const EXIT_OUTPUT_FULL: i32 = 6;
+
+// The split stage logs progress every minute by default
+const DEFAULT_PROGRESS_INTERVAL_SECS: u64 = 60;
+// Progress logs include an ETA after jobs of this percentage of input bytes finish
+const ETA_MIN_PERCENT: u64 = 5;
+// Number of the slowest jobs logged at the end of a stage with --verbose-timing
+const SLOWEST_JOBS: usize = 10;
+// --heartbeat-file is touched this often, a supervisor should allow a few missed beats
+const HEARTBEAT_INTERVAL_SECS: u64 = 5;
+// Input lines longer than this are skipped, the largest messages, e.g., full snapshots
+// of order books, take a few megabytes
+const DEFAULT_MAX_LINE_BYTES: u64 = 64 << 20;
+
+const USAGE: &str = "Usage: crypto-daily-processor <exchange> <msg_type> <market_type> <day> <input_dir> <output_dir_raw> <output_dir_parsed> [options]
+       crypto-daily-processor repair-pairs <output_dir_parsed> [--dry-run]
+       crypto-daily-processor recompress <file_or_dir>...
+       crypto-daily-processor reparse <exchange> <msg_type> <market_type> <day> <output_dir_raw> <output_dir_parsed> [options]
+
+<msg_type> and <market_type> can be * or a comma separated list, e.g., trade,l2_event, to process combos present in input files one by one
+
+Options:
+    --input-pattern <pattern>    Template of input file names, default {exchange}.{market}.{msg}.{day}-??-??.json.gz
+    --channel-capacity <n>       Bound of result channels between worker threads and the collector, default 1024
+    --stage <split|sort|all>     Run only the split stage, only the sort stage, or both, default all
+    --max-open-files <n>         Max number of splitted files open at the same time, default derived from the rlimit
+    --partition <day|hour>       Write one output file per symbol per day or per hour, default day
+    --profile                    Report time spent in decode/parse/hash/sort/write at the end of each stage
+    --timestamp-unit <auto|s|ms|us>  Unit of received_at in input files, default auto
+    --on-error <fail-fast|continue>  Abort the stage on the first failed file, or skip it and continue, default continue
+    --quarantine-dir <dir>       Move malformed input files to this directory instead of failing the split stage
+    --output <raw|parsed|both>   Produce only raw files, only parsed files, or both, default both
+    --canonical-dedup            Deduplicate messages regardless of the order of JSON keys, costs more CPU
+    --day-range <from:to>        Process consecutive days from..=to instead of <day>, e.g., 2024-01-01:2024-01-31
+    --continue                   Continue with the next day if a day of --day-range failed
+    --deterministic              Process files single-threaded in lexicographic order for reproducible intermediate files
+    --validate-parsed            Check parsed messages against their schemas and move violations to rejects files
+    --received-at-path <exchange=pointer>  JSON pointer of the receive time inside json if received_at is absent, e.g., binance=/data/E
+    --compress-threads <n>       Compress all sorted files with a multithreaded xz encoder of n threads
+    --max-symbols <n>            Abort the split stage if there are more than n distinct symbols
+    --raw-sort-key <received_at|timestamp>  Sort raw files by the receive time or the exchange event time, default received_at
+    --verify-checksums           Verify input files against their .sha256, .sha1 or .md5 sidecar files before splitting
+    --count-files                Write the number of lines of each sorted file to a sibling .count file
+    --files-from <file|->        Read newline-delimited input files from a file or stdin instead of globbing input_dir
+    --transform <name>           Apply a built-in transform to each parsed message, mid_price or venue, can be repeated
+    --allow-empty                Succeed even if input files have no lines at all
+    --dedup-dir <dir>            Persist hashes of written messages to dir, so that a rerun of a failed split appends only new messages
+    --combined                   Write raw and parsed messages tagged by kind to one file per symbol in output_dir_raw
+    --intermediate-compression <none|gzip>  Compression of files between the split and sort stages, default gzip
+    --pin-threads                Pin each worker thread to a distinct CPU core, can NOT be used with --compress-threads
+    --stats-only-sort            Only check that existing .json.xz files are sorted, without splitting or writing any file
+    --keep-parsed-empty          Write raw lines of messages parsed to nothing to parsed_empty files
+    --staging                    Sort into a staging directory and move files into place only after all of them succeed
+    --clean-staging              Remove the staging directory if the sort stage fails, by default it is left for inspection
+    --memory-limit <bytes>       Memory budget shared by the split and sort stages, e.g., 16G
+    --run-id <id>                ID of this run in every log line, a random one by default
+    --annotate-source            Add the name of the input file as _src to each parsed message
+    --pixz-percentile <p>        Compress sorted files ranked at or above this percentile by size with the external xz, default 0.9
+    --pixz-min-size <bytes>      Compress only sorted files of at least this many bytes of splitted files with the external xz
+    --l2-reconstruct <seconds>   Rebuild order books from parsed l2_event files and write full snapshots every n seconds to l2_book files
+    --max-file-size <bytes>      Roll a sorted file over to numbered parts once its compressed size exceeds bytes, supports K, M and G suffixes
+    --skip-parse <exchange:market_type:msg_type>  Write only raw messages of a combo with a known-buggy parser, can be repeated
+    --verify-gzip                Decode each input file fully to check its gzip CRC and length before splitting
+    --output-extension <msg_type=ext>  Extension of sorted files of msg_type instead of json.xz, e.g., trade=trades.jsonl.xz, can be repeated
+    --preview <n>                Print the first n parsed messages of input files to stdout without writing any file
+    --progress-interval <seconds>  Log progress of the split stage every n seconds, 0 disables it, default 60
+    --output-format <files|sqlite>  Also export parsed messages of each day to one SQLite database, requires the sqlite feature
+    --spillover-hours <n>        Also read the first n hours of files of the next day for late messages, default 1
+    --only-new                   Skip combos whose _SUCCESS markers of the day already exist
+    --dedup-scope <combo|file|none>  Deduplicate messages across all files of a day, within each file, or not at all, default combo
+    --heartbeat-file <path>      Touch path every few seconds while running, for liveness checks of supervisors
+    --input-concurrency <n>      Max number of input files being split at the same time, default unlimited
+    --rejected-lines             Write lines producing no output with their reasons to rejected_lines files
+    --sort-order <asc|desc>      Direction of sorted files, default asc
+    --parser-strict              Count parse failures as malformed lines, which fail the split stage above 1%
+    --max-stage-secs <seconds>   Stop starting jobs of a stage after this many seconds and exit with code 5
+    --no-dedup                   Skip hashing and write every valid line, the input must be duplicate-free, same as --dedup-scope none
+    --latency-stats              Write p50 and p99 of received_at minus timestamp per symbol to latency files
+    --symbols <s1,s2>            Only process messages of these symbols, can be repeated
+    --symbols-from <file>        Only process messages of symbols listed in file, one per line, merged with --symbols
+    --max-output-bytes <bytes>   Stop the run with exit code 6 before writing more than bytes to output directories, e.g., 500G
+    --l2-depth <n>               Keep only the best n price levels per side of parsed l2_event messages, lossy
+    --count-only                 Print line counts of files the sort stage would write, without sorting or removing splitted files
+    --day-filter-basis <mixed|received_at|event_timestamp|either>  Timestamp deciding whether a message belongs to the day, default mixed
+    --normalize-received-at      Accept received_at stored as a string or float, and write it as an integer of milliseconds
+    --shard-index <k>            Only process symbols whose hash modulo --shard-count is k, to split a backfill across machines
+    --shard-count <n>            Number of shards, markers and manifests of each shard are named with .shard-<k>-of-<n>
+    --bloom-dir <dir>            Save a Bloom filter of messages of each day to dir, and drop messages found in that of the previous day
+    --bloom-fp-rate <rate>       False positive rate of Bloom filters of --bloom-dir, default 0.000001
+    --verbose-timing             Log the size, lines and duration of each split and sort job, and the slowest jobs of each stage
+    --output-json-style <style>  ndjson or array, array wraps each sorted file in [ ... ] with comma separated lines, default ndjson
+    --max-gap-secs <seconds>     With --stats-only-sort, report intervals between consecutive messages longer than this as clock anomalies
+    --buffer-small-files <bytes> Keep splitted files in memory up to bytes, and write those still smaller at the end of the split stage at once
+    --input-granularity <g>      combined or per-symbol, per-symbol takes the symbol of input files from their names, e.g., {exchange}.{market}.{msg}.{symbol}.{day}.json.gz, default combined
+    --json-lines-limit-bytes <b> Skip input lines longer than this as malformed instead of buffering them, default 64M
+    --exclude-symbols <s1,s2>    Drop messages of these symbols, applied after --symbols, can be repeated
+    --exclude-symbols-from <file> Drop messages of symbols listed in file, one per line, merged with --exclude-symbols";
+
+/// Stages to run.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    /// Split input files to `.json.gz` files only
+    Split,
+    /// Sort `.json.gz` files generated by a previous split only
+    Sort,
+    All,
 }
 
-fn get_day(timestamp_millis: i64) -> String {
-    let dt = Utc.timestamp_opt(timestamp_millis / 1000, 0).unwrap();
-    dt.format("%Y-%m-%d").to_string()
+/// Granularity of output files.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Partition {
+    /// One file per symbol per day
+    Day,
+    /// One file per symbol per hour
+    Hour,
 }
 
-fn get_hour(timestamp_millis: i64) -> String {
-    let dt = Utc.timestamp_opt(timestamp_millis / 1000, 0).unwrap();
-    dt.format("%Y-%m-%d-%H").to_string()
+/// Sort key of raw files, parsed files are always sorted by `timestamp`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RawSortKey {
+    /// `received_at` of the envelope
+    ReceivedAt,
+    /// The event time inside `json`, falling back to `received_at` if unavailable
+    Timestamp,
 }
 
-// Output to a raw file and a parsed file.
-#[derive(Clone)]
-struct Output(Arc<Mutex<Box<dyn std::io::Write + Send>>>);
+/// Direction of sorted files, consumers commonly assume `Asc`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SortOrder {
+    Asc,
+    /// The most recent message first
+    Desc,
+}
 
-fn get_real_market_type(exchange: &str, market_type: MarketType, symbol: &str) -> MarketType {
-    if exchange == "bitmex" && market_type == MarketType::Unknown {
-        crypto_pair::get_market_type(symbol, "bitmex", None)
-    } else if exchange == "deribit" && symbol.ends_with("-PERPETUAL") {
-        MarketType::InverseSwap
-    } else {
-        market_type
+/// Which timestamp places a message in the day being processed, for raw and parsed outputs.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DayFilterBasis {
+    /// `received_at` for raw messages and the event `timestamp` for parsed messages
+    Mixed,
+    ReceivedAt,
+    /// The event time, falling back to `received_at` if unavailable
+    EventTimestamp,
+    /// Either of them is in the day
+    Either,
+}
+
+impl DayFilterBasis {
+    fn name(self) -> &'static str {
+        match self {
+            DayFilterBasis::Mixed => "mixed",
+            DayFilterBasis::ReceivedAt => "received_at",
+            DayFilterBasis::EventTimestamp => "event_timestamp",
+            DayFilterBasis::Either => "either",
+        }
+    }
+
+    /// Whether raw messages need their event time.
+    fn needs_event_timestamp(self) -> bool {
+        matches!(
+            self,
+            DayFilterBasis::EventTimestamp | DayFilterBasis::Either
+        )
+    }
+
+    /// The timestamp which places a raw or parsed message in `day`, None if it is out of
+    /// the day. It also decides the hour of the splitted file.
+    fn day_timestamp(
+        self,
+        day: &str,
+        received_at: i64,
+        event_timestamp: Option<i64>,
+        parsed: bool,
+    ) -> Option<i64> {
+        let event_timestamp = event_timestamp.unwrap_or(received_at);
+        let candidates = match self {
+            DayFilterBasis::Mixed if parsed => [Some(event_timestamp), None],
+            DayFilterBasis::Mixed | DayFilterBasis::ReceivedAt => [Some(received_at), None],
+            DayFilterBasis::EventTimestamp => [Some(event_timestamp), None],
+            DayFilterBasis::Either => [Some(received_at), Some(event_timestamp)],
+        };
+        candidates
+            .into_iter()
+            .flatten()
+            .find(|timestamp| get_day(*timestamp) == day)
     }
 }
 
-fn is_blocked_market(market_type: MarketType) -> bool {
-    market_type == MarketType::QuantoFuture
-        || market_type == MarketType::QuantoSwap
-        || market_type == MarketType::EuropeanOption // TODO: need to figure out how to parse option data
+/// How a sorted file is compressed, recorded in the manifest.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Compressor {
+    /// The single-threaded in-process encoder
+    Xz,
+    /// The multithreaded in-process encoder of `--compress-threads`
+    XzMt,
+    /// The external `xz` process
+    ExternalXz,
 }
 
-/// Split a file by symbol and write to multiple files.
-///
-/// This function does split, dedup and parse together, and it is
-/// thread-safe, each `input_file` will launch a thread.
-///
-/// ## Arguments:
-///
-/// - input_file A `.json.gz` file downloaded from AWS S3
-/// - day `yyyy-MM-dd` string, all messages beyond [day-5min, day+5min] will be dropped
-/// - output_dir Where raw messages will be written to
-/// - splitted_files A HashMap that tracks opened files, key is `msg.symbol`, value is file of
-///  `output_dir/exchange.market_type.msg_type.symbol.hour.json.gz`. Each `exchange, msg_type, market_type`
-///  has one `splitted_files` HashMap
-/// - visited A HashSet for deduplication, each `exchange, msg_type, market_type` has one
-///  `visited` Hashset
-fn split_file_raw<P>(
-    input_file: P,
-    day: String,
-    output_dir: P,
-    splitted_files: Arc<DashMap<String, Output>>,
-    visited: Arc<DashSet<u64>>,
-) -> (i64, i64, i64, i64, i64)
-where
-    P: AsRef<Path>,
-{
-    let file_name = input_file.as_ref().file_name().unwrap();
-    let v: Vec<&str> = file_name.to_str().unwrap().split('.').collect();
-    let exchange = v[0];
-    let market_type = MarketType::from_str(v[1]).unwrap();
-    let msg_type_str = v[2];
-    let msg_type = MessageType::from_str(msg_type_str).unwrap();
-    let f_in = std::fs::File::open(&input_file)
-        .unwrap_or_else(|_| panic!("{:?} does not exist", input_file.as_ref().display()));
-    let buf_reader = std::io::BufReader::new(GzDecoder::new(f_in));
-    let mut total_lines = 0;
-    let mut unique_lines = 0;
-    let mut duplicated_lines = 0;
-    let mut error_lines = 0;
-    let mut expired_lines = 0;
-    for line in buf_reader.lines() {
-        if let Ok(line) = line {
-            total_lines += 1;
-            if let Ok(mut msg) = serde_json::from_str::<Message>(&line) {
-                assert_eq!(msg.exchange, exchange);
-                if market_type != MarketType::Unknown {
-                    assert_eq!(msg.market_type, market_type);
-                }
-                assert_eq!(msg.msg_type, msg_type);
-                let hashcode = {
-                    let mut hasher = DefaultHasher::new();
-                    msg.json.hash(&mut hasher);
-                    hasher.finish()
-                };
-                if let Ok(symbol) = extract_symbol(exchange, market_type, &msg.json) {
-                    let real_market_type = get_real_market_type(exchange, msg.market_type, &symbol);
+/// Compression of intermediate files between the split and sort stages.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum IntermediateCompression {
+    /// `.json` files, trading disk for CPU
+    None,
+    /// `.json.gz` files
+    Gzip,
+}
 
-                    if day == get_day(msg.received_at as i64) {
-                        // raw
-                        if visited.insert(hashcode) {
-                            unique_lines += 1;
-                            let output_file_name = {
-                                let hour = get_hour(msg.received_at as i64);
-                                format!(
-                                    "{}.{}.{}.{}.{}.json.gz",
-                                    exchange,
-                                    real_market_type,
-                                    msg_type_str,
-                                    encode_symbol(&symbol),
-                                    hour
-                                )
-                            };
-                            let output = {
-                                let output_dir_clone = output_dir.as_ref().to_path_buf();
-                                // `.entry().or_insert_with()` is atomic, see https://github.com/xacrimon/dashmap/issues/78
-                                let entry = splitted_files
-                                    .entry(output_file_name.clone())
-                                    .or_insert_with(move || {
-                                        let buf_writer_raw = {
-                                            let output_dir =
-                                                output_dir_clone.join(real_market_type.to_string());
-                                            std::fs::create_dir_all(output_dir.as_path()).unwrap();
-                                            let f_out = std::fs::OpenOptions::new()
-                                                .create(true)
-                                                .write(true)
-                                                .truncate(true)
-                                                .open(
-                                                    Path::new(output_dir.as_path())
-                                                        .join(output_file_name),
-                                                )
-                                                .unwrap();
-                                            std::io::BufWriter::new(GzEncoder::new(
-                                                f_out,
-                                                Compression::default(),
-                                            ))
-                                        };
-                                        Output(Arc::new(Mutex::new(Box::new(buf_writer_raw))))
-                                    });
-                                entry.value().clone()
-                            };
-                            let mut writer = output.0.lock().unwrap();
-                            if msg.market_type != real_market_type
-                                || msg.exchange == "mxc"
-                                || msg.exchange == "okex"
-                            {
-                                msg.market_type = real_market_type;
-                                if msg.exchange == "mxc" {
-                                    msg.exchange = "mexc".to_string();
-                                } else if msg.exchange == "okex" {
-                                    msg.exchange = "okx".to_string();
-                                }
-                                writeln!(writer, "{}", serde_json::to_string(&msg).unwrap())
-                                    .unwrap();
-                            } else {
-                                writeln!(writer, "{line}").unwrap();
-                            }
-                        } else {
-                            duplicated_lines += 1;
-                        }
-                    } else {
-                        expired_lines += 1;
-                    }
-                } else {
-                    warn!("No symbol: {}", line);
-                    error_lines += 1;
-                }
-            } else {
-                warn!("Not a valid Message: {}", line);
-                error_lines += 1;
-            }
-        } else {
-            error!("malformed file {}", input_file.as_ref().display());
-            error_lines += 1;
-            total_lines += 1;
+impl IntermediateCompression {
+    fn extension(self) -> &'static str {
+        match self {
+            IntermediateCompression::None => "json",
+            IntermediateCompression::Gzip => "json.gz",
         }
     }
-    (
-        total_lines,
-        unique_lines,
-        duplicated_lines,
-        error_lines,
-        expired_lines,
-    )
 }
 
-fn split_file_parsed<P>(
-    input_file: P,
-    day: String,
-    output_dir: P,
-    splitted_files: Arc<DashMap<String, Output>>,
-    visited: Arc<DashSet<u64>>,
-) -> (i64, i64, i64, i64, i64)
-where
-    P: AsRef<Path>,
-{
-    let file_name = input_file.as_ref().file_name().unwrap();
-    let v: Vec<&str> = file_name.to_str().unwrap().split('.').collect();
-    let exchange = v[0];
-    let market_type = MarketType::from_str(v[1]).unwrap();
-    let msg_type_str = v[2];
-    let msg_type = MessageType::from_str(msg_type_str).unwrap();
-    let f_in = std::fs::File::open(&input_file)
-        .unwrap_or_else(|_| panic!("{:?} does not exist", input_file.as_ref().display()));
-    let buf_reader = std::io::BufReader::new(GzDecoder::new(f_in));
-    let mut total_lines = 0;
-    let mut unique_lines = 0;
-    let mut duplicated_lines = 0;
-    let mut error_lines = 0;
-    let mut expired_lines = 0;
-    for line in buf_reader.lines() {
-        if let Ok(line) = line {
-            total_lines += 1;
-            if let Ok(msg) = serde_json::from_str::<Message>(&line) {
-                assert_eq!(msg.exchange, exchange);
-                if market_type != MarketType::Unknown {
-                    assert_eq!(msg.market_type, market_type);
-                }
-                assert_eq!(msg.msg_type, msg_type);
-                let hashcode = {
-                    let mut hasher = DefaultHasher::new();
-                    msg.json.hash(&mut hasher);
-                    hasher.finish()
-                };
-                if let Ok(symbol) = extract_symbol(exchange, market_type, &msg.json) {
-                    let real_market_type = get_real_market_type(exchange, msg.market_type, &symbol);
+/// Persistence of parsed messages besides sorted files.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// Sorted `.json.xz` files only
+    Files,
+    /// Also one `.sqlite` database per combo and day
+    Sqlite,
+}
 
-                    if visited.insert(hashcode) {
-                        unique_lines += 1;
-                        // parsed
-                        let write_parsed =
-                            |market_type: MarketType, json: String, timestamp: i64| {
-                                let output_file_name = {
-                                    let hour = get_hour(timestamp);
-                                    let pair = crypto_pair::normalize_pair(&symbol, exchange)
-                                        .unwrap_or_else(|| panic!("{symbol} {exchange} {line}"));
-                                    let (base, quote) = {
-                                        let v = pair.as_str().split('/').collect::<Vec<&str>>();
-                                        (v[0], v[1])
-                                    };
-                                    format!(
-                                        "{}.{}.{}.{}.{}.{}.{}.json.gz",
-                                        exchange,
-                                        market_type,
-                                        msg_type_str,
-                                        encode_symbol(base),
-                                        encode_symbol(quote),
-                                        encode_symbol(&symbol),
-                                        hour
-                                    )
-                                };
-                                let output = {
-                                    let output_dir_clone = output_dir.as_ref().to_path_buf();
-                                    // `.entry().or_insert_with()` is atomic, see https://github.com/xacrimon/dashmap/issues/78
-                                    let entry = splitted_files
-                                        .entry(output_file_name.clone())
-                                        .or_insert_with(move || {
-                                            let buf_writer_parsed = {
-                                                let output_dir =
-                                                    output_dir_clone.join(market_type.to_string());
-                                                std::fs::create_dir_all(output_dir.as_path())
-                                                    .unwrap();
-                                                let f_out = std::fs::OpenOptions::new()
-                                                    .create(true)
-                                                    .write(true)
-                                                    .truncate(true)
-                                                    .open(
-                                                        Path::new(output_dir.as_path())
-                                                            .join(output_file_name),
-                                                    )
-                                                    .unwrap();
-                                                std::io::BufWriter::new(GzEncoder::new(
-                                                    f_out,
-                                                    Compression::default(),
-                                                ))
-                                            };
-                                            Output(Arc::new(Mutex::new(Box::new(
-                                                buf_writer_parsed,
-                                            ))))
-                                        });
-                                    entry.value().clone()
-                                };
-                                let mut writer = output.0.lock().unwrap();
-                                writeln!(writer, "{json}").unwrap();
-                            };
+/// Layout of messages in sorted files.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputJsonStyle {
+    /// One message per line
+    Ndjson,
+    /// A JSON array, still one message per line, but separated by commas between `[` and `]`
+    Array,
+}
 
-                        match msg.msg_type {
-                            MessageType::L2Event => {
-                                // Skip unsupported markets
-                                if !is_blocked_market(real_market_type) {
-                                    if let Ok(messages) = parse_l2(
-                                        exchange,
-                                        msg.market_type,
-                                        &msg.json,
-                                        Some(msg.received_at as i64),
-                                    ) {
-                                        for mut message in messages {
-                                            assert_eq!(real_market_type, message.market_type);
-                                            if message.exchange == "mxc" {
-                                                message.exchange = "mexc".to_string();
-                                            } else if message.exchange == "okex" {
-                                                message.exchange = "okx".to_string();
-                                            }
-                                            if get_day(message.timestamp) == day {
-                                                write_parsed(
-                                                    message.market_type,
-                                                    serde_json::to_string(&message).unwrap(),
-                                                    message.timestamp,
-                                                );
-                                            } else {
-                                                expired_lines += 1;
-                                            }
-                                        }
-                                    } else {
-                                        warn!("parse_l2 failed: {}", line);
-                                    }
-                                }
-                            }
-                            MessageType::Trade => {
-                                if let Ok(messages) =
-                                    parse_trade(&msg.exchange, msg.market_type, &msg.json)
-                                {
-                                    for mut message in messages {
-                                        if !(real_market_type == MarketType::InverseSwap
-                                            && message.market_type == MarketType::InverseFuture
-                                            && message.exchange == "deribit")
-                                        {
-                                            // For deribit, inverse_swap is included in inverse_future
-                                            assert_eq!(
-                                                real_market_type, message.market_type,
-                                                "{real_market_type}, {line}",
-                                            );
-                                        }
-                                        if message.exchange == "mxc" {
-                                            message.exchange = "mexc".to_string();
-                                        } else if message.exchange == "okex" {
-                                            message.exchange = "okx".to_string();
-                                        }
-                                        if get_day(message.timestamp) == day {
-                                            write_parsed(
-                                                message.market_type,
-                                                serde_json::to_string(&message).unwrap(),
-                                                message.timestamp,
-                                            );
-                                        } else {
-                                            expired_lines += 1;
-                                        }
-                                    }
-                                } else {
-                                    warn!("parse_trade failed: {}", line);
-                                }
-                            }
-                            _ => panic!("Unknown msg_type {}", msg.msg_type),
-                        };
-                    } else {
-                        duplicated_lines += 1;
-                    }
-                } else {
-                    warn!("No symbol: {}", line);
-                    error_lines += 1;
-                }
-            } else {
-                warn!("Not a valid Message: {}", line);
-                error_lines += 1;
-            }
-        } else {
-            error!("malformed file {}", input_file.as_ref().display());
-            error_lines += 1;
-            total_lines += 1;
+/// Which messages a message is deduplicated against.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DedupScope {
+    /// All input files of a combo and day, which drops messages replayed in later hourly files
+    Combo,
+    /// Each input file alone, keeping duplicates retransmitted across files
+    File,
+    /// No deduplication at all
+    None,
+}
+
+impl DedupScope {
+    fn name(self) -> &'static str {
+        match self {
+            DedupScope::Combo => "combo",
+            DedupScope::File => "file",
+            DedupScope::None => "none",
         }
     }
-    (
-        total_lines,
-        unique_lines,
-        duplicated_lines,
-        error_lines,
-        expired_lines,
-    )
 }
 
-fn sort_file<P>(input_file: P, writer: &mut dyn std::io::Write) -> (i64, i64)
-where
-    P: AsRef<Path>,
-{
-    assert!(input_file.as_ref().to_str().unwrap().ends_with(".json.gz"));
-    if !input_file.as_ref().exists() {
-        panic!("{:?} does not exist", input_file.as_ref().display());
+/// Which output files to produce.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputKind {
+    Raw,
+    Parsed,
+    Both,
+}
+
+impl OutputKind {
+    fn raw(self) -> bool {
+        self != OutputKind::Parsed
     }
-    let buf_reader = {
-        let f_in = std::fs::File::open(&input_file).unwrap();
-        std::io::BufReader::new(GzDecoder::new(f_in))
-    };
-    let mut total_lines = 0;
-    let mut error_lines = 0;
-    let mut lines: Vec<(i64, String)> = Vec::new();
-    for line in buf_reader.lines() {
-        if let Ok(line) = line {
-            total_lines += 1;
-            if let Ok(msg) = serde_json::from_str::<HashMap<String, Value>>(&line) {
-                if msg.contains_key("received_at") || msg.contains_key("timestamp") {
-                    let timestamp = if msg.contains_key("received_at") {
-                        msg.get("received_at").unwrap().as_i64().unwrap()
-                    } else {
-                        msg.get("timestamp").unwrap().as_i64().unwrap()
-                    };
-                    lines.push((timestamp, line))
-                } else {
-                    warn!("Can NOT find received_at nor timestamp: {}", line);
-                    error_lines += 1;
-                }
-            } else {
-                warn!("Not a JSON object: {}", line);
-                error_lines += 1;
-            }
+
+    fn parsed(self) -> bool {
+        self != OutputKind::Raw
+    }
+}
+
+/// What to do when processing one file fails.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum OnError {
+    /// Skip jobs not started yet and fail the stage
+    FailFast,
+    /// Keep processing other files and only report failed ones
+    Continue,
+}
+
+/// Unit of `Message.received_at` in input files, normalized to milliseconds internally.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum TimestampUnit {
+    /// Detected from the magnitude of the first message in each file
+    Auto,
+    Seconds,
+    Millis,
+    Micros,
+}
+
+impl TimestampUnit {
+    /// Guess the unit of a Unix timestamp by its magnitude.
+    fn detect(timestamp: u64) -> Self {
+        if timestamp > 1_000_000_000_000_000 {
+            TimestampUnit::Micros
+        } else if timestamp > 1_000_000_000_000 {
+            TimestampUnit::Millis
         } else {
-            error!("malformed file {}", input_file.as_ref().display());
-            error_lines += 1;
+            TimestampUnit::Seconds
         }
     }
-    std::fs::remove_file(input_file.as_ref()).unwrap();
-    if error_lines == 0 {
-        lines.sort_by_key(|x| x.0); // sort by timestamp
 
-        for line in lines {
-            writeln!(writer, "{}", line.1).unwrap();
+    fn to_millis(self, timestamp: u64) -> u64 {
+        match self {
+            TimestampUnit::Seconds => timestamp * 1000,
+            TimestampUnit::Millis | TimestampUnit::Auto => timestamp,
+            TimestampUnit::Micros => timestamp / 1000,
         }
-        writer.flush().unwrap();
-    } else {
-        error!(
-            "Found {} malformed lines out of total {} lines in file {}",
-            error_lines,
-            total_lines,
-            input_file.as_ref().display()
-        );
     }
-    (error_lines, total_lines)
 }
 
-// Use xz if use_xz is true, and semaphore allows only two xz processes
-fn sort_files<P>(
-    mut hourly_files: Vec<P>,
-    output_file: P,
-    use_xz: bool,
-    semaphore: Arc<AtomicUsize>,
-) -> (i64, i64)
-where
-    P: AsRef<Path>,
-{
-    for input_file in hourly_files.iter() {
-        assert!(input_file.as_ref().to_str().unwrap().ends_with(".json.gz"));
-        if !input_file.as_ref().exists() {
-            panic!("{:?} does not exist", input_file.as_ref().display());
+/// Normalize `timestamp` of a parsed message to milliseconds, which the day of the message
+/// is derived from. `unit` starts as `Auto` for each input file and is detected from its
+/// first parsed message, a warning is logged if `crypto-msg-parser` returns another unit.
+fn parsed_timestamp_millis(unit: &mut TimestampUnit, timestamp: i64, input_file: &Path) -> i64 {
+    if *unit == TimestampUnit::Auto {
+        *unit = TimestampUnit::detect(timestamp.max(0) as u64);
+        if *unit != TimestampUnit::Millis {
+            warn!(
+                "Parsed timestamp {} of {} looks like {:?} instead of milliseconds, converted",
+                timestamp,
+                input_file.display(),
+                unit
+            );
         }
     }
-    if hourly_files.len() < 24 {
-        warn!(
-            "There are only {} files for {}",
-            hourly_files.len(),
-            output_file.as_ref().display()
-        );
+    unit.to_millis(timestamp.max(0) as u64) as i64
+}
+
+/// Optional settings, passed as `--name value` after positional arguments.
+#[derive(Clone)]
+struct Options {
+    /// Template of input file names, `{exchange}`, `{market}`, `{msg}` and `{day}`
+    /// will be replaced with actual values
+    input_pattern: String,
+    /// Bound of channels between worker threads and the result collector, a worker thread
+    /// blocks when the channel is full, which applies back-pressure to fast workers
+    channel_capacity: usize,
+    stage: Stage,
+    /// Max number of splitted files open at the same time, each open gzip stream
+    /// takes about 256KB memory, None means derived from the rlimit
+    max_open_files: Option<usize>,
+    partition: Partition,
+    /// Time each phase and report the breakdown at the end of each stage
+    profile: bool,
+    timestamp_unit: TimestampUnit,
+    on_error: OnError,
+    /// Where malformed input files are moved to, None means failing the split stage
+    quarantine_dir: Option<String>,
+    output: OutputKind,
+    /// Hash parsed JSON with sorted keys instead of the raw string for deduplication
+    canonical_dedup: bool,
+    /// Inclusive range of days to process, replacing the positional day
+    day_range: Option<(NaiveDate, NaiveDate)>,
+    /// Continue with the next day of `day_range` if a day failed
+    keep_going: bool,
+    /// Process files single-threaded in lexicographic order, so that splitted files
+    /// are byte-identical across runs
+    deterministic: bool,
+    /// Check each parsed message against its schema, violations go to rejects files
+    validate_parsed: bool,
+    /// JSON pointers to extract the receive time from `Message.json` per exchange,
+    /// used when `received_at` is absent or zero
+    received_at_paths: HashMap<String, String>,
+    /// Accept `received_at` stored as a string or float, and rewrite it as an integer in raw files
+    normalize_received_at: bool,
+    /// Threads of the in-process multithreaded xz encoder, None means single-threaded
+    /// encoder for most files and the external xz for the largest files
+    compress_threads: Option<u32>,
+    /// Abort the split stage if the number of distinct symbols exceeds it
+    max_symbols: Option<usize>,
+    /// Symbols of `--symbols` and `--symbols-from`, None means all symbols
+    symbols: Option<HashSet<String>>,
+    /// Symbols of `--exclude-symbols` and `--exclude-symbols-from`, dropped after `symbols`
+    exclude_symbols: HashSet<String>,
+    /// Only symbols of this shard, `--shard-index` and `--shard-count`
+    shard: Option<Shard>,
+    /// Log the size, lines and duration of each split and sort job
+    verbose_timing: bool,
+    /// Save a Bloom filter of messages of each day, and drop messages in that of the previous day
+    bloom_dir: Option<String>,
+    bloom_fp_rate: f64,
+    output_json_style: OutputJsonStyle,
+    /// Intervals of `--stats-only-sort` longer than this are reported as clock anomalies
+    max_gap_ms: Option<i64>,
+    /// Splitted files are buffered in memory up to this many bytes
+    buffer_small_files: Option<u64>,
+    input_granularity: InputGranularity,
+    /// Input lines longer than this many bytes are malformed
+    max_line_bytes: u64,
+    /// Sort key of raw files
+    raw_sort_key: RawSortKey,
+    sort_order: SortOrder,
+    /// Verify input files against their checksum sidecar files before splitting
+    verify_checksums: bool,
+    /// Write the number of lines of each sorted file to a sibling `.count` file
+    count_files: bool,
+    /// Where to read the list of input files from, `-` means stdin
+    files_from: Option<String>,
+    /// Input files read from `files_from` by `main()`, None means globbing `input_dir`
+    input_files: Option<Vec<PathBuf>>,
+    /// Applied in order to each parsed message before writing it
+    transforms: Vec<Transform>,
+    /// Succeed even if input files have no lines, which usually means missing data
+    allow_empty: bool,
+    /// Where hashes of written messages are persisted across invocations
+    dedup_dir: Option<String>,
+    /// Write raw and parsed messages wrapped as `{"kind":"raw"|"parsed","data":...}` to
+    /// one file per symbol in `output_dir_raw`
+    combined: bool,
+    /// Compression of splitted files, which are deleted by the sort stage
+    intermediate_compression: IntermediateCompression,
+    /// Pin each worker thread to a distinct CPU core
+    pin_threads: bool,
+    /// Only check the order of existing sorted files
+    stats_only_sort: bool,
+    /// Count lines of splitted files instead of sorting them
+    count_only: bool,
+    /// Write raw lines of messages which are parsed to no message to parsed_empty files
+    keep_parsed_empty: bool,
+    /// Sort into `output_dir/.staging/` and move files into place after all of them succeed
+    staging: bool,
+    /// Remove the staging directory if the sort stage fails
+    clean_staging: bool,
+    /// Memory budget of open splitted files, visited hashes and sort jobs in bytes
+    memory_limit: Option<u64>,
+    /// Stop the run once this many bytes are written to output directories
+    max_output_bytes: Option<u64>,
+    /// Included in every log line, None means a random one
+    run_id: Option<String>,
+    /// Add the name of the input file as `_src` to each parsed message
+    annotate_source: bool,
+    /// Sorted files ranked at or above this percentile by size are compressed by the external xz
+    pixz_percentile: f64,
+    /// Minimal total size of the splitted files of a sorted file compressed by the external xz
+    pixz_min_size: u64,
+    /// Interval of order book snapshots rebuilt from parsed l2_event files in milliseconds
+    l2_reconstruct: Option<i64>,
+    /// Price levels kept per side of parsed l2_event messages, None means full depth
+    l2_depth: Option<usize>,
+    /// Compressed size at which a sorted file rolls over to the next part, None means unlimited
+    max_file_size: Option<u64>,
+    /// Combos of exchange, market_type and msg_type which are not parsed
+    skip_parse: Vec<(String, MarketType, MessageType)>,
+    /// Check the gzip trailer of each input file in a pre-pass
+    verify_gzip: bool,
+    /// Extensions of sorted files by msg_type, `json.xz` if absent
+    output_extensions: HashMap<MessageType, String>,
+    /// Print this many parsed messages and exit
+    preview: Option<usize>,
+    /// Interval of progress logs of the split stage, None disables them
+    progress_interval: Option<Duration>,
+    output_format: OutputFormat,
+    /// Hours of files of the next day read for late messages of the day
+    spillover_hours: u32,
+    /// Skip days of combos completed by a previous run
+    only_new: bool,
+    dedup_scope: DedupScope,
+    heartbeat_file: Option<PathBuf>,
+    /// Max number of input files submitted to the thread pool and not finished yet,
+    /// None means all files are submitted at once
+    input_concurrency: Option<usize>,
+    /// Write rejected lines to `rejected_lines.*.{raw,parsed}.json.gz`
+    rejected_lines: bool,
+    /// Count failures of `parse_trade()` and `parse_l2()` as malformed lines
+    parser_strict: bool,
+    /// Jobs of a stage starting after this long are skipped, None means no limit
+    max_stage_secs: Option<Duration>,
+    /// Write percentiles of `received_at - timestamp` per symbol to `latency.*.json`
+    latency_stats: bool,
+    day_filter_basis: DayFilterBasis,
+    /// Input files are sorted raw files of the `reparse` subcommand, named by symbol
+    /// instead of hour
+    reparse: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            input_pattern: DEFAULT_INPUT_PATTERN.to_string(),
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            stage: Stage::All,
+            max_open_files: None,
+            partition: Partition::Day,
+            profile: false,
+            timestamp_unit: TimestampUnit::Auto,
+            on_error: OnError::Continue,
+            quarantine_dir: None,
+            output: OutputKind::Both,
+            canonical_dedup: false,
+            day_range: None,
+            keep_going: false,
+            deterministic: false,
+            validate_parsed: false,
+            received_at_paths: HashMap::new(),
+            normalize_received_at: false,
+            compress_threads: None,
+            max_symbols: None,
+            symbols: None,
+            exclude_symbols: HashSet::new(),
+            shard: None,
+            verbose_timing: false,
+            bloom_dir: None,
+            bloom_fp_rate: 1e-6,
+            output_json_style: OutputJsonStyle::Ndjson,
+            max_gap_ms: None,
+            buffer_small_files: None,
+            input_granularity: InputGranularity::Combined,
+            max_line_bytes: DEFAULT_MAX_LINE_BYTES,
+            raw_sort_key: RawSortKey::ReceivedAt,
+            sort_order: SortOrder::Asc,
+            verify_checksums: false,
+            count_files: false,
+            files_from: None,
+            input_files: None,
+            transforms: Vec::new(),
+            allow_empty: false,
+            dedup_dir: None,
+            combined: false,
+            intermediate_compression: IntermediateCompression::Gzip,
+            pin_threads: false,
+            stats_only_sort: false,
+            count_only: false,
+            keep_parsed_empty: false,
+            staging: false,
+            clean_staging: false,
+            memory_limit: None,
+            max_output_bytes: None,
+            run_id: None,
+            annotate_source: false,
+            pixz_percentile: 0.9,
+            pixz_min_size: 0,
+            l2_reconstruct: None,
+            l2_depth: None,
+            max_file_size: None,
+            skip_parse: Vec::new(),
+            verify_gzip: false,
+            output_extensions: HashMap::new(),
+            preview: None,
+            progress_interval: Some(Duration::from_secs(DEFAULT_PROGRESS_INTERVAL_SECS)),
+            output_format: OutputFormat::Files,
+            spillover_hours: 1,
+            only_new: false,
+            dedup_scope: DedupScope::Combo,
+            heartbeat_file: None,
+            input_concurrency: None,
+            rejected_lines: false,
+            parser_strict: false,
+            max_stage_secs: None,
+            latency_stats: false,
+            day_filter_basis: DayFilterBasis::Mixed,
+            reparse: false,
+        }
     }
-    hourly_files.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
-    assert!(output_file.as_ref().to_str().unwrap().ends_with(".json.xz"));
+}
 
-    let mut writer: Box<dyn std::io::Write> = if !use_xz {
-        let f_out = std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(output_file.as_ref())
-            .unwrap();
-        let e = xz2::write::XzEncoder::new(f_out, 6);
-        Box::new(std::io::BufWriter::new(e))
-    } else {
-        let json_file = {
-            let output_dir = output_file.as_ref().parent().unwrap().to_path_buf();
-            let filename = output_file.as_ref().file_name().unwrap().to_str().unwrap();
-            output_dir.join(&filename[..filename.len() - 3])
-        };
-        let f_out = std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
+impl Options {
+    /// Output directories having sorted files.
+    fn output_dirs<'a>(&self, output_dir_raw: &'a str, output_dir_parsed: &'a str) -> Vec<&'a str> {
+        let mut output_dirs = Vec::new();
+        if self.output.raw() || self.combined {
+            output_dirs.push(output_dir_raw);
+        }
+        if self.output.parsed() && !self.combined {
+            output_dirs.push(output_dir_parsed);
+        }
+        output_dirs
+    }
+
+    /// Extension of sorted files of `msg_type`, without the leading dot.
+    fn output_extension(&self, msg_type: MessageType) -> &str {
+        self.output_extensions
+            .get(&msg_type)
+            .map(String::as_str)
+            .unwrap_or("json.xz")
+    }
+
+    /// Whether messages of `exchange`, `market_type` and `msg_type` are skipped by `--skip-parse`.
+    fn skips_parse(&self, exchange: &str, market_type: MarketType, msg_type: MessageType) -> bool {
+        self.skip_parse
+            .iter()
+            .any(|(e, m, t)| e == exchange && *m == market_type && *t == msg_type)
+    }
+
+    /// Parse `--name value` pairs.
+    fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut shard_index = None;
+        let mut shard_count = None;
+        let mut iter = args.iter();
+        while let Some(name) = iter.next() {
+            let mut value = || {
+                iter.next()
+                    .cloned()
+                    .ok_or_else(|| format!("{name} requires a value"))
+            };
+            match name.as_str() {
+                "--input-pattern" => {
+                    let pattern = value()?;
+                    if !pattern.contains("{day}") {
+                        return Err(format!("{pattern} should contain {{day}}"));
+                    }
+                    options.input_pattern = pattern;
+                }
+                "--channel-capacity" => {
+                    let capacity = value()?;
+                    options.channel_capacity = match capacity.parse::<usize>() {
+                        Ok(n) if n > 0 => n,
+                        _ => return Err(format!("{capacity} is not a positive integer")),
+                    };
+                }
+                "--stage" => {
+                    options.stage = match value()?.as_str() {
+                        "split" => Stage::Split,
+                        "sort" => Stage::Sort,
+                        "all" => Stage::All,
+                        other => return Err(format!("Unknown stage {other}")),
+                    };
+                }
+                "--max-open-files" => {
+                    let n = value()?;
+                    options.max_open_files = match n.parse::<usize>() {
+                        Ok(n) if n > 1 => Some(n),
+                        _ => return Err(format!("{n} should be an integer greater than 1")),
+                    };
+                }
+                "--partition" => {
+                    options.partition = match value()?.as_str() {
+                        "day" => Partition::Day,
+                        "hour" => Partition::Hour,
+                        other => return Err(format!("Unknown partition {other}")),
+                    };
+                }
+                "--raw-sort-key" => {
+                    options.raw_sort_key = match value()?.as_str() {
+                        "received_at" => RawSortKey::ReceivedAt,
+                        "timestamp" => RawSortKey::Timestamp,
+                        other => return Err(format!("Unknown sort key {other}")),
+                    };
+                }
+                "--day-filter-basis" => {
+                    options.day_filter_basis = match value()?.as_str() {
+                        "mixed" => DayFilterBasis::Mixed,
+                        "received_at" => DayFilterBasis::ReceivedAt,
+                        "event_timestamp" => DayFilterBasis::EventTimestamp,
+                        "either" => DayFilterBasis::Either,
+                        other => return Err(format!("Unknown day filter basis {other}")),
+                    };
+                }
+                "--sort-order" => {
+                    options.sort_order = match value()?.as_str() {
+                        "asc" => SortOrder::Asc,
+                        "desc" => SortOrder::Desc,
+                        other => return Err(format!("Unknown sort order {other}")),
+                    };
+                }
+                "--profile" => options.profile = true,
+                "--canonical-dedup" => options.canonical_dedup = true,
+                "--day-range" => {
+                    let range = value()?;
+                    let days = range
+                        .split_once(':')
+                        .map(|(from, to)| {
+                            (
+                                NaiveDate::parse_from_str(from, "%Y-%m-%d"),
+                                NaiveDate::parse_from_str(to, "%Y-%m-%d"),
+                            )
+                        })
+                        .and_then(|days| match days {
+                            (Ok(from), Ok(to)) if from <= to => Some((from, to)),
+                            _ => None,
+                        });
+                    if days.is_none() {
+                        return Err(format!("{range} should be yyyy-MM-dd:yyyy-MM-dd"));
+                    }
+                    options.day_range = days;
+                }
+                "--continue" => options.keep_going = true,
+                "--deterministic" => options.deterministic = true,
+                "--validate-parsed" => options.validate_parsed = true,
+                "--verify-checksums" => options.verify_checksums = true,
+                "--verify-gzip" => options.verify_gzip = true,
+                "--only-new" => options.only_new = true,
+                "--rejected-lines" => options.rejected_lines = true,
+                "--parser-strict" => options.parser_strict = true,
+                "--latency-stats" => options.latency_stats = true,
+                "--normalize-received-at" => options.normalize_received_at = true,
+                "--max-stage-secs" => {
+                    let n = value()?;
+                    options.max_stage_secs = match n.parse::<u64>() {
+                        Ok(n) if n > 0 => Some(Duration::from_secs(n)),
+                        _ => return Err(format!("{n} is not a positive number of seconds")),
+                    };
+                }
+                "--heartbeat-file" => options.heartbeat_file = Some(PathBuf::from(value()?)),
+                "--no-dedup" => options.dedup_scope = DedupScope::None,
+                "--dedup-scope" => {
+                    options.dedup_scope = match value()?.as_str() {
+                        "combo" => DedupScope::Combo,
+                        "file" => DedupScope::File,
+                        "none" => DedupScope::None,
+                        other => return Err(format!("Unknown dedup scope {other}")),
+                    };
+                }
+                "--progress-interval" => {
+                    let n = value()?;
+                    options.progress_interval = match n.parse::<u64>() {
+                        Ok(0) => None,
+                        Ok(n) => Some(Duration::from_secs(n)),
+                        _ => return Err(format!("{n} should be a non-negative integer")),
+                    };
+                }
+                "--preview" => {
+                    let n = value()?;
+                    options.preview = match n.parse::<usize>() {
+                        Ok(n) if n > 0 => Some(n),
+                        _ => return Err(format!("{n} should be a positive integer")),
+                    };
+                }
+                "--output-extension" => {
+                    let (msg_type, extension) = parse_output_extension(&value()?)?;
+                    options.output_extensions.insert(msg_type, extension);
+                }
+                "--count-files" => options.count_files = true,
+                "--allow-empty" => options.allow_empty = true,
+                "--combined" => options.combined = true,
+                "--pin-threads" => options.pin_threads = true,
+                "--stats-only-sort" => options.stats_only_sort = true,
+                "--count-only" => options.count_only = true,
+                "--keep-parsed-empty" => options.keep_parsed_empty = true,
+                "--staging" => options.staging = true,
+                "--clean-staging" => options.clean_staging = true,
+                "--annotate-source" => options.annotate_source = true,
+                "--pixz-percentile" => {
+                    let p = value()?;
+                    options.pixz_percentile = match p.parse::<f64>() {
+                        Ok(p) if (0.0..=1.0).contains(&p) => p,
+                        _ => return Err(format!("{p} should be a number between 0 and 1")),
+                    };
+                }
+                "--pixz-min-size" => options.pixz_min_size = parse_bytes(&value()?)?,
+                "--l2-reconstruct" => {
+                    let n = value()?;
+                    options.l2_reconstruct = match n.parse::<i64>() {
+                        Ok(n) if n > 0 => Some(n * 1000),
+                        _ => return Err(format!("{n} should be a positive integer")),
+                    };
+                }
+                "--run-id" => {
+                    let run_id = value()?;
+                    if run_id.is_empty() || run_id.contains(char::is_whitespace) {
+                        return Err(format!("Invalid run ID {run_id:?}"));
+                    }
+                    options.run_id = Some(run_id);
+                }
+                "--skip-parse" => options.skip_parse.push(parse_combo(&value()?)?),
+                "--max-file-size" => {
+                    options.max_file_size = Some(parse_bytes(&value()?)?);
+                }
+                "--memory-limit" => {
+                    options.memory_limit = Some(parse_bytes(&value()?)?);
+                }
+                "--max-output-bytes" => {
+                    options.max_output_bytes = Some(parse_bytes(&value()?)?);
+                }
+                "--spillover-hours" => {
+                    let n = value()?;
+                    options.spillover_hours = match n.parse::<u32>() {
+                        Ok(n) if n <= 24 => n,
+                        _ => return Err(format!("{n} should be an integer between 0 and 24")),
+                    };
+                }
+                "--output-format" => {
+                    options.output_format =
+                        match value()?.as_str() {
+                            "files" => OutputFormat::Files,
+                            "sqlite" if cfg!(feature = "sqlite") => OutputFormat::Sqlite,
+                            "sqlite" => return Err(
+                                "--output-format sqlite requires building with --features sqlite"
+                                    .to_string(),
+                            ),
+                            other => return Err(format!("Unknown output format {other}")),
+                        };
+                }
+                "--intermediate-compression" => {
+                    options.intermediate_compression = match value()?.as_str() {
+                        "none" => IntermediateCompression::None,
+                        "gzip" => IntermediateCompression::Gzip,
+                        other => return Err(format!("Unknown compression {other}")),
+                    };
+                }
+                "--compress-threads" => {
+                    let n = value()?;
+                    options.compress_threads = match n.parse::<u32>() {
+                        Ok(n) if n > 0 => Some(n),
+                        _ => return Err(format!("{n} is not a positive integer")),
+                    };
+                }
+                "--input-concurrency" => {
+                    let n = value()?;
+                    options.input_concurrency = match n.parse::<usize>() {
+                        Ok(n) if n > 0 => Some(n),
+                        _ => return Err(format!("{n} is not a positive integer")),
+                    };
+                }
+                "--l2-depth" => {
+                    let n = value()?;
+                    options.l2_depth = match n.parse::<usize>() {
+                        Ok(n) if n > 0 => Some(n),
+                        _ => return Err(format!("{n} is not a positive integer")),
+                    };
+                }
+                "--max-symbols" => {
+                    let n = value()?;
+                    options.max_symbols = match n.parse::<usize>() {
+                        Ok(n) if n > 0 => Some(n),
+                        _ => return Err(format!("{n} is not a positive integer")),
+                    };
+                }
+                "--verbose-timing" => options.verbose_timing = true,
+                "--max-gap-secs" => {
+                    let n = value()?;
+                    options.max_gap_ms = match n.parse::<i64>() {
+                        Ok(n) if n > 0 => Some(n * 1000),
+                        _ => return Err(format!("{n} is not a positive number of seconds")),
+                    };
+                }
+                "--buffer-small-files" => {
+                    options.buffer_small_files = Some(parse_bytes(&value()?)?);
+                }
+                "--json-lines-limit-bytes" => {
+                    options.max_line_bytes = match parse_bytes(&value()?)? {
+                        0 => return Err("--json-lines-limit-bytes should be positive".to_string()),
+                        n => n,
+                    };
+                }
+                "--input-granularity" => {
+                    options.input_granularity = match value()?.as_str() {
+                        "combined" => InputGranularity::Combined,
+                        "per-symbol" => InputGranularity::PerSymbol,
+                        other => return Err(format!("Unknown input granularity {other}")),
+                    };
+                }
+                "--output-json-style" => {
+                    options.output_json_style = match value()?.as_str() {
+                        "ndjson" => OutputJsonStyle::Ndjson,
+                        "array" => OutputJsonStyle::Array,
+                        other => return Err(format!("Unknown JSON style {other}")),
+                    };
+                }
+                "--bloom-dir" => options.bloom_dir = Some(value()?),
+                "--bloom-fp-rate" => {
+                    let rate = value()?;
+                    options.bloom_fp_rate = match rate.parse::<f64>() {
+                        Ok(rate) if rate > 0.0 && rate < 1.0 => rate,
+                        _ => return Err(format!("{rate} should be between 0 and 1")),
+                    };
+                }
+                "--shard-index" => {
+                    let n = value()?;
+                    shard_index = match n.parse::<u64>() {
+                        Ok(n) => Some(n),
+                        _ => return Err(format!("{n} is not a non-negative integer")),
+                    };
+                }
+                "--shard-count" => {
+                    let n = value()?;
+                    shard_count = match n.parse::<u64>() {
+                        Ok(n) if n > 0 => Some(n),
+                        _ => return Err(format!("{n} is not a positive integer")),
+                    };
+                }
+                "--symbols" => {
+                    let list = value()?;
+                    options.symbols.get_or_insert_with(HashSet::new).extend(
+                        list.split(',')
+                            .map(str::trim)
+                            .filter(|s| !s.is_empty())
+                            .map(String::from),
+                    );
+                }
+                "--symbols-from" => {
+                    let file = value()?;
+                    let list = std::fs::read_to_string(&file)
+                        .map_err(|err| format!("Failed to read symbols from {file}, {err}"))?;
+                    options
+                        .symbols
+                        .get_or_insert_with(HashSet::new)
+                        .extend(parse_symbol_list(&list));
+                }
+                "--exclude-symbols" => {
+                    let list = value()?;
+                    options.exclude_symbols.extend(
+                        list.split(',')
+                            .map(str::trim)
+                            .filter(|s| !s.is_empty())
+                            .map(String::from),
+                    );
+                }
+                "--exclude-symbols-from" => {
+                    let file = value()?;
+                    let list = std::fs::read_to_string(&file)
+                        .map_err(|err| format!("Failed to read symbols from {file}, {err}"))?;
+                    options.exclude_symbols.extend(parse_symbol_list(&list));
+                }
+                "--received-at-path" => {
+                    let path = value()?;
+                    match path.split_once('=') {
+                        Some((exchange, pointer)) if pointer.starts_with('/') => {
+                            options
+                                .received_at_paths
+                                .insert(exchange.to_string(), pointer.to_string());
+                        }
+                        _ => return Err(format!("{path} should be <exchange>=/json/pointer")),
+                    }
+                }
+                "--timestamp-unit" => {
+                    options.timestamp_unit = match value()?.as_str() {
+                        "auto" => TimestampUnit::Auto,
+                        "s" => TimestampUnit::Seconds,
+                        "ms" => TimestampUnit::Millis,
+                        "us" => TimestampUnit::Micros,
+                        other => return Err(format!("Unknown timestamp unit {other}")),
+                    };
+                }
+                "--on-error" => {
+                    options.on_error = match value()?.as_str() {
+                        "fail-fast" => OnError::FailFast,
+                        "continue" => OnError::Continue,
+                        other => return Err(format!("Unknown error policy {other}")),
+                    };
+                }
+                "--quarantine-dir" => options.quarantine_dir = Some(value()?),
+                "--files-from" => options.files_from = Some(value()?),
+                "--dedup-dir" => options.dedup_dir = Some(value()?),
+                "--transform" => {
+                    let name = value()?;
+                    match builtin_transform(&name) {
+                        Some(transform) => options.transforms.push(transform),
+                        None => {
+                            return Err(format!(
+                                "Unknown transform {name}, available transforms: {}",
+                                BUILTIN_TRANSFORMS.join(", ")
+                            ))
+                        }
+                    }
+                }
+                "--output" => {
+                    options.output = match value()?.as_str() {
+                        "raw" => OutputKind::Raw,
+                        "parsed" => OutputKind::Parsed,
+                        "both" => OutputKind::Both,
+                        other => return Err(format!("Unknown output {other}")),
+                    };
+                }
+                _ => return Err(format!("Unknown option {name}")),
+            }
+        }
+        if options.combined && options.output != OutputKind::Both {
+            return Err("--combined requires --output both".to_string());
+        }
+        if options.combined && options.l2_reconstruct.is_some() {
+            return Err("--l2-reconstruct can NOT be used with --combined".to_string());
+        }
+        if options.output_format == OutputFormat::Sqlite
+            && (options.combined || !options.output.parsed())
+        {
+            return Err(
+                "--output-format sqlite requires parsed files without --combined".to_string(),
+            );
+        }
+        if options.max_file_size.is_some() && options.l2_reconstruct.is_some() {
+            return Err("--l2-reconstruct can NOT be used with --max-file-size".to_string());
+        }
+        if options.count_only
+            && (options.stats_only_sort
+                || options.l2_reconstruct.is_some()
+                || options.output_format != OutputFormat::Files)
+        {
+            return Err(
+                "--count-only can NOT be used with --stats-only-sort, --l2-reconstruct nor --output-format sqlite"
+                    .to_string(),
+            );
+        }
+        if options.output_json_style == OutputJsonStyle::Array
+            && (options.max_file_size.is_some()
+                || options.stats_only_sort
+                || options.l2_reconstruct.is_some()
+                || options.output_format != OutputFormat::Files)
+        {
+            return Err(
+                "--output-json-style array can NOT be used with --max-file-size, --stats-only-sort, --l2-reconstruct nor --output-format sqlite"
+                    .to_string(),
+            );
+        }
+        if options.input_granularity == InputGranularity::PerSymbol
+            && options.input_pattern == DEFAULT_INPUT_PATTERN
+        {
+            options.input_pattern = PER_SYMBOL_INPUT_PATTERN.to_string();
+        }
+        if options.pin_threads && options.compress_threads.is_some() {
+            // threads of liblzma would share the core of their worker thread
+            return Err("--pin-threads can NOT be used with --compress-threads".to_string());
+        }
+        if options.max_gap_ms.is_some() && !options.stats_only_sort {
+            return Err("--max-gap-secs requires --stats-only-sort".to_string());
+        }
+        if options.l2_depth.is_some() && options.l2_reconstruct.is_some() {
+            return Err("--l2-reconstruct requires full depth, without --l2-depth".to_string());
+        }
+        if options.sort_order == SortOrder::Desc && options.l2_reconstruct.is_some() {
+            return Err("--l2-reconstruct requires --sort-order asc".to_string());
+        }
+        if options.latency_stats && !options.output.parsed() {
+            return Err("--latency-stats requires parsed output".to_string());
+        }
+        if options.dedup_dir.is_some() && options.dedup_scope != DedupScope::Combo {
+            return Err("--dedup-dir requires --dedup-scope combo".to_string());
+        }
+        if options.bloom_dir.is_some()
+            && (options.dedup_scope != DedupScope::Combo || !options.output.raw())
+        {
+            return Err("--bloom-dir requires --dedup-scope combo and raw output".to_string());
+        }
+        options.shard = match (shard_index, shard_count) {
+            (None, None) => None,
+            (Some(index), Some(count)) if index < count => Some(Shard { index, count }),
+            (Some(index), Some(count)) => {
+                return Err(format!(
+                    "--shard-index {index} should be less than --shard-count {count}"
+                ))
+            }
+            _ => return Err("--shard-index and --shard-count must be used together".to_string()),
+        };
+        Ok(options)
+    }
+}
+
+/// Parse a number of bytes with an optional suffix `K`, `M` or `G` of 1024 based units.
+fn parse_bytes(s: &str) -> Result<u64, String> {
+    let (number, unit) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    match number.parse::<u64>().ok().filter(|n| *n > 0) {
+        Some(n) => n
+            .checked_mul(unit)
+            .ok_or_else(|| format!("{s} is not a positive number of bytes")),
+        None => Err(format!("{s} is not a positive number of bytes")),
+    }
+}
+
+fn get_day(timestamp_millis: i64) -> String {
+    let dt = Utc.timestamp_opt(timestamp_millis / 1000, 0).unwrap();
+    dt.format("%Y-%m-%d").to_string()
+}
+
+fn get_hour(timestamp_millis: i64) -> String {
+    let dt = Utc.timestamp_opt(timestamp_millis / 1000, 0).unwrap();
+    dt.format("%Y-%m-%d-%H").to_string()
+}
+
+/// A counting semaphore, waiters are woken up when a permit is released instead of polling.
+struct Semaphore {
+    permits: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Block until a permit is available, it is released when the guard is dropped, even if
+    /// the holder panics.
+    fn acquire(&self) -> SemaphorePermit<'_> {
+        self.wait();
+        SemaphorePermit { semaphore: self }
+    }
+
+    /// Like `acquire()`, but the guard can be moved to other threads.
+    fn acquire_owned(self: &Arc<Self>) -> OwnedSemaphorePermit {
+        self.wait();
+        OwnedSemaphorePermit {
+            semaphore: self.clone(),
+        }
+    }
+
+    fn wait(&self) {
+        let mut permits = self.permits.lock().unwrap();
+        if *permits == 0 {
+            debug!("Waiting for semaphore");
+        }
+        while *permits == 0 {
+            permits = self.released.wait(permits).unwrap();
+        }
+        *permits -= 1;
+    }
+
+    fn release(&self) {
+        *self.permits.lock().unwrap() += 1;
+        self.released.notify_one();
+    }
+}
+
+struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.release();
+    }
+}
+
+struct OwnedSemaphorePermit {
+    semaphore: Arc<Semaphore>,
+}
+
+impl Drop for OwnedSemaphorePermit {
+    fn drop(&mut self) {
+        self.semaphore.release();
+    }
+}
+
+/// Memory budget shared by the split and sort stages of all days, in bytes.
+///
+/// Only large allocations are accounted, i.e., buffers of open splitted files, hashes of
+/// visited messages and lines loaded by sort jobs. Nothing is accounted without a limit.
+struct MemoryBudget {
+    limit: Option<u64>,
+    used: Mutex<u64>,
+    released: Condvar,
+}
+
+impl MemoryBudget {
+    fn new(limit: Option<u64>) -> Self {
+        MemoryBudget {
+            limit,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Reserve `bytes` if they fit in the limit.
+    fn try_reserve(&self, bytes: u64) -> bool {
+        if let Some(limit) = self.limit {
+            let mut used = self.used.lock().unwrap();
+            if *used + bytes > limit {
+                return false;
+            }
+            *used += bytes;
+        }
+        true
+    }
+
+    /// Reserve `bytes` of memory which is in use already, regardless of the limit.
+    fn force_reserve(&self, bytes: u64) {
+        if self.limit.is_some() {
+            *self.used.lock().unwrap() += bytes;
+        }
+    }
+
+    /// Block until `bytes` fit in the limit, or nothing else is reserved, so that a job
+    /// larger than the limit still runs alone.
+    fn reserve(&self, bytes: u64) -> Reservation<'_> {
+        if let Some(limit) = self.limit {
+            let mut used = self.used.lock().unwrap();
+            while *used > 0 && *used + bytes > limit {
+                used = self.released.wait(used).unwrap();
+            }
+            *used += bytes;
+        }
+        Reservation {
+            budget: self,
+            bytes,
+        }
+    }
+
+    fn release(&self, bytes: u64) {
+        if self.limit.is_some() {
+            let mut used = self.used.lock().unwrap();
+            *used = used.saturating_sub(bytes);
+            self.released.notify_all();
+        }
+    }
+
+    fn used(&self) -> u64 {
+        *self.used.lock().unwrap()
+    }
+
+    fn exceeded(&self) -> bool {
+        self.limit
+            .is_some_and(|limit| *self.used.lock().unwrap() > limit)
+    }
+}
+
+/// Bytes written to output directories by the split and sort stages of all days, for
+/// `--max-output-bytes`.
+///
+/// Splitted files are accounted by their uncompressed lines, which over-estimates gzip files,
+/// and sorted files by their sizes, so the limit is reached before the disk is full.
+struct OutputBudget {
+    limit: Option<u64>,
+    written: AtomicU64,
+}
+
+impl OutputBudget {
+    fn new(limit: Option<u64>) -> Self {
+        OutputBudget {
+            limit,
+            written: AtomicU64::new(0),
+        }
+    }
+
+    fn add(&self, bytes: u64) {
+        if self.limit.is_some() {
+            self.written.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Nothing more should be written.
+    fn exceeded(&self) -> bool {
+        self.limit
+            .is_some_and(|limit| self.written.load(Ordering::Relaxed) >= limit)
+    }
+
+    fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+}
+
+/// Available bytes of the disk which `path` is on, None if unknown.
+fn available_space(path: &Path) -> Option<u64> {
+    use sysinfo::{DiskExt, System, SystemExt};
+    let path = path.canonicalize().ok()?;
+    let mut system = System::new();
+    system.refresh_disks_list();
+    system
+        .disks()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| disk.available_space())
+}
+
+/// Reserved memory, released when dropped.
+struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: u64,
+}
+
+impl Reservation<'_> {
+    /// Reserve more memory which is in use already.
+    fn grow(&mut self, bytes: u64) {
+        self.budget.force_reserve(bytes);
+        self.bytes += bytes;
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+// Output to a raw file or a parsed file.
+#[derive(Clone)]
+struct Output(Arc<Mutex<OutputFile>>);
+
+struct OutputFile {
+    path: PathBuf,
+    // None if the file is not open
+    writer: Option<Box<dyn std::io::Write + Send>>,
+    // uncompressed lines of a small file not written yet
+    buffer: Option<Vec<u8>>,
+    created: bool,
+    last_used: u64,
+}
+
+impl OutputFile {
+    // The file is truncated the first time, and appended later, so a file closed
+    // and re-opened contains multiple gzip members.
+    fn open(&mut self) {
+        let f_out = self.create_or_append();
+        self.writer = if self.path.extension().is_some_and(|ext| ext == "gz") {
+            Some(Box::new(std::io::BufWriter::new(GzEncoder::new(
+                f_out,
+                Compression::default(),
+            ))))
+        } else {
+            Some(Box::new(std::io::BufWriter::new(f_out)))
+        };
+    }
+
+    fn create_or_append(&mut self) -> std::fs::File {
+        let f_out = if self.created {
+            std::fs::OpenOptions::new()
+                .append(true)
+                .open(self.path.as_path())
+                .unwrap()
+        } else {
+            std::fs::create_dir_all(self.path.parent().unwrap()).unwrap();
+            std::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(self.path.as_path())
+                .unwrap()
+        };
+        self.created = true;
+        f_out
+    }
+
+    /// Compress the buffered lines in memory and write them with one call, returns
+    /// the number of buffered bytes.
+    fn write_buffer(&mut self) -> usize {
+        if let Some(buffer) = self.buffer.take() {
+            let len = buffer.len();
+            let content = if self.path.extension().is_some_and(|ext| ext == "gz") {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&buffer).unwrap();
+                encoder.finish().unwrap()
+            } else {
+                buffer
+            };
+            self.create_or_append().write_all(&content).unwrap();
+            len
+        } else {
+            0
+        }
+    }
+
+    fn close(&mut self) -> bool {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush().unwrap();
+            true // the gzip trailer is written when the writer is dropped
+        } else {
+            false
+        }
+    }
+}
+
+/// Output files of one split stage.
+///
+/// At most `max_open_files` files are open at the same time, the least recently
+/// used file is closed when the limit is reached, and will be re-opened in append mode.
+/// The least recently used file is also closed if `memory_budget` is exhausted.
+///
+/// With `buffer_small_files`, a file starts as a buffer in memory, neither open nor counted
+/// in `max_open_files`, and is only opened once its lines exceed that many bytes or the
+/// `memory_budget`, so that the many small files of exchanges with many instruments are
+/// compressed and written at once by `close_all()`.
+struct SplittedFiles {
+    outputs: DashMap<PathBuf, Output>,
+    max_open_files: usize,
+    buffer_small_files: Option<u64>,
+    memory_budget: Arc<MemoryBudget>,
+    output_budget: Arc<OutputBudget>,
+    /// Append to existing files left by a previous run instead of truncating them
+    append: bool,
+    open_files: AtomicUsize,
+    clock: AtomicU64,
+    /// Bytes of lines written, before compression
+    uncompressed_bytes: AtomicU64,
+}
+
+impl SplittedFiles {
+    fn new(
+        max_open_files: usize,
+        buffer_small_files: Option<u64>,
+        append: bool,
+        memory_budget: Arc<MemoryBudget>,
+        output_budget: Arc<OutputBudget>,
+    ) -> Self {
+        SplittedFiles {
+            outputs: DashMap::new(),
+            max_open_files,
+            buffer_small_files,
+            memory_budget,
+            output_budget,
+            append,
+            open_files: AtomicUsize::new(0),
+            clock: AtomicU64::new(0),
+            uncompressed_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Write a line to `output_file`, which is created the first time.
+    fn write_line(&self, output_file: PathBuf, line: &str) {
+        self.output_budget.add(line.len() as u64 + 1);
+        self.uncompressed_bytes
+            .fetch_add(line.len() as u64 + 1, Ordering::Relaxed);
+        self.with_writer(output_file, |writer| writeln!(writer, "{line}").unwrap());
+    }
+
+    /// Write newline-terminated lines to `output_file` while holding the lock only once.
+    fn write_batch(&self, output_file: PathBuf, lines: &str) {
+        self.output_budget.add(lines.len() as u64);
+        self.uncompressed_bytes
+            .fetch_add(lines.len() as u64, Ordering::Relaxed);
+        self.with_writer(output_file, |writer| {
+            writer.write_all(lines.as_bytes()).unwrap()
+        });
+    }
+
+    fn with_writer(&self, output_file: PathBuf, f: impl FnOnce(&mut dyn Write)) {
+        // `.entry().or_insert_with()` is atomic, see https://github.com/xacrimon/dashmap/issues/78
+        let output = self
+            .outputs
+            .entry(output_file.clone())
+            .or_insert_with(move || {
+                let created = self.append && output_file.exists();
+                Output(Arc::new(Mutex::new(OutputFile {
+                    path: output_file,
+                    writer: None,
+                    buffer: self.buffer_small_files.map(|_| Vec::new()),
+                    created,
+                    last_used: 0,
+                })))
+            })
+            .value()
+            .clone();
+        let mut output_file = output.0.lock().unwrap();
+        if let Some(buffer) = output_file.buffer.as_mut() {
+            let len = buffer.len();
+            f(buffer);
+            let grown = (buffer.len() - len) as u64;
+            if buffer.len() as u64 <= self.buffer_small_files.unwrap()
+                && self.memory_budget.try_reserve(grown)
+            {
+                return;
+            }
+            // too large to buffer, streamed from now on
+            let buffer = output_file.buffer.take().unwrap();
+            self.memory_budget.release(len as u64);
+            self.open(&mut output_file);
+            output_file.last_used = self.clock.fetch_add(1, Ordering::Relaxed);
+            output_file
+                .writer
+                .as_mut()
+                .unwrap()
+                .write_all(&buffer)
+                .unwrap();
+            return;
+        }
+        if output_file.writer.is_none() {
+            self.open(&mut output_file);
+        }
+        output_file.last_used = self.clock.fetch_add(1, Ordering::Relaxed);
+        f(output_file.writer.as_mut().unwrap());
+    }
+
+    fn open(&self, output_file: &mut OutputFile) {
+        let over_budget = !self.memory_budget.try_reserve(OPEN_FILE_BYTES);
+        if self.open_files.fetch_add(1, Ordering::SeqCst) >= self.max_open_files || over_budget {
+            self.close_least_recently_used();
+        }
+        if over_budget {
+            // takes over the memory released by the closed file
+            self.memory_budget.force_reserve(OPEN_FILE_BYTES);
+        }
+        output_file.open();
+    }
+
+    fn close_least_recently_used(&self) {
+        // files being written by other threads are skipped
+        let lru = self
+            .outputs
+            .iter()
+            .filter_map(|entry| {
+                let output_file = entry.value().0.try_lock().ok()?;
+                if output_file.writer.is_some() {
+                    Some((output_file.last_used, entry.value().clone()))
+                } else {
+                    None
+                }
+            })
+            .min_by_key(|(last_used, _)| *last_used);
+        if let Some((_, output)) = lru {
+            if let Ok(mut output_file) = output.0.try_lock() {
+                if output_file.close() {
+                    self.open_files.fetch_sub(1, Ordering::SeqCst);
+                    self.memory_budget.release(OPEN_FILE_BYTES);
+                }
+            }
+        }
+    }
+
+    /// Bytes of all files on disk, after `close_all()`.
+    fn file_bytes(&self) -> u64 {
+        self.outputs
+            .iter()
+            .map(|entry| std::fs::metadata(entry.key()).map_or(0, |m| m.len()))
+            .sum()
+    }
+
+    /// Flush and close all files, and write buffered files.
+    fn close_all(&self) {
+        for entry in self.outputs.iter() {
+            let mut output_file = entry.value().0.lock().unwrap();
+            let buffered = output_file.write_buffer();
+            self.memory_budget.release(buffered as u64);
+            if output_file.close() {
+                self.open_files.fetch_sub(1, Ordering::SeqCst);
+                self.memory_budget.release(OPEN_FILE_BYTES);
+            }
+        }
+    }
+}
+
+/// Phases timed by `--profile`.
+#[derive(Clone, Copy)]
+enum Phase {
+    Decode,
+    Parse,
+    Hash,
+    Sort,
+    Write,
+}
+
+const PHASES: [(Phase, &str); 5] = [
+    (Phase::Decode, "decode"),
+    (Phase::Parse, "parse"),
+    (Phase::Hash, "hash"),
+    (Phase::Sort, "sort"),
+    (Phase::Write, "write"),
+];
+
+/// Wall time spent in each phase, summed over all worker threads.
+struct Profile {
+    enabled: bool,
+    nanos: [AtomicU64; 5],
+}
+
+impl Profile {
+    fn new(enabled: bool) -> Self {
+        Profile {
+            enabled,
+            nanos: Default::default(),
+        }
+    }
+
+    /// Run `f` and add its elapsed time to `phase`, does nothing extra if disabled.
+    fn time<T>(&self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        if !self.enabled {
+            return f();
+        }
+        let start = Instant::now();
+        let result = f();
+        self.nanos[phase as usize].fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        result
+    }
+
+    fn report(
+        &self,
+        stage: &str,
+        exchange: &str,
+        market_type: MarketType,
+        msg_type: MessageType,
+        day: &str,
+    ) {
+        let total: u64 = self.nanos.iter().map(|n| n.load(Ordering::Relaxed)).sum();
+        let breakdown = PHASES
+            .iter()
+            .map(|(phase, name)| {
+                let nanos = self.nanos[*phase as usize].load(Ordering::Relaxed);
+                format!(
+                    "{} {:.3}s ({:.1}%)",
+                    name,
+                    nanos as f64 / 1e9,
+                    if total > 0 {
+                        nanos as f64 * 100.0 / total as f64
+                    } else {
+                        0.0
+                    }
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+        info!(
+            "Profile of {} {} {} {} {}: {}",
+            stage, exchange, market_type, msg_type, day, breakdown
+        );
+    }
+}
+
+/// Set `msg.received_at` from `msg.json` by the JSON pointer configured for the exchange,
+/// returns false if there is no pointer or nothing is found.
+fn extract_received_at(msg: &mut Message, options: &Options) -> bool {
+    let received_at = options
+        .received_at_paths
+        .get(&msg.exchange)
+        .and_then(|pointer| {
+            let json = serde_json::from_str::<Value>(&msg.json).ok()?;
+            match json.pointer(pointer)? {
+                Value::Number(n) => n.as_u64(),
+                Value::String(s) => s.parse::<u64>().ok(),
+                _ => None,
+            }
+        });
+    if let Some(received_at) = received_at {
+        msg.received_at = received_at;
+        true
+    } else {
+        false
+    }
+}
+
+/// Parse a line of input files, with `--normalize-received-at` a `received_at` stored as a
+/// string or float is converted to an integer, and the returned flag is true.
+fn parse_envelope(line: &str, options: &Options) -> Option<(Message, bool)> {
+    match serde_json::from_str::<Message>(line) {
+        Ok(msg) => Some((msg, false)),
+        Err(_) if options.normalize_received_at => {
+            let mut envelope = serde_json::from_str::<serde_json::Map<String, Value>>(line).ok()?;
+            let received_at = normalize_received_at(envelope.get("received_at")?)?;
+            envelope.insert("received_at".to_string(), Value::from(received_at));
+            serde_json::from_value::<Message>(Value::Object(envelope))
+                .ok()
+                .map(|msg| (msg, true))
+        }
+        Err(_) => None,
+    }
+}
+
+/// An integer of a `received_at` stored as a number or a numeric string, fractional seconds
+/// are converted to milliseconds, other values are rounded in their own unit.
+fn normalize_received_at(received_at: &Value) -> Option<u64> {
+    if let Some(n) = received_at.as_u64() {
+        return Some(n);
+    }
+    let f = match received_at {
+        Value::Number(n) => n.as_f64()?,
+        Value::String(s) => s.trim().parse::<f64>().ok()?,
+        _ => return None,
+    };
+    if !f.is_finite() || f < 0.0 {
+        None
+    } else if f.fract() != 0.0 && TimestampUnit::detect(f as u64) == TimestampUnit::Seconds {
+        Some((f * 1000.0).round() as u64)
+    } else {
+        Some(f.round() as u64)
+    }
+}
+
+/// Returns why an input file should be quarantined, None if it is good enough.
+fn quarantine_reason(
+    exchange: &str,
+    total_lines: i64,
+    error_lines: i64,
+    corrupted: bool,
+) -> Option<String> {
+    if corrupted {
+        Some("truncated or corrupted gzip".to_string())
+    } else if (error_lines as f64) / (total_lines.max(1) as f64) > 0.01
+        && !EXEMPTED_EXCHANGES.contains(&exchange)
+    {
+        Some(format!("{error_lines}/{total_lines} malformed lines"))
+    } else {
+        None
+    }
+}
+
+/// Verify `input_file` against its `.sha256`, `.sha1` or `.md5` sidecar file, the algorithm
+/// is decided by the extension.
+///
+/// Returns `Ok(false)` if there is no sidecar file.
+fn verify_checksum(input_file: &Path) -> Result<bool, String> {
+    fn digest<D: sha2::Digest + Write>(input_file: &Path) -> Result<String, String> {
+        let mut f_in = std::fs::File::open(input_file).map_err(|err| err.to_string())?;
+        let mut hasher = D::new();
+        std::io::copy(&mut f_in, &mut hasher).map_err(|err| err.to_string())?;
+        Ok(hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect())
+    }
+    for extension in ["sha256", "sha1", "md5"] {
+        let sidecar_file = {
+            let mut path = input_file.as_os_str().to_os_string();
+            path.push(".");
+            path.push(extension);
+            PathBuf::from(path)
+        };
+        if !sidecar_file.exists() {
+            continue;
+        }
+        // the format of sha256sum, i.e., `<checksum>  <file name>`, or the checksum only
+        let expected = std::fs::read_to_string(&sidecar_file)
+            .map_err(|err| format!("Failed to read {}, {}", sidecar_file.display(), err))?
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        let actual = match extension {
+            "sha256" => digest::<sha2::Sha256>(input_file)?,
+            "sha1" => digest::<sha1::Sha1>(input_file)?,
+            _ => digest::<md5::Md5>(input_file)?,
+        };
+        return if actual == expected {
+            Ok(true)
+        } else {
+            Err(format!(
+                "{} checksum mismatch, expected {}, actual {}",
+                extension, expected, actual
+            ))
+        };
+    }
+    Ok(false)
+}
+
+/// Decode `input_file` fully, so that a CRC or length mismatch of the gzip trailer is found
+/// before any line is processed, plain `.json` files are always good.
+fn verify_gzip(input_file: &Path) -> Result<(), String> {
+    if InputFormat::detect(input_file).unwrap_or_else(|| InputFormat::from_extension(input_file))
+        != InputFormat::Gzip
+    {
+        return Ok(());
+    }
+    std::io::copy(&mut open_input_file(input_file), &mut std::io::sink())
+        .map(|_| ())
+        .map_err(|err| format!("corrupted gzip, {err}"))
+}
+
+/// Print the first `n` parsed messages of `input_files` to stdout with transforms applied,
+/// returns the number of printed messages.
+fn preview(input_files: &[PathBuf], n: usize, options: &Options) -> usize {
+    let mut printed = 0;
+    let mut stdout = std::io::stdout().lock();
+    for input_file in input_files {
+        let lines = BoundedLines {
+            reader: std::io::BufReader::new(open_input_file(input_file)),
+            max_bytes: options.max_line_bytes,
+        };
+        for line in lines {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) if line_too_long(&err).is_some() => {
+                    warn!("{}, {}", input_file.display(), err);
+                    continue;
+                }
+                Err(err) => {
+                    warn!("{}, {}", input_file.display(), err);
+                    break;
+                }
+            };
+            let parsed = serde_json::from_str::<Message>(&line)
+                .map_err(|err| err.to_string())
+                .and_then(|msg| parse_message(&msg));
+            match parsed {
+                Ok(messages) => {
+                    for message in messages {
+                        let json = serde_json::to_string(&message).unwrap();
+                        writeln!(stdout, "{}", apply_transforms(json, &options.transforms))
+                            .unwrap();
+                        printed += 1;
+                        if printed >= n {
+                            return printed;
+                        }
+                    }
+                }
+                Err(err) => warn!("{}, {}", err, line),
+            }
+        }
+    }
+    printed
+}
+
+/// Assert that `msg.json` is non-empty valid JSON, catching collector bugs in debug builds,
+/// it compiles out in release builds.
+fn debug_assert_message(msg: &Message) {
+    debug_assert!(
+        !msg.json.is_empty(),
+        "Empty json of {} {} {} received at {}",
+        msg.exchange,
+        msg.market_type,
+        msg.msg_type,
+        msg.received_at
+    );
+    debug_assert!(
+        serde_json::from_str::<serde::de::IgnoredAny>(&msg.json).is_ok(),
+        "json of {} {} {} is not valid JSON: {}",
+        msg.exchange,
+        msg.market_type,
+        msg.msg_type,
+        msg.json
+    );
+}
+
+/// Symbols of a `--symbols-from` or `--exclude-symbols-from` file, one per line, blank lines and lines starting with `#`
+/// are ignored.
+fn parse_symbol_list(list: &str) -> impl Iterator<Item = String> + '_ {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+}
+
+/// Whether messages of `symbol` are selected by `--symbols` and `--symbols-from`.
+fn is_selected_symbol(symbol: &str, symbols: Option<&HashSet<String>>) -> bool {
+    symbols.is_none_or(|symbols| symbols.contains(symbol))
+}
+
+/// Slice `index` of `count` slices of symbols, so that `count` machines process disjoint
+/// symbols of the same days.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+struct Shard {
+    index: u64,
+    count: u64,
+}
+
+impl Shard {
+    /// Whether `symbol` belongs to this shard. `DefaultHasher::new()` has fixed keys, so
+    /// machines running the same build agree on the partitioning.
+    fn contains(self, symbol: &str) -> bool {
+        let mut hasher = DefaultHasher::new();
+        symbol.hash(&mut hasher);
+        hasher.finish() % self.count == self.index
+    }
+}
+
+/// Whether messages of `symbol` belong to the shard of this invocation, if any.
+fn is_in_shard(symbol: &str, shard: Option<Shard>) -> bool {
+    shard.is_none_or(|shard| shard.contains(symbol))
+}
+
+/// Suffix of markers, manifests and staging directories of a shard, e.g., `.shard-3-of-8`,
+/// so that shards sharing output directories do not overwrite or remove those of others.
+fn shard_suffix(shard: Option<Shard>) -> String {
+    match shard {
+        Some(shard) => format!(".shard-{}-of-{}", shard.index, shard.count),
+        None => String::new(),
+    }
+}
+
+/// Record `symbol` and return true if there are more than `max_symbols` distinct symbols.
+fn too_many_symbols(symbols: &DashSet<String>, symbol: &str, max_symbols: Option<usize>) -> bool {
+    if !symbols.contains(symbol) {
+        symbols.insert(symbol.to_string());
+    }
+    max_symbols.is_some_and(|max_symbols| symbols.len() > max_symbols)
+}
+
+/// A line of `quarantine_dir/quarantine.jsonl`.
+#[derive(Serialize)]
+struct QuarantineRecord<'a> {
+    path: String,
+    reason: &'a str,
+}
+
+/// Move quarantined files from `input_dir` to `quarantine_dir` keeping their relative
+/// paths, and append their original paths and reasons to `quarantine_dir/quarantine.jsonl`.
+///
+/// Files outside `input_dir`, e.g., of `--files-from`, keep their whole paths under
+/// `quarantine_dir`, so that files of the same name in different directories do not collide.
+fn move_to_quarantine(
+    input_dir: &str,
+    quarantine_dir: &str,
+    quarantined: &DashMap<PathBuf, String>,
+) {
+    if quarantined.is_empty() {
+        return;
+    }
+    std::fs::create_dir_all(quarantine_dir).unwrap();
+    let mut manifest = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(Path::new(quarantine_dir).join("quarantine.jsonl"))
+        .unwrap();
+    for entry in quarantined.iter() {
+        let (input_file, reason) = entry.pair();
+        let relative = match input_file.strip_prefix(input_dir) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => input_file
+                .components()
+                .filter(|component| matches!(component, Component::Normal(_)))
+                .collect(),
+        };
+        let quarantined_file = Path::new(quarantine_dir).join(relative);
+        std::fs::create_dir_all(quarantined_file.parent().unwrap()).unwrap();
+        if std::fs::rename(input_file, quarantined_file.as_path()).is_err() {
+            // on different filesystems
+            std::fs::copy(input_file, quarantined_file.as_path()).unwrap();
+            std::fs::remove_file(input_file).unwrap();
+        }
+        let record = QuarantineRecord {
+            path: input_file.display().to_string(),
+            reason,
+        };
+        writeln!(manifest, "{}", serde_json::to_string(&record).unwrap()).unwrap();
+        warn!(
+            "Moved {} to {}, because of {}",
+            input_file.display(),
+            quarantined_file.display(),
+            reason
+        );
+    }
+}
+
+/// Open an input file, plain `.json` files are read as is, others are gzip compressed.
+fn open_input_file(input_file: &Path) -> Box<dyn Read> {
+    let f_in = std::fs::File::open(input_file)
+        .unwrap_or_else(|_| panic!("{:?} does not exist", input_file.display()));
+    let mut reader = std::io::BufReader::new(f_in);
+    // the content decides, e.g., a gzip file named .json
+    let format = match reader.fill_buf() {
+        Ok(head) if !head.is_empty() => InputFormat::from_magic(head),
+        _ => InputFormat::from_extension(input_file),
+    };
+    match format {
+        InputFormat::Plain => Box::new(reader),
+        // sorted raw files read by reparse
+        InputFormat::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(reader)),
+        InputFormat::Gzip => Box::new(GzDecoder::new(reader)),
+    }
+}
+
+/// Compression of an input file.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum InputFormat {
+    Plain,
+    Gzip,
+    Xz,
+}
+
+impl InputFormat {
+    /// Guess the format by the extension, `.json` is plain, `.xz` is xz and others are gzip.
+    fn from_extension(input_file: &Path) -> Self {
+        match input_file.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => InputFormat::Plain,
+            Some("xz") => InputFormat::Xz,
+            _ => InputFormat::Gzip,
+        }
+    }
+
+    /// Detect the format by the magic bytes at the beginning of a file.
+    fn from_magic(head: &[u8]) -> Self {
+        if head.starts_with(&[0x1f, 0x8b]) {
+            InputFormat::Gzip
+        } else if head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            InputFormat::Xz
+        } else {
+            InputFormat::Plain
+        }
+    }
+
+    /// Detect the format of a file by its content, None if it is empty or unreadable.
+    fn detect(input_file: &Path) -> Option<Self> {
+        let mut head = Vec::with_capacity(6);
+        std::fs::File::open(input_file)
+            .ok()?
+            .take(6)
+            .read_to_end(&mut head)
+            .ok()?;
+        if head.is_empty() {
+            None
+        } else {
+            Some(InputFormat::from_magic(&head))
+        }
+    }
+}
+
+/// The error of a line longer than the limit of `BoundedLines`, with its length in bytes.
+#[derive(Debug)]
+struct LineTooLong(u64);
+
+impl std::fmt::Display for LineTooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line of {} bytes is too long", self.0)
+    }
+}
+
+impl std::error::Error for LineTooLong {}
+
+/// Length of a line skipped by `BoundedLines`, None for other errors.
+fn line_too_long(err: &std::io::Error) -> Option<u64> {
+    err.get_ref()?.downcast_ref::<LineTooLong>().map(|e| e.0)
+}
+
+/// Like `BufRead::lines()`, but a line longer than `max_bytes` is read up to its newline
+/// without being buffered, and returned as a `LineTooLong` error, so that a corrupted
+/// file with no newline for gigabytes can NOT exhaust memory.
+struct BoundedLines<R> {
+    reader: R,
+    max_bytes: u64,
+}
+
+impl<R: BufRead> Iterator for BoundedLines<R> {
+    type Item = std::io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buf: Vec<u8> = Vec::new();
+        // bytes of the line read so far, including those not buffered
+        let mut len: u64 = 0;
+        let mut eof = true;
+        loop {
+            let available = match self.reader.fill_buf() {
+                Ok(available) => available,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Some(Err(err)),
+            };
+            if available.is_empty() {
+                break;
+            }
+            eof = false;
+            let newline = available.iter().position(|b| *b == b'\n');
+            let chunk = &available[..newline.unwrap_or(available.len())];
+            if len + chunk.len() as u64 <= self.max_bytes {
+                buf.extend_from_slice(chunk);
+            }
+            len += chunk.len() as u64;
+            let consumed = newline.map_or(chunk.len(), |pos| pos + 1);
+            self.reader.consume(consumed);
+            if newline.is_some() {
+                break;
+            }
+        }
+        if eof {
+            return None;
+        }
+        if len > self.max_bytes {
+            return Some(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                LineTooLong(len),
+            )));
+        }
+        if buf.last() == Some(&b'\r') {
+            buf.pop();
+        }
+        Some(String::from_utf8(buf).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "stream did not contain valid UTF-8",
+            )
+        }))
+    }
+}
+
+/// Whether `input_file` is a `.tar.gz` or `.tgz` archive of multiple files.
+fn is_tar_archive(input_file: &Path) -> bool {
+    let file_name = input_file.to_str().unwrap_or_default();
+    file_name.ends_with(".tar.gz") || file_name.ends_with(".tgz")
+}
+
+/// Lines of all `.json` and `.json.gz` entries of a tar archive, in the order of entries.
+///
+/// Entries whose names do not start with the exchange, market type and msg type of the archive are skipped.
+fn tar_lines<'a, R: Read + 'a>(
+    archive: &'a mut tar::Archive<R>,
+    file_name_parts: &'a FileNameParts,
+    max_line_bytes: u64,
+) -> Box<dyn Iterator<Item = std::io::Result<String>> + 'a> {
+    let entries = match archive.entries() {
+        Ok(entries) => entries,
+        Err(err) => return Box::new(std::iter::once(Err(err))),
+    };
+    Box::new(entries.flat_map(
+        move |entry| -> Box<dyn Iterator<Item = std::io::Result<String>> + 'a> {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => return Box::new(std::iter::once(Err(err))),
+            };
+            if !entry.header().entry_type().is_file() {
+                return Box::new(std::iter::empty());
+            }
+            let entry_name = entry
+                .path()
+                .ok()
+                .and_then(|path| path.file_name().and_then(|s| s.to_str()).map(String::from))
+                .unwrap_or_default();
+            match FileNameParts::from_str(&entry_name) {
+                Ok(parts)
+                    if parts.exchange == file_name_parts.exchange
+                        && parts.market_type == file_name_parts.market_type
+                        && parts.msg_type == file_name_parts.msg_type => {}
+                _ => {
+                    warn!("Skipped tar entry {}", entry_name);
+                    return Box::new(std::iter::empty());
+                }
+            }
+            if entry_name.ends_with(".gz") {
+                Box::new(BoundedLines {
+                    reader: std::io::BufReader::new(MultiGzDecoder::new(entry)),
+                    max_bytes: max_line_bytes,
+                })
+            } else {
+                Box::new(BoundedLines {
+                    reader: std::io::BufReader::new(entry),
+                    max_bytes: max_line_bytes,
+                })
+            }
+        },
+    ))
+}
+
+/// Memoizes `crypto_pair::normalize_pair()`, so that each `(symbol, exchange)` is normalized once.
+///
+/// Pairs are keyed by exchange, then by symbol, so that a hit neither allocates a key nor clones
+/// a pair.
+struct PairCache {
+    pairs: DashMap<String, DashMap<String, Option<Arc<str>>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl PairCache {
+    fn new() -> Self {
+        PairCache {
+            pairs: DashMap::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// None if `crypto_pair` can NOT normalize the symbol.
+    fn normalize_pair(&self, symbol: &str, exchange: &str) -> Option<Arc<str>> {
+        if let Some(pairs) = self.pairs.get(exchange) {
+            if let Some(pair) = pairs.get(symbol) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return pair.clone();
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let pair: Option<Arc<str>> = crypto_pair::normalize_pair(symbol, exchange).map(Arc::from);
+        self.pairs
+            .entry(exchange.to_string())
+            .or_default()
+            .insert(symbol.to_string(), pair.clone());
+        pair
+    }
+
+    /// Number of distinct symbols normalized.
+    fn len(&self) -> usize {
+        self.pairs.iter().map(|pairs| pairs.len()).sum()
+    }
+
+    fn hit_rate(&self) -> f64 {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        hits as f64 / (hits + misses).max(1) as f64
+    }
+}
+
+/// Counts bytes read from the inner reader.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> CountingReader<R> {
+    fn new(inner: R) -> Self {
+        CountingReader { inner, count: 0 }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Failed jobs of a stage, shared by worker threads.
+struct Failures {
+    on_error: OnError,
+    failed: AtomicUsize,
+    skipped: AtomicUsize,
+}
+
+impl Failures {
+    fn new(on_error: OnError) -> Self {
+        Failures {
+            on_error,
+            failed: AtomicUsize::new(0),
+            skipped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Run the job of `input_file`, returns None if it panics, or if it is skipped
+    /// because another job has failed with `OnError::FailFast`.
+    fn run<T>(&self, input_file: &Path, job: impl FnOnce() -> T) -> Option<T> {
+        if self.on_error == OnError::FailFast && self.failed.load(Ordering::SeqCst) > 0 {
+            self.skipped.fetch_add(1, Ordering::SeqCst);
+            return None;
+        }
+        match std::panic::catch_unwind(AssertUnwindSafe(job)) {
+            Ok(t) => Some(t),
+            Err(_) => {
+                error!("Failed to process {}", input_file.display());
+                self.failed.fetch_add(1, Ordering::SeqCst);
+                None
+            }
+        }
+    }
+
+    /// Log the outcome, returns false if the stage should fail.
+    fn report(
+        &self,
+        stage: &str,
+        exchange: &str,
+        market_type: MarketType,
+        msg_type: MessageType,
+        day: &str,
+    ) -> bool {
+        let failed = self.failed.load(Ordering::SeqCst);
+        let skipped = self.skipped.load(Ordering::SeqCst);
+        if failed == 0 {
+            return true;
+        }
+        match self.on_error {
+            OnError::FailFast => {
+                error!(
+                    "Aborted {} {} {} {} {}, {} jobs failed, {} jobs skipped",
+                    stage, exchange, market_type, msg_type, day, failed, skipped
+                );
+                false
+            }
+            OnError::Continue => {
+                error!(
+                    "{} jobs of {} {} {} {} {} failed, continued with the other files",
+                    failed, stage, exchange, market_type, msg_type, day
+                );
+                true
+            }
+        }
+    }
+}
+
+/// Hash a JSON message for deduplication.
+///
+/// If `canonical` is true, the message is parsed and keys of objects are hashed in sorted
+/// order, so that a message re-serialized with reordered keys is still a duplicate.
+fn hash_json(json: &str, canonical: bool) -> u64 {
+    fn hash_value(value: &Value, hasher: &mut DefaultHasher) {
+        match value {
+            Value::Object(map) => {
+                '{'.hash(hasher);
+                map.len().hash(hasher);
+                let mut keys: Vec<&String> = map.keys().collect();
+                keys.sort();
+                for key in keys {
+                    key.hash(hasher);
+                    hash_value(&map[key], hasher);
+                }
+            }
+            Value::Array(values) => {
+                '['.hash(hasher);
+                values.len().hash(hasher);
+                for value in values {
+                    hash_value(value, hasher);
+                }
+            }
+            _ => value.to_string().hash(hasher),
+        }
+    }
+
+    let mut hasher = DefaultHasher::new();
+    match if canonical {
+        serde_json::from_str::<Value>(json).ok()
+    } else {
+        None
+    } {
+        Some(value) => hash_value(&value, &mut hasher),
+        _ => json.hash(&mut hasher),
+    }
+    hasher.finish()
+}
+
+/// Why an input line produces no output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RejectReason {
+    /// Not a valid `Message`
+    JsonEnvelopeParse,
+    /// `extract_symbol()` failed
+    SymbolExtraction,
+    /// `crypto_pair` can NOT normalize the symbol
+    PairNormalization,
+    /// `parse_trade()` or `parse_l2()` failed
+    ParserError,
+    /// The message does not belong to the day
+    OutOfDay,
+    /// The rest of a corrupted file is unreadable
+    Truncated,
+    /// Longer than `--json-lines-limit-bytes`
+    LineTooLong,
+}
+
+const REJECT_REASONS: [RejectReason; 7] = [
+    RejectReason::JsonEnvelopeParse,
+    RejectReason::SymbolExtraction,
+    RejectReason::PairNormalization,
+    RejectReason::ParserError,
+    RejectReason::OutOfDay,
+    RejectReason::Truncated,
+    RejectReason::LineTooLong,
+];
+
+impl RejectReason {
+    fn name(self) -> &'static str {
+        match self {
+            RejectReason::JsonEnvelopeParse => "json_envelope_parse",
+            RejectReason::SymbolExtraction => "symbol_extraction",
+            RejectReason::PairNormalization => "pair_normalization",
+            RejectReason::ParserError => "parser_error",
+            RejectReason::OutOfDay => "out_of_day",
+            RejectReason::Truncated => "truncated",
+            RejectReason::LineTooLong => "line_too_long",
+        }
+    }
+}
+
+/// Rejected lines of the raw or parsed jobs of a split stage, counted by reason.
+///
+/// With `--rejected-lines` each of them is also written to `file_name` in the output
+/// directory as `{"reason":"...","file":"...","line":"..."}`, `line` is null if truncated or too long.
+struct Rejects {
+    counts: [AtomicU64; REJECT_REASONS.len()],
+    file_name: Option<String>,
+}
+
+impl Rejects {
+    fn new(file_name: Option<String>) -> Self {
+        Rejects {
+            counts: Default::default(),
+            file_name,
+        }
+    }
+
+    fn add(
+        &self,
+        reason: RejectReason,
+        input_file: &Path,
+        line: Option<&str>,
+        splitted_files: &SplittedFiles,
+        output_dir: &Path,
+    ) {
+        self.counts[reason as usize].fetch_add(1, Ordering::Relaxed);
+        if let Some(file_name) = self.file_name.as_ref() {
+            // reason first, for readers skimming the files
+            let record = format!(
+                r#"{{"reason":"{}","file":{},"line":{}}}"#,
+                reason.name(),
+                serde_json::to_string(&input_file.file_name().and_then(|s| s.to_str())).unwrap(),
+                serde_json::to_string(&line).unwrap()
+            );
+            splitted_files.write_line(output_dir.join(file_name), &record);
+        }
+    }
+
+    /// Non-zero counts, e.g., `out_of_day=3, parser_error=1`.
+    fn summary(&self) -> String {
+        REJECT_REASONS
+            .iter()
+            .filter_map(|reason| {
+                let count = self.counts[*reason as usize].load(Ordering::Relaxed);
+                (count > 0).then(|| format!("{}={}", reason.name(), count))
+            })
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
+/// Skews between `received_at` and the exchange `timestamp` of parsed messages in
+/// milliseconds per symbol, for `--latency-stats`.
+///
+/// Skews are counted by value, collectors receive messages within a few hundred distinct
+/// milliseconds, so exact percentiles cost little memory.
+struct Latencies {
+    skews: DashMap<String, BTreeMap<i64, u64>>,
+}
+
+/// Latency percentiles of a symbol in `latency.*.json`.
+#[derive(Serialize)]
+struct LatencyStats {
+    messages: u64,
+    p50_ms: i64,
+    p99_ms: i64,
+}
+
+impl Latencies {
+    fn new() -> Self {
+        Latencies {
+            skews: DashMap::new(),
+        }
+    }
+
+    /// Merge skews counted by a job.
+    fn add(&self, symbol: String, skews: BTreeMap<i64, u64>) {
+        let mut entry = self.skews.entry(symbol).or_default();
+        for (skew, count) in skews {
+            *entry.entry(skew).or_default() += count;
+        }
+    }
+
+    /// Percentiles of each symbol, and of all symbols under `*`.
+    fn stats(&self) -> BTreeMap<String, LatencyStats> {
+        let mut all: BTreeMap<i64, u64> = BTreeMap::new();
+        let mut stats: BTreeMap<String, LatencyStats> = self
+            .skews
+            .iter()
+            .map(|entry| {
+                for (skew, count) in entry.value() {
+                    *all.entry(*skew).or_default() += count;
+                }
+                (entry.key().clone(), latency_stats(entry.value()))
+            })
+            .collect();
+        if !all.is_empty() {
+            stats.insert("*".to_string(), latency_stats(&all));
+        }
+        stats
+    }
+}
+
+fn latency_stats(skews: &BTreeMap<i64, u64>) -> LatencyStats {
+    LatencyStats {
+        messages: skews.values().sum(),
+        p50_ms: percentile(skews, 50),
+        p99_ms: percentile(skews, 99),
+    }
+}
+
+/// The nearest-rank `percent`-th percentile of values counted by value, 0 if empty.
+fn percentile(counts: &BTreeMap<i64, u64>, percent: u64) -> i64 {
+    let total: u64 = counts.values().sum();
+    let rank = (percent * total).div_ceil(100).max(1);
+    let mut seen = 0;
+    for (value, count) in counts {
+        seen += count;
+        if seen >= rank {
+            return *value;
+        }
+    }
+    0
+}
+
+/// Lines of an input file held back until the file is known not to be quarantined, so that
+/// a quarantined file writes nothing to splitted files and marks no message as visited.
+///
+/// Lines are spilled to a hidden file in the output directory instead of memory, which has
+/// an `m <hash>` line per unique message, `m -` without deduplication, followed by an
+/// `<index of output file> <line>` line per line of the message. The spill file is removed
+/// when dropped.
+struct PendingLines {
+    path: PathBuf,
+    spill: std::io::BufWriter<std::fs::File>,
+    /// Output files by their indexes in the spill file
+    output_files: Vec<PathBuf>,
+    indexes: HashMap<PathBuf, usize>,
+    /// Hashes of messages of this file
+    hashes: HashSet<u64>,
+    memory_budget: Arc<MemoryBudget>,
+}
+
+/// Spill files created by this process, which make their names unique.
+static PENDING_FILES: AtomicU64 = AtomicU64::new(0);
+
+impl PendingLines {
+    fn new(output_dir: &Path, input_file: &Path, memory_budget: Arc<MemoryBudget>) -> Self {
+        std::fs::create_dir_all(output_dir).unwrap();
+        let path = output_dir.join(format!(
+            ".{}.{}.{}.pending",
+            input_file.file_name().unwrap().to_str().unwrap(),
+            std::process::id(),
+            PENDING_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let spill = std::io::BufWriter::with_capacity(
+            PENDING_BUFFER_BYTES,
+            std::fs::File::create(path.as_path()).unwrap(),
+        );
+        memory_budget.force_reserve((PENDING_BUFFER_BYTES + PENDING_BATCH_BYTES) as u64);
+        PendingLines {
+            path,
+            spill,
+            output_files: Vec::new(),
+            indexes: HashMap::new(),
+            hashes: HashSet::new(),
+            memory_budget,
+        }
+    }
+
+    /// Start a unique message, whose lines follow.
+    fn add(&mut self, hash: Option<u64>) {
+        match hash {
+            Some(hash) => writeln!(self.spill, "m {hash}"),
+            None => writeln!(self.spill, "m -"),
+        }
+        .unwrap();
+    }
+
+    /// Hold back `lines` of the last message, separated by newlines.
+    fn write(&mut self, output_file: PathBuf, lines: &str) {
+        let index = match self.indexes.get(&output_file) {
+            Some(index) => *index,
+            None => {
+                self.output_files.push(output_file.clone());
+                self.indexes
+                    .insert(output_file, self.output_files.len() - 1);
+                self.output_files.len() - 1
+            }
+        };
+        for line in lines.lines() {
+            writeln!(self.spill, "{index} {line}").unwrap();
+        }
+    }
+
+    /// Mark messages as visited and write their lines, returns the number of messages
+    /// dropped as duplicates, because other files visited them meanwhile.
+    fn commit(mut self, visited: &DashSet<u64>, splitted_files: &SplittedFiles) -> i64 {
+        self.spill.flush().unwrap();
+        let reader = std::io::BufReader::new(std::fs::File::open(self.path.as_path()).unwrap());
+        let mut batches: Vec<String> = vec![String::new(); self.output_files.len()];
+        let mut batched = 0;
+        let mut duplicate = false;
+        let mut duplicated = 0;
+        for line in reader.lines() {
+            let line = line.unwrap();
+            let (key, line) = line.split_once(' ').unwrap();
+            if key == "m" {
+                duplicate = line.parse::<u64>().is_ok_and(|hash| !visited.insert(hash));
+                if duplicate {
+                    duplicated += 1;
+                }
+            } else if !duplicate {
+                let batch = &mut batches[key.parse::<usize>().unwrap()];
+                batch.push_str(line);
+                batch.push('\n');
+                batched += line.len() + 1;
+                if batched >= PENDING_BATCH_BYTES {
+                    self.write_batches(&mut batches, splitted_files);
+                    batched = 0;
+                }
+            }
+        }
+        self.write_batches(&mut batches, splitted_files);
+        duplicated
+    }
+
+    fn write_batches(&self, batches: &mut [String], splitted_files: &SplittedFiles) {
+        for (output_file, batch) in self.output_files.iter().zip(batches.iter_mut()) {
+            if !batch.is_empty() {
+                splitted_files.write_batch(output_file.clone(), batch);
+                batch.clear();
+            }
+        }
+    }
+}
+
+impl Drop for PendingLines {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(self.path.as_path());
+        self.memory_budget
+            .release((PENDING_BUFFER_BYTES + PENDING_BATCH_BYTES) as u64);
+    }
+}
+
+/// Whether a message of `hash` is seen for the first time, which marks it as visited, or
+/// as pending until its file is known not to be quarantined.
+fn is_new_message(
+    hash: u64,
+    previous_day: Option<&BloomFilter>,
+    visited: &DashSet<u64>,
+    pending: Option<&mut PendingLines>,
+) -> bool {
+    // messages of the previous day replayed in files of this day
+    if previous_day.is_some_and(|bloom| bloom.contains(hash)) {
+        return false;
+    }
+    match pending {
+        Some(pending) => !visited.contains(&hash) && pending.hashes.insert(hash),
+        None => visited.insert(hash),
+    }
+}
+
+/// State of a combo and day shared by its split jobs, i.e., counters, sets of symbols and
+/// rejected lines.
+struct SplitContext {
+    /// `yyyy-MM-dd`
+    day: String,
+    options: Arc<Options>,
+    profile: Profile,
+    /// Bytes of input files after decompression
+    decompressed_bytes: AtomicU64,
+    /// Input lines read so far, for progress logs
+    lines_read: AtomicU64,
+    /// Input files to move to `--quarantine-dir` and why
+    quarantined: Option<DashMap<PathBuf, String>>,
+    /// Distinct symbols of raw and parsed messages
+    symbols: DashSet<String>,
+    /// Symbols which crypto_pair can NOT normalize
+    unmapped_symbols: DashSet<String>,
+    pair_cache: PairCache,
+    rejects_raw: Rejects,
+    rejects_parsed: Rejects,
+    latencies: Latencies,
+    /// Hashes of messages of the previous day, `--bloom-dir`
+    previous_day: Option<BloomFilter>,
+    /// Parsed messages violating their schemas
+    rejected: AtomicU64,
+    /// Messages which the parser fails to parse
+    parse_errors: AtomicU64,
+    /// Messages which are parsed to nothing
+    parsed_empty: AtomicU64,
+    /// Parsed lines whose received_at and event time are on different days
+    day_disagreements: AtomicU64,
+    /// Input lines of symbols of `--exclude-symbols`
+    excluded: AtomicU64,
+}
+
+/// Split a file by symbol and write to multiple files.
+///
+/// This function does split, dedup and parse together, and it is
+/// thread-safe, each `input_file` will launch a thread.
+///
+/// ## Arguments:
+///
+/// - input_file A `.json.gz` file downloaded from AWS S3
+/// - output_dir Where raw messages will be written to
+/// - splitted_files Tracks splitted files of
+///   `output_dir/exchange.market_type.msg_type.symbol.hour.json.gz`. Each `exchange, msg_type, market_type`
+///   has one `splitted_files`
+/// - visited A HashSet for deduplication, each `exchange, msg_type, market_type` has one
+///   `visited` Hashset, or each input file with `--dedup-scope file`
+/// - ctx Counters and sets of the combo and day, all messages beyond [day-5min, day+5min]
+///   will be dropped
+fn split_file_raw<P>(
+    input_file: P,
+    file_name_parts: FileNameParts,
+    output_dir: P,
+    splitted_files: Arc<SplittedFiles>,
+    visited: Arc<DashSet<u64>>,
+    ctx: Arc<SplitContext>,
+) -> (i64, i64, i64, i64, i64)
+where
+    P: AsRef<Path>,
+{
+    let SplitContext {
+        day,
+        options,
+        profile,
+        decompressed_bytes,
+        lines_read,
+        quarantined,
+        symbols,
+        rejects_raw: rejects,
+        previous_day,
+        excluded,
+        ..
+    } = &*ctx;
+    let exchange = file_name_parts.exchange.as_str();
+    let market_type = file_name_parts.market_type;
+    let msg_type = file_name_parts.msg_type;
+    let msg_type_str = msg_type.to_string();
+    // messages of per-symbol input files are not extracted one by one
+    let file_symbol = parse_input_file_name(input_file.as_ref(), options.input_granularity)
+        .ok()
+        .and_then(|(_, symbol)| symbol);
+    let compressed = std::fs::metadata(&input_file)
+        .unwrap_or_else(|_| panic!("{:?} does not exist", input_file.as_ref().display()))
+        .len();
+    let mut counting_reader = CountingReader::new(open_input_file(input_file.as_ref()));
+    let mut archive;
+    let mut total_lines = 0;
+    let mut unique_lines = 0;
+    let mut duplicated_lines = 0;
+    let mut error_lines = 0;
+    let mut expired_lines = 0;
+    let mut timestamp_unit = options.timestamp_unit;
+    let mut corrupted = false;
+    // lines of a file which may be quarantined are written once it is not
+    let mut pending = quarantined.as_ref().map(|_| {
+        PendingLines::new(
+            output_dir.as_ref(),
+            input_file.as_ref(),
+            splitted_files.memory_budget.clone(),
+        )
+    });
+    let write_raw = |pending: &mut Option<PendingLines>, output_file: PathBuf, json: &str| {
+        let json = if options.combined {
+            std::borrow::Cow::Owned(format!(r#"{{"kind":"raw","data":{json}}}"#))
+        } else {
+            std::borrow::Cow::Borrowed(json)
+        };
+        match pending {
+            Some(pending) => pending.write(output_file, &json),
+            None => profile.time(Phase::Write, || {
+                splitted_files.write_line(output_file, &json)
+            }),
+        }
+    };
+    let reject = |reason: RejectReason, line: Option<&str>| {
+        rejects.add(
+            reason,
+            input_file.as_ref(),
+            line,
+            &splitted_files,
+            output_dir.as_ref(),
+        )
+    };
+    let mut lines: Box<dyn Iterator<Item = std::io::Result<String>>> =
+        if is_tar_archive(input_file.as_ref()) {
+            archive = tar::Archive::new(&mut counting_reader);
+            tar_lines(&mut archive, &file_name_parts, options.max_line_bytes)
+        } else {
+            Box::new(BoundedLines {
+                reader: std::io::BufReader::new(&mut counting_reader),
+                max_bytes: options.max_line_bytes,
+            })
+        };
+    while let Some(line) = profile.time(Phase::Decode, || lines.next()) {
+        if splitted_files.output_budget.exceeded() {
+            // the stage is stopped and rerun as a whole
+            break;
+        }
+        if let Some(len) = line.as_ref().err().and_then(line_too_long) {
+            warn!(
+                "Skipped a line of {} bytes in {}, longer than --json-lines-limit-bytes",
+                len,
+                input_file.as_ref().display()
+            );
+            total_lines += 1;
+            lines_read.fetch_add(1, Ordering::Relaxed);
+            error_lines += 1;
+            reject(RejectReason::LineTooLong, None);
+            continue;
+        }
+        if let Ok(line) = line {
+            total_lines += 1;
+            lines_read.fetch_add(1, Ordering::Relaxed);
+            if let Some((mut msg, normalized)) =
+                profile.time(Phase::Parse, || parse_envelope(&line, options))
+            {
+                let extracted = msg.received_at == 0 && extract_received_at(&mut msg, options);
+                if timestamp_unit == TimestampUnit::Auto {
+                    timestamp_unit = TimestampUnit::detect(msg.received_at);
+                    info!(
+                        "Detected timestamp unit {:?} of {}",
+                        timestamp_unit,
+                        input_file.as_ref().display()
+                    );
+                }
+                msg.received_at = timestamp_unit.to_millis(msg.received_at);
+                // a misrouted input file fails as a whole
+                if let Err(err) = check_combo(&msg, exchange, market_type, msg_type) {
+                    panic!("{}, {}", err, input_file.as_ref().display());
+                }
+                debug_assert_message(&msg);
+                if let Ok(symbol) = profile.time(Phase::Parse, || match &file_symbol {
+                    Some(symbol) => Ok(symbol.clone()),
+                    None => extract_symbol(exchange, market_type, &msg.json),
+                }) {
+                    if !is_selected_symbol(&symbol, options.symbols.as_ref())
+                        || !is_in_shard(&symbol, options.shard)
+                    {
+                        // lines of other symbols are not part of this run
+                        total_lines -= 1;
+                        continue;
+                    }
+                    if options.exclude_symbols.contains(&symbol) {
+                        excluded.fetch_add(1, Ordering::Relaxed);
+                        total_lines -= 1;
+                        continue;
+                    }
+                    if too_many_symbols(symbols, &symbol, options.max_symbols) {
+                        // stop early instead of creating a file per garbage symbol
+                        error_lines += 1;
+                        break;
+                    }
+                    let real_market_type = get_real_market_type(exchange, msg.market_type, &symbol);
+
+                    let event_timestamp = if options.day_filter_basis.needs_event_timestamp() {
+                        profile.time(Phase::Parse, || {
+                            extract_timestamp(exchange, real_market_type, &msg.json)
+                                .ok()
+                                .flatten()
+                        })
+                    } else {
+                        None
+                    };
+                    if let Some(day_timestamp) = options.day_filter_basis.day_timestamp(
+                        day,
+                        msg.received_at as i64,
+                        event_timestamp,
+                        false,
+                    ) {
+                        // raw, --dedup-scope none skips hashing entirely
+                        let hash = (options.dedup_scope != DedupScope::None).then(|| {
+                            profile.time(Phase::Hash, || {
+                                hash_json(&msg.json, options.canonical_dedup)
+                            })
+                        });
+                        if hash.is_none_or(|hash| {
+                            is_new_message(hash, previous_day.as_ref(), &visited, pending.as_mut())
+                        }) {
+                            unique_lines += 1;
+                            if let Some(pending) = pending.as_mut() {
+                                pending.add(hash);
+                            }
+                            let output_file = {
+                                let hour = get_hour(day_timestamp);
+                                let output_file_name = format!(
+                                    "{}.{}.{}.{}.{}.{}",
+                                    exchange,
+                                    real_market_type,
+                                    msg_type_str,
+                                    encode_symbol(&symbol),
+                                    hour,
+                                    options.intermediate_compression.extension()
+                                );
+                                output_dir
+                                    .as_ref()
+                                    .join(real_market_type.to_string())
+                                    .join(output_file_name)
+                            };
+                            let json = raw_line(
+                                &msg,
+                                &line,
+                                real_market_type,
+                                timestamp_unit != TimestampUnit::Millis || extracted || normalized,
+                            );
+                            write_raw(&mut pending, output_file, &json);
+                        } else {
+                            duplicated_lines += 1;
+                        }
+                    } else {
+                        expired_lines += 1;
+                        reject(RejectReason::OutOfDay, Some(&line));
+                    }
+                } else {
+                    warn!("No symbol: {}", line);
+                    error_lines += 1;
+                    reject(RejectReason::SymbolExtraction, Some(&line));
+                }
+            } else {
+                warn!("Not a valid Message: {}", line);
+                error_lines += 1;
+                reject(RejectReason::JsonEnvelopeParse, Some(&line));
+            }
+        } else {
+            error!("malformed file {}", input_file.as_ref().display());
+            reject(RejectReason::Truncated, None);
+            error_lines += 1;
+            total_lines += 1;
+            corrupted = true;
+            break; // the rest of a corrupted gzip stream is unreadable
+        }
+    }
+    if let Some(reason) = quarantine_reason(exchange, total_lines, error_lines, corrupted) {
+        if let Some(quarantined) = quarantined {
+            quarantined
+                .entry(input_file.as_ref().to_path_buf())
+                .or_insert(reason);
+            // Lines of this file are excluded from the error ratio of the stage
+            return (0, 0, 0, 0, 0);
+        }
+    }
+    if let Some(pending) = pending {
+        let duplicated = profile.time(Phase::Write, || pending.commit(&visited, &splitted_files));
+        unique_lines -= duplicated;
+        duplicated_lines += duplicated;
+    }
+    drop(lines);
+    let decompressed = counting_reader.count;
+    decompressed_bytes.fetch_add(decompressed, Ordering::SeqCst);
+    debug!(
+        "Decompression ratio of {} is {:.2}",
+        input_file.as_ref().display(),
+        decompressed as f64 / compressed.max(1) as f64
+    );
+    (
+        total_lines,
+        unique_lines,
+        duplicated_lines,
+        error_lines,
+        expired_lines,
+    )
+}
+
+fn split_file_parsed<P>(
+    input_file: P,
+    file_name_parts: FileNameParts,
+    output_dir: P,
+    splitted_files: Arc<SplittedFiles>,
+    visited: Arc<DashSet<u64>>,
+    ctx: Arc<SplitContext>,
+) -> (i64, i64, i64, i64, i64)
+where
+    P: AsRef<Path>,
+{
+    let SplitContext {
+        day,
+        options,
+        profile,
+        lines_read,
+        quarantined,
+        symbols,
+        unmapped_symbols,
+        pair_cache,
+        rejects_parsed: rejects,
+        latencies,
+        previous_day,
+        rejected,
+        parse_errors,
+        parsed_empty,
+        day_disagreements,
+        excluded,
+        ..
+    } = &*ctx;
+    let exchange = file_name_parts.exchange.as_str();
+    let market_type = file_name_parts.market_type;
+    let msg_type = file_name_parts.msg_type;
+    let msg_type_str = msg_type.to_string();
+    let file_symbol = parse_input_file_name(input_file.as_ref(), options.input_granularity)
+        .ok()
+        .and_then(|(_, symbol)| symbol);
+    let mut decoder = open_input_file(input_file.as_ref());
+    // skews of this file, merged into latencies once it is not quarantined
+    let mut skews: HashMap<String, BTreeMap<i64, u64>> = HashMap::new();
+    // the JSON string of the file name, for --annotate-source
+    let source = options.annotate_source.then(|| {
+        let file_name = input_file.as_ref().file_name().unwrap().to_str().unwrap();
+        serde_json::to_string(file_name).unwrap()
+    });
+    let mut archive;
+    let mut total_lines = 0;
+    let mut unique_lines = 0;
+    let mut duplicated_lines = 0;
+    let mut error_lines = 0;
+    let mut expired_lines = 0;
+    let mut timestamp_unit = options.timestamp_unit;
+    let mut parsed_timestamp_unit = TimestampUnit::Auto;
+    let mut corrupted = false;
+    // lines of a file which may be quarantined are written once it is not
+    let mut pending = quarantined.as_ref().map(|_| {
+        PendingLines::new(
+            output_dir.as_ref(),
+            input_file.as_ref(),
+            splitted_files.memory_budget.clone(),
+        )
+    });
+    let reject = |reason: RejectReason, line: Option<&str>| {
+        rejects.add(
+            reason,
+            input_file.as_ref(),
+            line,
+            &splitted_files,
+            output_dir.as_ref(),
+        )
+    };
+    let mut lines: Box<dyn Iterator<Item = std::io::Result<String>>> =
+        if is_tar_archive(input_file.as_ref()) {
+            archive = tar::Archive::new(&mut decoder);
+            tar_lines(&mut archive, &file_name_parts, options.max_line_bytes)
+        } else {
+            Box::new(BoundedLines {
+                reader: std::io::BufReader::new(&mut decoder),
+                max_bytes: options.max_line_bytes,
+            })
+        };
+    while let Some(line) = profile.time(Phase::Decode, || lines.next()) {
+        if splitted_files.output_budget.exceeded() {
+            // the stage is stopped and rerun as a whole
+            break;
+        }
+        if line.as_ref().err().and_then(line_too_long).is_some() {
+            // logged by split_file_raw() unless raw files are skipped
+            if !options.output.raw() {
+                warn!(
+                    "Skipped a line longer than --json-lines-limit-bytes in {}",
+                    input_file.as_ref().display()
+                );
+                lines_read.fetch_add(1, Ordering::Relaxed);
+            }
+            total_lines += 1;
+            error_lines += 1;
+            reject(RejectReason::LineTooLong, None);
+            continue;
+        }
+        if let Ok(line) = line {
+            total_lines += 1;
+            if !options.output.raw() {
+                // otherwise counted by split_file_raw()
+                lines_read.fetch_add(1, Ordering::Relaxed);
+            }
+            if let Some((mut msg, _)) =
+                profile.time(Phase::Parse, || parse_envelope(&line, options))
+            {
+                if msg.received_at == 0 {
+                    extract_received_at(&mut msg, options);
+                }
+                if timestamp_unit == TimestampUnit::Auto {
+                    // split_file_raw() has logged the detected unit of the same file
+                    timestamp_unit = TimestampUnit::detect(msg.received_at);
+                }
+                msg.received_at = timestamp_unit.to_millis(msg.received_at);
+                // a misrouted input file fails as a whole
+                if let Err(err) = check_combo(&msg, exchange, market_type, msg_type) {
+                    panic!("{}, {}", err, input_file.as_ref().display());
+                }
+                debug_assert_message(&msg);
+                if let Ok(symbol) = profile.time(Phase::Parse, || match &file_symbol {
+                    Some(symbol) => Ok(symbol.clone()),
+                    None => extract_symbol(exchange, market_type, &msg.json),
+                }) {
+                    if !is_selected_symbol(&symbol, options.symbols.as_ref())
+                        || !is_in_shard(&symbol, options.shard)
+                    {
+                        // lines of other symbols are not part of this run
+                        total_lines -= 1;
+                        continue;
+                    }
+                    if options.exclude_symbols.contains(&symbol) {
+                        // otherwise counted by split_file_raw()
+                        if !options.output.raw() {
+                            excluded.fetch_add(1, Ordering::Relaxed);
+                        }
+                        total_lines -= 1;
+                        continue;
+                    }
+                    if too_many_symbols(symbols, &symbol, options.max_symbols) {
+                        // stop early instead of creating a file per garbage symbol
+                        error_lines += 1;
+                        break;
+                    }
+                    let real_market_type = get_real_market_type(exchange, msg.market_type, &symbol);
+
+                    // --dedup-scope none skips hashing entirely
+                    let hash = (options.dedup_scope != DedupScope::None).then(|| {
+                        profile.time(Phase::Hash, || {
+                            hash_json(&msg.json, options.canonical_dedup)
+                        })
+                    });
+                    if hash.is_none_or(|hash| {
+                        is_new_message(hash, previous_day.as_ref(), &visited, pending.as_mut())
+                    }) {
+                        unique_lines += 1;
+                        if let Some(pending) = pending.as_mut() {
+                            pending.add(hash);
+                        }
+                        if options.skips_parse(exchange, real_market_type, msg.msg_type) {
+                            // the raw file still has this line
+                            continue;
+                        }
+                        let pair = if let Some(pair) = pair_cache.normalize_pair(&symbol, exchange)
+                        {
+                            pair
+                        } else {
+                            // skip parsing, the raw file still has this line
+                            if unmapped_symbols.insert(symbol.clone()) {
+                                warn!("Failed to normalize symbol {} of {}", symbol, exchange);
+                            }
+                            reject(RejectReason::PairNormalization, Some(&line));
+                            continue;
+                        };
+                        // parsed, lines of the same output file are written under one lock
+                        let mut batches: HashMap<PathBuf, String> = HashMap::new();
+                        let mut write_parsed =
+                            |market_type: MarketType, json: String, timestamp: i64| {
+                                if options.validate_parsed {
+                                    if let Err(err) = validate_parsed(msg_type, &json) {
+                                        debug!("{}, {}", err, json);
+                                        rejected.fetch_add(1, Ordering::SeqCst);
+                                        let rejects_file = output_dir.as_ref().join(format!(
+                                            "rejects.{exchange}.{market_type}.{msg_type_str}.{day}.json.gz"
+                                        ));
+                                        splitted_files.write_line(rejects_file, &json);
+                                        return;
+                                    }
+                                }
+                                let json = apply_transforms(json, &options.transforms);
+                                let json = if let Some(source) = source.as_ref() {
+                                    annotate_source(json, source)
+                                } else {
+                                    json
+                                };
+                                let output_file_name = if options.combined {
+                                    // the same file as raw messages of the symbol
+                                    format!(
+                                        "{}.{}.{}.{}.{}.{}",
+                                        exchange,
+                                        market_type,
+                                        msg_type_str,
+                                        encode_symbol(&symbol),
+                                        get_hour(timestamp),
+                                        options.intermediate_compression.extension()
+                                    )
+                                } else {
+                                    let hour = get_hour(timestamp);
+                                    let (base, quote) = {
+                                        let v = pair.split('/').collect::<Vec<&str>>();
+                                        (v[0], v[1])
+                                    };
+                                    format!(
+                                        "{}.{}.{}.{}.{}.{}.{}.{}",
+                                        exchange,
+                                        market_type,
+                                        msg_type_str,
+                                        encode_symbol(base),
+                                        encode_symbol(quote),
+                                        encode_symbol(&symbol),
+                                        hour,
+                                        options.intermediate_compression.extension()
+                                    )
+                                };
+                                let output_file = output_dir
+                                    .as_ref()
+                                    .join(market_type.to_string())
+                                    .join(output_file_name);
+                                let batch = batches.entry(output_file).or_default();
+                                if options.combined {
+                                    batch
+                                        .push_str(&format!(r#"{{"kind":"parsed","data":{json}}}"#));
+                                } else {
+                                    batch.push_str(&json);
+                                }
+                                batch.push('\n');
+                            };
+
+                        // well-formed messages without content, e.g., heartbeats
+                        let write_parsed_empty = || {
+                            parsed_empty.fetch_add(1, Ordering::SeqCst);
+                            if options.keep_parsed_empty {
+                                let parsed_empty_file = output_dir.as_ref().join(format!(
+                                    "parsed_empty.{exchange}.{market_type}.{msg_type_str}.{day}.json.gz"
+                                ));
+                                splitted_files.write_line(parsed_empty_file, &line);
+                            }
+                        };
+                        // a line is rejected once even if several of its messages are out of the day
+                        let mut out_of_day = false;
+                        // and counted once if its received_at and event time are on different days
+                        let mut disagrees = false;
+                        // Skip unsupported markets
+                        let blocked = msg.msg_type == MessageType::L2Event
+                            && is_blocked_market(real_market_type);
+                        match profile.time(Phase::Parse, || {
+                            if blocked {
+                                Ok(Vec::new())
+                            } else {
+                                parse_message(&msg)
+                            }
+                        }) {
+                            Ok(messages) => {
+                                if messages.is_empty() && !blocked {
+                                    write_parsed_empty();
+                                }
+                                for mut message in messages {
+                                    if !(real_market_type == MarketType::InverseSwap
+                                        && message.market_type() == MarketType::InverseFuture
+                                        && exchange == "deribit")
+                                    {
+                                        // For deribit, inverse_swap is included in inverse_future
+                                        assert_eq!(
+                                            real_market_type,
+                                            message.market_type(),
+                                            "{real_market_type}, {line}",
+                                        );
+                                    }
+                                    if let (Some(depth), ParsedMsg::OrderBook(message)) =
+                                        (options.l2_depth, &mut message)
+                                    {
+                                        truncate_depth(message, depth);
+                                    }
+                                    let timestamp = parsed_timestamp_millis(
+                                        &mut parsed_timestamp_unit,
+                                        message.timestamp(),
+                                        input_file.as_ref(),
+                                    );
+                                    message.set_timestamp(timestamp);
+                                    if is_day_disagreement(day, msg.received_at as i64, timestamp) {
+                                        disagrees = true;
+                                    }
+                                    if let Some(day_timestamp) =
+                                        options.day_filter_basis.day_timestamp(
+                                            day,
+                                            msg.received_at as i64,
+                                            Some(timestamp),
+                                            true,
+                                        )
+                                    {
+                                        if options.latency_stats {
+                                            *skews
+                                                .entry(symbol.clone())
+                                                .or_default()
+                                                .entry(msg.received_at as i64 - timestamp)
+                                                .or_default() += 1;
+                                        }
+                                        let json = profile.time(Phase::Parse, || {
+                                            serde_json::to_string(&message).unwrap()
+                                        });
+                                        write_parsed(message.market_type(), json, day_timestamp);
+                                    } else {
+                                        expired_lines += 1;
+                                        out_of_day = true;
+                                    }
+                                }
+                            }
+                            Err(err) => {
+                                warn!("Failed to parse {}, {}", line, err);
+                                parse_errors.fetch_add(1, Ordering::SeqCst);
+                                if options.parser_strict {
+                                    // counted by the error ratio instead
+                                    unique_lines -= 1;
+                                    error_lines += 1;
+                                }
+                                reject(RejectReason::ParserError, Some(&line));
+                            }
+                        }
+                        if out_of_day {
+                            reject(RejectReason::OutOfDay, Some(&line));
+                        }
+                        if disagrees {
+                            day_disagreements.fetch_add(1, Ordering::Relaxed);
+                        }
+                        for (output_file, batch) in batches {
+                            match pending.as_mut() {
+                                Some(pending) => pending.write(output_file, &batch),
+                                None => profile.time(Phase::Write, || {
+                                    splitted_files.write_batch(output_file, &batch)
+                                }),
+                            }
+                        }
+                    } else {
+                        duplicated_lines += 1;
+                    }
+                } else {
+                    warn!("No symbol: {}", line);
+                    error_lines += 1;
+                    reject(RejectReason::SymbolExtraction, Some(&line));
+                }
+            } else {
+                warn!("Not a valid Message: {}", line);
+                error_lines += 1;
+                reject(RejectReason::JsonEnvelopeParse, Some(&line));
+            }
+        } else {
+            error!("malformed file {}", input_file.as_ref().display());
+            reject(RejectReason::Truncated, None);
+            error_lines += 1;
+            total_lines += 1;
+            corrupted = true;
+            break; // the rest of a corrupted gzip stream is unreadable
+        }
+    }
+    if let Some(reason) = quarantine_reason(exchange, total_lines, error_lines, corrupted) {
+        if let Some(quarantined) = quarantined {
+            quarantined
+                .entry(input_file.as_ref().to_path_buf())
+                .or_insert(reason);
+            return (0, 0, 0, 0, 0);
+        }
+    }
+    if let Some(pending) = pending {
+        let duplicated = profile.time(Phase::Write, || pending.commit(&visited, &splitted_files));
+        unique_lines -= duplicated;
+        duplicated_lines += duplicated;
+    }
+    for (symbol, skews) in skews {
+        latencies.add(symbol, skews);
+    }
+    (
+        total_lines,
+        unique_lines,
+        duplicated_lines,
+        error_lines,
+        expired_lines,
+    )
+}
+
+/// Whether exactly one of `received_at` and the event `timestamp` is in `day`, then raw
+/// and parsed outputs disagree on the line with `--day-filter-basis mixed`.
+fn is_day_disagreement(day: &str, received_at: i64, timestamp: i64) -> bool {
+    (get_day(received_at) == day) != (get_day(timestamp) == day)
+}
+
+/// Append a `_src` field of the JSON string `source` to a serialized parsed message.
+fn annotate_source(mut json: String, source: &str) -> String {
+    if json.ends_with('}') && json.len() > 2 {
+        json.pop();
+        json.push_str(r#","_src":"#);
+        json.push_str(source);
+        json.push('}');
+    }
+    json
+}
+
+/// Extract the exchange event time from `json` of a raw message.
+fn event_timestamp(msg: &serde_json::Map<String, Value>) -> Option<i64> {
+    let exchange = msg.get("exchange")?.as_str()?;
+    let market_type = serde_json::from_value::<MarketType>(msg.get("market_type")?.clone()).ok()?;
+    let json = msg.get("json")?.as_str()?;
+    extract_timestamp(exchange, market_type, json)
+        .ok()
+        .flatten()
+}
+
+/// Sort key of a line, i.e., `received_at` of raw messages, or the event time if `raw_sort_key`
+/// is `Timestamp`, and `timestamp` of parsed messages.
+fn sort_key(line: &str, raw_sort_key: RawSortKey) -> Result<i64, String> {
+    let msg = serde_json::from_str::<serde_json::Map<String, Value>>(line)
+        .map_err(|_| format!("Not a JSON object: {line}"))?;
+    // lines of --combined are wrapped as {"kind":...,"data":...}
+    let msg = match (msg.get("kind"), msg.get("data")) {
+        (Some(_), Some(Value::Object(data))) => data,
+        _ => &msg,
+    };
+    if let Some(received_at) = msg.get("received_at") {
+        let event_timestamp = if raw_sort_key == RawSortKey::Timestamp {
+            event_timestamp(msg)
+        } else {
+            None
+        };
+        event_timestamp
+            .or_else(|| received_at.as_i64())
+            .ok_or_else(|| format!("received_at is not an integer: {line}"))
+    } else if let Some(timestamp) = msg.get("timestamp") {
+        timestamp
+            .as_i64()
+            .ok_or_else(|| format!("timestamp is not an integer: {line}"))
+    } else {
+        Err(format!("Can NOT find received_at nor timestamp: {line}"))
+    }
+}
+
+/// Kinds of clock anomalies found by `--max-gap-secs`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ClockAnomalyKind {
+    /// No message for a long time in the sort order, e.g., an outage of the collector
+    Gap,
+    /// `received_at` goes back in the sort order, e.g., the clock of the collector was reset
+    BackwardJump,
+}
+
+impl ClockAnomalyKind {
+    fn name(self) -> &'static str {
+        match self {
+            ClockAnomalyKind::Gap => "gap",
+            ClockAnomalyKind::BackwardJump => "backward_jump",
+        }
+    }
+}
+
+/// An interval between two consecutive lines of a sorted file longer than `--max-gap-secs`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct ClockAnomaly {
+    kind: ClockAnomalyKind,
+    /// 1-based number of the second line
+    line: i64,
+    previous: i64,
+    current: i64,
+}
+
+impl ClockAnomaly {
+    fn interval_ms(&self) -> i64 {
+        (self.current - self.previous).abs()
+    }
+}
+
+/// Check that lines of a sorted `.json.xz` file are in non-decreasing order of `sort_key()`,
+/// or non-increasing order with `SortOrder::Desc`.
+///
+/// With `max_gap_ms`, intervals of the sort key longer than it are reported as gaps, and so
+/// are backward jumps of `received_at` in files sorted by the event time, which the
+/// order check alone can not see.
+///
+/// Returns the number of lines and clock anomalies, or the first violation.
+fn check_sorted(
+    sorted_file: &Path,
+    raw_sort_key: RawSortKey,
+    sort_order: SortOrder,
+    max_gap_ms: Option<i64>,
+) -> Result<(i64, Vec<ClockAnomaly>), String> {
+    let f_in = std::fs::File::open(sorted_file).map_err(|err| err.to_string())?;
+    let buf_reader = std::io::BufReader::new(xz2::read::XzDecoder::new_multi_decoder(f_in));
+    let mut previous = None;
+    let mut previous_received_at = None;
+    let mut anomalies = Vec::new();
+    let mut total_lines = 0;
+    for line in buf_reader.lines() {
+        let line = line.map_err(|err| format!("malformed file, {err}"))?;
+        total_lines += 1;
+        let timestamp =
+            sort_key(&line, raw_sort_key).map_err(|err| format!("line {total_lines}, {err}"))?;
+        if let Some(previous) = previous {
+            if sort_order == SortOrder::Asc && timestamp < previous {
+                return Err(format!(
+                    "line {} has sort key {} less than {} of the previous line",
+                    total_lines, timestamp, previous
+                ));
+            }
+            if sort_order == SortOrder::Desc && timestamp > previous {
+                return Err(format!(
+                    "line {} has sort key {} greater than {} of the previous line",
+                    total_lines, timestamp, previous
+                ));
+            }
+            if max_gap_ms.is_some_and(|max_gap_ms| (timestamp - previous).abs() > max_gap_ms) {
+                anomalies.push(ClockAnomaly {
+                    kind: ClockAnomalyKind::Gap,
+                    line: total_lines,
+                    previous,
+                    current: timestamp,
+                });
+            }
+        }
+        previous = Some(timestamp);
+        if let Some(max_gap_ms) = max_gap_ms {
+            if raw_sort_key == RawSortKey::Timestamp {
+                // received_at of raw lines, timestamp of parsed lines which never jumps
+                let received_at = sort_key(&line, RawSortKey::ReceivedAt)
+                    .map_err(|err| format!("line {total_lines}, {err}"))?;
+                if let Some(previous_received_at) = previous_received_at {
+                    let backward = match sort_order {
+                        SortOrder::Asc => previous_received_at - received_at,
+                        SortOrder::Desc => received_at - previous_received_at,
+                    };
+                    if backward > max_gap_ms {
+                        anomalies.push(ClockAnomaly {
+                            kind: ClockAnomalyKind::BackwardJump,
+                            line: total_lines,
+                            previous: previous_received_at,
+                            current: received_at,
+                        });
+                    }
+                }
+                previous_received_at = Some(received_at);
+            }
+        }
+    }
+    Ok((total_lines, anomalies))
+}
+
+fn sort_file<P>(
+    input_file: P,
+    writer: &mut dyn std::io::Write,
+    profile: &Profile,
+    raw_sort_key: RawSortKey,
+    sort_order: SortOrder,
+) -> (i64, i64, u64)
+where
+    P: AsRef<Path>,
+{
+    let file_name = input_file.as_ref().to_str().unwrap();
+    assert!(file_name.ends_with(".json.gz") || file_name.ends_with(".json"));
+    if !input_file.as_ref().exists() {
+        panic!("{:?} does not exist", input_file.as_ref().display());
+    }
+    let buf_reader = open_splitted_file(input_file.as_ref());
+    let mut total_lines = 0;
+    let mut error_lines = 0;
+    // bytes of sorted lines written to writer
+    let mut written = 0;
+    let mut lines: Vec<(i64, String)> = Vec::new();
+    let mut buf_lines = buf_reader.lines();
+    while let Some(line) = profile.time(Phase::Decode, || buf_lines.next()) {
+        if let Ok(line) = line {
+            total_lines += 1;
+            match profile.time(Phase::Parse, || sort_key(&line, raw_sort_key)) {
+                Ok(timestamp) => lines.push((timestamp, line)),
+                Err(err) => {
+                    warn!("{}", err);
+                    error_lines += 1;
+                }
+            }
+        } else {
+            error!("malformed file {}", input_file.as_ref().display());
+            error_lines += 1;
+            break;
+        }
+    }
+    if error_lines == 0 {
+        // sort by timestamp, stable so that lines of the same timestamp keep their order
+        profile.time(Phase::Sort, || match sort_order {
+            SortOrder::Asc => lines.sort_by_key(|x| x.0),
+            SortOrder::Desc => lines.sort_by_key(|x| Reverse(x.0)),
+        });
+
+        profile.time(Phase::Write, || {
+            for line in lines {
+                writeln!(writer, "{}", line.1).unwrap();
+                written += line.1.len() as u64 + 1;
+            }
+            writer.flush().unwrap();
+        });
+    } else {
+        error!(
+            "Found {} malformed lines out of total {} lines in file {}",
+            error_lines,
+            total_lines,
+            input_file.as_ref().display()
+        );
+    }
+    (error_lines, total_lines, written)
+}
+
+/// Open a `.json.gz` or `.json` file written by the split stage.
+fn open_splitted_file(input_file: &Path) -> Box<dyn BufRead> {
+    let f_in = std::fs::File::open(input_file).unwrap();
+    if input_file.extension().is_some_and(|ext| ext == "gz") {
+        // splitted files re-opened in append mode contain multiple gzip members
+        Box::new(std::io::BufReader::new(MultiGzDecoder::new(f_in)))
+    } else {
+        Box::new(std::io::BufReader::new(f_in))
+    }
+}
+
+/// Count lines of splitted files without writing or removing anything, returns numbers
+/// of malformed lines and all lines like `sort_file()`.
+fn count_files(input_files: &[PathBuf], raw_sort_key: RawSortKey) -> (i64, i64) {
+    let mut error_lines = 0;
+    let mut total_lines = 0;
+    for input_file in input_files {
+        for line in open_splitted_file(input_file).lines() {
+            total_lines += 1;
+            match line {
+                Ok(line) => {
+                    if let Err(err) = sort_key(&line, raw_sort_key) {
+                        warn!("{}", err);
+                        error_lines += 1;
+                    }
+                }
+                Err(_) => {
+                    error!("malformed file {}", input_file.display());
+                    error_lines += 1;
+                    break;
+                }
+            }
+        }
+    }
+    (error_lines, total_lines)
+}
+
+/// Frames lines written to it in `style`.
+///
+/// In `OutputJsonStyle::Array` the newline of each line is held back until the next
+/// line starts, so that a comma can be put before it, and `finish()` closes the array.
+/// Nothing but one flag is buffered, lines are streamed to `inner` as they come.
+struct JsonStyleWriter<W: std::io::Write> {
+    inner: W,
+    style: OutputJsonStyle,
+    started: bool,
+    // a newline held back at the end of the previous line
+    pending_newline: bool,
+}
+
+impl<W: std::io::Write> JsonStyleWriter<W> {
+    fn new(inner: W, style: OutputJsonStyle) -> Self {
+        JsonStyleWriter {
+            inner,
+            style,
+            started: false,
+            pending_newline: false,
+        }
+    }
+
+    /// Close the array and returns the inner writer.
+    fn finish(mut self) -> std::io::Result<W> {
+        if self.style == OutputJsonStyle::Array {
+            if self.started {
+                self.inner.write_all(b"\n]\n")?;
+            } else {
+                self.inner.write_all(b"[]\n")?;
+            }
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for JsonStyleWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.style == OutputJsonStyle::Ndjson {
+            return self.inner.write(buf);
+        }
+        let mut rest = buf;
+        while !rest.is_empty() {
+            if self.pending_newline || !self.started {
+                self.inner
+                    .write_all(if self.started { b",\n" } else { b"[\n" })?;
+                self.started = true;
+                self.pending_newline = false;
+            }
+            match rest.iter().position(|b| *b == b'\n') {
+                Some(pos) => {
+                    self.inner.write_all(&rest[..pos])?;
+                    self.pending_newline = true;
+                    rest = &rest[pos + 1..];
+                }
+                None => {
+                    self.inner.write_all(rest)?;
+                    rest = &[];
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Create a multithreaded xz encoder if `compress_threads` is set.
+fn mt_stream(compress_threads: Option<u32>) -> Option<xz2::stream::Stream> {
+    compress_threads.and_then(|threads| {
+        match xz2::stream::MtStreamBuilder::new()
+            .threads(threads)
+            .preset(6)
+            .check(xz2::stream::Check::Crc64)
+            .encoder()
+        {
+            Ok(stream) => Some(stream),
+            Err(err) => {
+                // fall back to the single-threaded encoder or the external xz
+                warn!("Failed to create a multithreaded xz encoder, {}", err);
+                None
+            }
+        }
+    })
+}
+
+/// Compresses lines to `output_file.partN.tmp` files, a part is finished at the
+/// first line end after its compressed size reaches `max_bytes`.
+///
+/// The encoder flushes compressed blocks lazily, so a part may exceed `max_bytes`
+/// by up to one block.
+struct RollingWriter {
+    output_file: PathBuf,
+    max_bytes: u64,
+    compress_threads: Option<u32>,
+    compressor: Compressor,
+    encoder: Option<xz2::write::XzEncoder<std::fs::File>>,
+    // finished parts and their numbers of lines
+    parts: Vec<(PathBuf, i64)>,
+    lines: i64,
+}
+
+impl RollingWriter {
+    fn new(output_file: &Path, max_bytes: u64, compress_threads: Option<u32>) -> Self {
+        RollingWriter {
+            output_file: output_file.to_path_buf(),
+            max_bytes,
+            compress_threads,
+            compressor: Compressor::Xz,
+            encoder: None,
+            parts: Vec::new(),
+            lines: 0,
+        }
+    }
+
+    fn tmp_file(&self, index: usize) -> PathBuf {
+        let mut path = self.output_file.as_os_str().to_os_string();
+        path.push(format!(".part{}.tmp", index + 1));
+        PathBuf::from(path)
+    }
+
+    fn encoder(&mut self) -> std::io::Result<&mut xz2::write::XzEncoder<std::fs::File>> {
+        if self.encoder.is_none() {
+            let f_out = std::fs::File::create(self.tmp_file(self.parts.len()))?;
+            self.encoder = Some(if let Some(stream) = mt_stream(self.compress_threads) {
+                self.compressor = Compressor::XzMt;
+                xz2::write::XzEncoder::new_stream(f_out, stream)
+            } else {
+                xz2::write::XzEncoder::new(f_out, 6)
+            });
+        }
+        Ok(self.encoder.as_mut().unwrap())
+    }
+
+    fn finish_part(&mut self) -> std::io::Result<()> {
+        if let Some(encoder) = self.encoder.take() {
+            encoder.finish()?;
+            self.parts
+                .push((self.tmp_file(self.parts.len()), self.lines));
+            self.lines = 0;
+        }
+        Ok(())
+    }
+
+    /// Finish the last part, returns the compressor and all parts, at least one.
+    fn finish(mut self) -> std::io::Result<(Compressor, Vec<(PathBuf, i64)>)> {
+        if self.parts.is_empty() {
+            self.encoder()?;
+        }
+        self.finish_part()?;
+        Ok((self.compressor, self.parts))
+    }
+}
+
+impl std::io::Write for RollingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len();
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let max_bytes = self.max_bytes;
+            let encoder = self.encoder()?;
+            let line_end = if encoder.total_out() >= max_bytes {
+                buf.iter().position(|b| *b == b'\n')
+            } else {
+                None
+            };
+            match line_end {
+                Some(pos) => {
+                    encoder.write_all(&buf[..=pos])?;
+                    self.lines += 1;
+                    self.finish_part()?;
+                    buf = &buf[pos + 1..];
+                }
+                None => {
+                    encoder.write_all(buf)?;
+                    self.lines += buf.iter().filter(|b| **b == b'\n').count() as i64;
+                    buf = &[];
+                }
+            }
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.encoder.as_mut() {
+            Some(encoder) => encoder.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Like `sort_files()`, but rolls the output over to `name.part1.json.xz`,
+/// `name.part2.json.xz` and so on once a part reaches `max_bytes` compressed, `json.xz`
+/// is `extension` actually.
+///
+/// Parts are in timestamp order, a file smaller than `max_bytes` keeps its usual name.
+#[allow(clippy::too_many_arguments)]
+fn sort_files_to_parts<P>(
+    hourly_files: &[P],
+    output_file: &Path,
+    extension: &str,
+    max_bytes: u64,
+    profile: &Profile,
+    compress_threads: Option<u32>,
+    raw_sort_key: RawSortKey,
+    sort_order: SortOrder,
+) -> (i64, i64, Compressor, Vec<(PathBuf, i64)>, u64)
+where
+    P: AsRef<Path>,
+{
+    let mut writer = RollingWriter::new(output_file, max_bytes, compress_threads);
+    let mut total_lines = 0;
+    let mut error_lines = 0;
+    let mut sorted_bytes = 0;
+    {
+        let mut buf_writer = std::io::BufWriter::new(&mut writer);
+        for input_file in hourly_files.iter() {
+            let (e, t, b) = sort_file(
+                input_file,
+                &mut buf_writer,
+                profile,
+                raw_sort_key,
+                sort_order,
+            );
+            total_lines += t;
+            error_lines += e;
+            sorted_bytes += b;
+        }
+        profile.time(Phase::Write, || buf_writer.flush().unwrap());
+    }
+    let (compressor, tmp_parts) = profile.time(Phase::Write, || writer.finish().unwrap());
+    if error_lines != 0 {
+        error!(
+            "Found {} malformed lines out of total {} total lines for {}",
+            error_lines,
+            total_lines,
+            output_file.display()
+        );
+        for (tmp_file, _) in tmp_parts {
+            std::fs::remove_file(tmp_file).unwrap();
+        }
+        return (
+            error_lines,
+            total_lines,
+            compressor,
+            Vec::new(),
+            sorted_bytes,
+        );
+    }
+    let num_parts = tmp_parts.len();
+    let parts = tmp_parts
+        .into_iter()
+        .enumerate()
+        .map(|(index, (tmp_file, lines))| {
+            let part_file = if num_parts == 1 {
+                output_file.to_path_buf()
+            } else {
+                let file_name = output_file.to_str().unwrap();
+                PathBuf::from(format!(
+                    "{}.part{}.{extension}",
+                    &file_name[..file_name.len() - extension.len() - 1],
+                    index + 1
+                ))
+            };
+            std::fs::File::open(tmp_file.as_path())
+                .and_then(|f| f.sync_all())
+                .unwrap();
+            std::fs::rename(tmp_file.as_path(), part_file.as_path()).unwrap();
+            (part_file, lines)
+        })
+        .collect();
+    (error_lines, total_lines, compressor, parts, sorted_bytes)
+}
+
+// Use xz if use_xz is true, and semaphore allows only two xz processes
+#[allow(clippy::too_many_arguments)]
+fn sort_files<P>(
+    mut hourly_files: Vec<P>,
+    output_file: P,
+    expected_files: usize,
+    use_xz: bool,
+    semaphore: Arc<Semaphore>,
+    profile: Arc<Profile>,
+    compress_threads: Option<u32>,
+    raw_sort_key: RawSortKey,
+    sort_order: SortOrder,
+    max_file_size: Option<u64>,
+    extension: &str,
+    json_style: OutputJsonStyle,
+) -> (i64, i64, Compressor, Vec<(PathBuf, i64)>, u64)
+where
+    P: AsRef<Path>,
+{
+    for input_file in hourly_files.iter() {
+        let file_name = input_file.as_ref().to_str().unwrap();
+        assert!(file_name.ends_with(".json.gz") || file_name.ends_with(".json"));
+        if !input_file.as_ref().exists() {
+            panic!("{:?} does not exist", input_file.as_ref().display());
+        }
+    }
+    if hourly_files.len() < expected_files {
+        warn!(
+            "There are only {} files for {}",
+            hourly_files.len(),
+            output_file.as_ref().display()
+        );
+    }
+    // hours are concatenated in the sort order too
+    hourly_files.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+    if sort_order == SortOrder::Desc {
+        hourly_files.reverse();
+    }
+    assert!(output_file
+        .as_ref()
+        .to_str()
+        .unwrap()
+        .ends_with(&format!(".{extension}")));
+    if let Some(max_bytes) = max_file_size {
+        return sort_files_to_parts(
+            &hourly_files,
+            output_file.as_ref(),
+            extension,
+            max_bytes,
+            &profile,
+            compress_threads,
+            raw_sort_key,
+            sort_order,
+        );
+    }
+    // Write to a temporary file and rename it to output_file only after success,
+    // so that a crash never leaves a partial output_file
+    let tmp_file = {
+        let mut path = output_file.as_ref().as_os_str().to_os_string();
+        path.push(".tmp");
+        PathBuf::from(path)
+    };
+
+    let mt_stream = mt_stream(compress_threads);
+    let use_xz = use_xz && mt_stream.is_none();
+    let compressor = if mt_stream.is_some() {
+        Compressor::XzMt
+    } else if use_xz {
+        Compressor::ExternalXz
+    } else {
+        Compressor::Xz
+    };
+
+    let writer: Box<dyn std::io::Write> = if let Some(stream) = mt_stream {
+        let f_out = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(tmp_file.as_path())
+            .unwrap();
+        let e = xz2::write::XzEncoder::new_stream(f_out, stream);
+        Box::new(std::io::BufWriter::new(e))
+    } else if !use_xz {
+        let f_out = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(tmp_file.as_path())
+            .unwrap();
+        let e = xz2::write::XzEncoder::new(f_out, 6);
+        Box::new(std::io::BufWriter::new(e))
+    } else {
+        let json_file = {
+            let output_dir = output_file.as_ref().parent().unwrap().to_path_buf();
+            let filename = output_file.as_ref().file_name().unwrap().to_str().unwrap();
+            output_dir.join(&filename[..filename.len() - 3])
+        };
+        let f_out = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
             .truncate(true)
             .open(json_file.as_path())
             .unwrap();
-        Box::new(std::io::BufWriter::new(f_out))
+        Box::new(std::io::BufWriter::new(f_out))
+    };
+    let mut writer = JsonStyleWriter::new(writer, json_style);
+
+    let mut total_lines = 0;
+    let mut error_lines = 0;
+    let mut sorted_bytes = 0;
+    for input_file in hourly_files.iter() {
+        let (e, t, b) = sort_file(input_file, &mut writer, &profile, raw_sort_key, sort_order);
+        total_lines += t;
+        error_lines += e;
+        sorted_bytes += b;
+    }
+    profile.time(Phase::Write, || {
+        let mut writer = writer.finish().unwrap();
+        writer.flush().unwrap();
+        drop(writer); // finishes the encoder
+    });
+    if error_lines == 0 {
+        if use_xz {
+            // Wait for the semaphore which allows only two xz processes
+            let permit = semaphore.acquire();
+            let json_file = {
+                let output_dir = output_file.as_ref().parent().unwrap().to_path_buf();
+                let filename = output_file.as_ref().file_name().unwrap().to_str().unwrap();
+                output_dir.join(&filename[..filename.len() - 3])
+            };
+            let f_out = std::fs::File::create(tmp_file.as_path()).unwrap();
+            match profile.time(Phase::Write, || {
+                unpinned(&mut std::process::Command::new("xz"))
+                    .args(["-9", "-c", "-T0", json_file.as_path().to_str().unwrap()])
+                    .stdout(f_out)
+                    .output()
+            }) {
+                Ok(output) => {
+                    if !output.status.success() {
+                        panic!("{}", String::from_utf8_lossy(&output.stderr));
+                    }
+                }
+                Err(err) => panic!("{}", err),
+            }
+            drop(permit);
+            std::fs::remove_file(json_file.as_path()).unwrap();
+        }
+        // xz has a CRC64 checksum of its own, sync to disk before making it visible
+        std::fs::File::open(tmp_file.as_path())
+            .and_then(|f| f.sync_all())
+            .unwrap();
+        std::fs::rename(tmp_file.as_path(), output_file.as_ref()).unwrap();
+        let parts = vec![(output_file.as_ref().to_path_buf(), total_lines)];
+        (error_lines, total_lines, compressor, parts, sorted_bytes)
+    } else {
+        error!(
+            "Found {} malformed lines out of total {} total lines for {}",
+            error_lines,
+            total_lines,
+            output_file.as_ref().display()
+        );
+        // cleanup
+        if use_xz {
+            let json_file = {
+                let output_dir = output_file.as_ref().parent().unwrap().to_path_buf();
+                let filename = output_file.as_ref().file_name().unwrap().to_str().unwrap();
+                output_dir.join(&filename[..filename.len() - 3])
+            };
+            std::fs::remove_file(json_file.as_path()).unwrap();
+        } else {
+            std::fs::remove_file(tmp_file.as_path()).unwrap();
+        }
+        (
+            error_lines,
+            total_lines,
+            compressor,
+            Vec::new(),
+            sorted_bytes,
+        )
+    }
+}
+
+/// `dedup_dir/exchange.market_type.msg_type.day.kind.dedup`, kind is `raw` or `parsed`.
+fn dedup_file(
+    dedup_dir: &str,
+    exchange: &str,
+    market_type: MarketType,
+    msg_type: MessageType,
+    day: &str,
+    kind: &str,
+) -> PathBuf {
+    Path::new(dedup_dir).join(format!(
+        "{exchange}.{market_type}.{msg_type}.{day}.{kind}.dedup"
+    ))
+}
+
+/// Load hashes saved by `save_dedup()`, an empty set if the file does not exist.
+fn load_dedup(dedup_file: &Path) -> DashSet<u64> {
+    match std::fs::read(dedup_file) {
+        Ok(bytes) => bytes
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect(),
+        Err(_) => DashSet::new(),
+    }
+}
+
+/// Save hashes as little-endian u64 integers, 8 bytes per message.
+fn save_dedup(dedup_file: &Path, hashes: &DashSet<u64>) {
+    std::fs::create_dir_all(dedup_file.parent().unwrap()).unwrap();
+    let mut bytes: Vec<u8> = Vec::with_capacity(hashes.len() * 8);
+    for hash in hashes.iter() {
+        bytes.extend_from_slice(&hash.key().to_le_bytes());
+    }
+    let tmp_file = dedup_file.with_extension("dedup.tmp");
+    std::fs::write(tmp_file.as_path(), bytes).unwrap();
+    std::fs::rename(tmp_file.as_path(), dedup_file).unwrap();
+}
+
+/// `bloom_dir/exchange.market_type.msg_type.day.bloom`.
+fn bloom_file(
+    bloom_dir: &str,
+    exchange: &str,
+    market_type: MarketType,
+    msg_type: MessageType,
+    day: &str,
+) -> PathBuf {
+    Path::new(bloom_dir).join(format!("{exchange}.{market_type}.{msg_type}.{day}.bloom"))
+}
+
+/// A Bloom filter of message hashes of a day for `--bloom-dir`.
+///
+/// `contains()` never misses an inserted hash, but returns true for other hashes with the
+/// probability it is sized for.
+struct BloomFilter {
+    /// Number of bit positions per hash
+    hashes: u64,
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    /// A filter of `n` hashes with the false positive rate `fp_rate`.
+    fn new(n: usize, fp_rate: f64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(n.max(1) as f64) * fp_rate.ln() / (ln2 * ln2)).ceil() as usize;
+        let hashes = ((num_bits as f64 / n.max(1) as f64) * ln2).round().max(1.0) as u64;
+        BloomFilter {
+            hashes,
+            bits: vec![0; num_bits.div_ceil(64).max(1)],
+        }
+    }
+
+    /// Bit positions of `hash` by double hashing.
+    fn positions(&self, hash: u64) -> impl Iterator<Item = usize> + '_ {
+        let num_bits = self.bits.len() as u64 * 64;
+        // the high and the low halves of the 64-bit hash
+        let h1 = hash >> 32;
+        let h2 = (hash & 0xffff_ffff) | 1;
+        (0..self.hashes).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+
+    fn insert(&mut self, hash: u64) {
+        let positions: Vec<usize> = self.positions(hash).collect();
+        for position in positions {
+            self.bits[position / 64] |= 1 << (position % 64);
+        }
+    }
+
+    fn contains(&self, hash: u64) -> bool {
+        self.positions(hash)
+            .all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
+    }
+
+    /// Save the number of hashes followed by the bits as little-endian u64 integers.
+    fn save(&self, bloom_file: &Path) {
+        std::fs::create_dir_all(bloom_file.parent().unwrap()).unwrap();
+        let mut bytes: Vec<u8> = Vec::with_capacity((self.bits.len() + 1) * 8);
+        bytes.extend_from_slice(&self.hashes.to_le_bytes());
+        for word in self.bits.iter() {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        let tmp_file = bloom_file.with_extension("bloom.tmp");
+        std::fs::write(tmp_file.as_path(), bytes).unwrap();
+        std::fs::rename(tmp_file.as_path(), bloom_file).unwrap();
+    }
+
+    fn load(bloom_file: &Path) -> Result<Self, String> {
+        let bytes = std::fs::read(bloom_file).map_err(|err| err.to_string())?;
+        if bytes.len() < 16 || bytes.len() % 8 != 0 {
+            return Err(format!("malformed Bloom filter of {} bytes", bytes.len()));
+        }
+        let mut words = bytes
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()));
+        let hashes = words.next().unwrap();
+        Ok(BloomFilter {
+            hashes,
+            bits: words.collect(),
+        })
+    }
+}
+
+/// Parse a `<msg_type>` or `<market_type>` argument, `*` means all, a comma separated list
+/// selects some of them.
+fn parse_selector<T: FromStr>(arg: &str) -> Result<Option<Vec<T>>, String> {
+    if arg == "*" {
+        return Ok(None);
+    }
+    arg.split(',')
+        .map(|s| T::from_str(s.trim()).map_err(|_| format!("Unknown type: {s}")))
+        .collect::<Result<Vec<T>, String>>()
+        .map(Some)
+}
+
+/// Parse an `exchange:market_type:msg_type` argument.
+fn parse_combo(arg: &str) -> Result<(String, MarketType, MessageType), String> {
+    let v: Vec<&str> = arg.split(':').collect();
+    if v.len() != 3 || v[0].is_empty() {
+        return Err(format!("{arg} should be exchange:market_type:msg_type"));
+    }
+    let market_type =
+        MarketType::from_str(v[1]).map_err(|_| format!("Unknown market type: {}", v[1]))?;
+    let msg_type =
+        MessageType::from_str(v[2]).map_err(|_| format!("Unknown msg type: {}", v[2]))?;
+    Ok((v[0].to_string(), market_type, msg_type))
+}
+
+/// Parse a `msg_type=ext` argument, sorted files are always xz compressed.
+fn parse_output_extension(arg: &str) -> Result<(MessageType, String), String> {
+    let (msg_type, extension) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("{arg} should be msg_type=ext"))?;
+    let msg_type =
+        MessageType::from_str(msg_type).map_err(|_| format!("Unknown msg type: {msg_type}"))?;
+    if extension.starts_with('.') || extension.contains('/') || !extension.ends_with(".xz") {
+        return Err(format!(
+            "Invalid extension {extension}, it should end with .xz and have no leading dot"
+        ));
+    }
+    Ok((msg_type, extension.to_string()))
+}
+
+/// Message types which `split_file_parsed()` can parse, i.e., what `*` means for `<msg_type>`.
+const SUPPORTED_MSG_TYPES: &[MessageType] = &[MessageType::Trade, MessageType::L2Event];
+
+/// Concrete combos of msg_type and market_type having input files of `day`, sorted.
+fn combos_of_day(
+    exchange: &str,
+    msg_types: Option<&[MessageType]>,
+    market_types: Option<&[MarketType]>,
+    day: &str,
+    input_dir: &str,
+    options: &Options,
+) -> Vec<(MessageType, MarketType)> {
+    let (combos, errors) = discover_combos(
+        input_dir,
+        &options.input_pattern,
+        options.input_granularity,
+        options.input_files.as_deref(),
+        exchange,
+        msg_types.unwrap_or(SUPPORTED_MSG_TYPES),
+        day,
+    );
+    for err in errors {
+        warn!("{}, skipped", err);
+    }
+    let mut combos: Vec<(MessageType, MarketType)> = combos
+        .into_iter()
+        .map(|(_, market_type, msg_type)| (msg_type, market_type))
+        .filter(|(_, market_type)| {
+            market_types.is_none_or(|market_types| market_types.contains(market_type))
+        })
+        .collect();
+    combos.sort_by_key(|(msg_type, market_type)| (msg_type.to_string(), market_type.to_string()));
+    combos
+}
+
+/// Outcome of one stage of one day.
+#[derive(Clone, Copy, Default, Debug)]
+struct StageStats {
+    /// false if the stage was skipped by `--stage` or had no files
+    ran: bool,
+    success: bool,
+    /// Stopped by `--max-stage-secs`, it is not a success
+    timed_out: bool,
+    /// Stopped by `--max-output-bytes`, it is not a success
+    output_full: bool,
+    /// Lines of input files for the split stage, or lines of sorted files for the sort stage
+    total_lines: i64,
+    error_lines: i64,
+    elapsed_secs: u64,
+    /// Bytes of lines written by the stage, before compression
+    uncompressed_bytes: u64,
+    /// Bytes of files written by the stage, i.e., splitted files or sorted files
+    compressed_bytes: u64,
+}
+
+/// Log the compression ratio of files written by `stage`, e.g., `split binance spot trade 2021-09-02`.
+fn log_compression(stage: &str, uncompressed_bytes: u64, compressed_bytes: u64) {
+    info!(
+        "Compressed {} bytes of lines to {} bytes of files in {}, ratio {:.2}",
+        uncompressed_bytes,
+        compressed_bytes,
+        stage,
+        uncompressed_bytes as f64 / compressed_bytes.max(1) as f64
+    );
+}
+
+impl StageStats {
+    /// A stage which has not run, it does not fail the day.
+    fn skipped() -> Self {
+        StageStats {
+            success: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// Outcome of `process_files_of_day()`.
+#[derive(Clone, Copy, Debug)]
+struct DayResult {
+    split: StageStats,
+    sort: StageStats,
+}
+
+impl DayResult {
+    fn success(&self) -> bool {
+        self.split.success && self.sort.success
+    }
+
+    fn timed_out(&self) -> bool {
+        self.split.timed_out || self.sort.timed_out
+    }
+
+    fn output_full(&self) -> bool {
+        self.split.output_full || self.sort.output_full
+    }
+}
+
+/// Extrapolate the remaining time of a stage from the bytes of finished jobs, None until
+/// enough of them finish for an estimate.
+fn estimate_remaining(
+    elapsed: Duration,
+    finished_bytes: u64,
+    total_bytes: u64,
+) -> Option<Duration> {
+    // the largest files run first, so a few percent are already representative
+    if finished_bytes == 0 || finished_bytes * 100 < total_bytes * ETA_MIN_PERCENT {
+        return None;
+    }
+    let remaining_bytes = total_bytes.saturating_sub(finished_bytes);
+    Some(elapsed.mul_f64(remaining_bytes as f64 / finished_bytes as f64))
+}
+
+/// A rough duration for logs, e.g., `18 min`, `1.5 h` or `40 s`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{:.1} h", secs as f64 / 3600.0)
+    } else if secs >= 60 {
+        format!("{} min", (secs + 30) / 60)
+    } else {
+        format!("{secs} s")
+    }
+}
+
+/// A job of `--verbose-timing`, i.e., its file, size in bytes, lines and duration.
+type JobTiming = (PathBuf, u64, i64, Duration);
+
+/// Log the slowest jobs of a stage, slowest first.
+fn log_slowest_jobs(
+    stage: &str,
+    exchange: &str,
+    market_type: MarketType,
+    msg_type: MessageType,
+    day: &str,
+    mut timings: Vec<JobTiming>,
+) {
+    timings.sort_by_key(|timing| Reverse(timing.3));
+    let slowest: Vec<String> = timings
+        .iter()
+        .take(SLOWEST_JOBS)
+        .map(|(file, bytes, lines, elapsed)| {
+            format!(
+                "{} {:.3}s {} bytes {} lines",
+                file.file_name().unwrap().to_string_lossy(),
+                elapsed.as_secs_f64(),
+                bytes,
+                lines
+            )
+        })
+        .collect();
+    info!(
+        "Slowest {} of {} {} jobs of {} {} {} {}: {}",
+        slowest.len(),
+        timings.len(),
+        stage,
+        exchange,
+        market_type,
+        msg_type,
+        day,
+        slowest.join(", ")
+    );
+}
+
+/// Whether the deadline of `--max-stage-secs` has passed, then a job is skipped.
+fn past_deadline(deadline: Option<Instant>, timed_out: &AtomicBool) -> bool {
+    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        timed_out.store(true, Ordering::SeqCst);
+        true
+    } else {
+        false
+    }
+}
+
+/// Split input files of one day by symbol, to raw and parsed `.json.gz` files.
+#[allow(clippy::too_many_arguments)]
+fn split_files_of_day(
+    exchange: &str,
+    msg_type: MessageType,
+    market_type: MarketType,
+    day: &str,
+    input_dir: &str,
+    output_dir_raw: &str,
+    output_dir_parsed: &str,
+    options: &Options,
+    thread_pool: &ThreadPool,
+    memory_budget: &Arc<MemoryBudget>,
+    output_budget: &Arc<OutputBudget>,
+) -> StageStats {
+    let glob_pattern = input_glob_pattern(
+        input_dir,
+        &options.input_pattern,
+        exchange,
+        market_type,
+        msg_type,
+        day,
+    );
+    let mut paths: Vec<PathBuf> = list_input_files(&glob_pattern, options.input_files.as_deref());
+    let zero_hour = Regex::new(r"\d{4}-\d{2}-\d{2}[-T]00[-.]").unwrap();
+    if paths
+        .iter()
+        .filter(|s| !zero_hour.is_match(s.file_name().unwrap().to_str().unwrap()))
+        .count()
+        == 0
+    {
+        warn!("There are no files to split, pattern: {}", glob_pattern);
+        return StageStats::skipped();
+    }
+    if options.spillover_hours > 0 {
+        // Add addtional files of tomorrow, because there might be some messages belong to today
+        let next_day = {
+            let day_timestamp = DateTime::parse_from_rfc3339(format!("{day}T00:00:00Z").as_str())
+                .unwrap()
+                .timestamp_millis()
+                / 1000;
+            let next_day: DateTime<Utc> = Utc.timestamp_opt(day_timestamp + 24 * 3600, 0).unwrap();
+            next_day.format("%Y-%m-%d").to_string()
+        };
+        let glob_pattern = input_glob_pattern(
+            input_dir,
+            &options.input_pattern,
+            exchange,
+            market_type,
+            msg_type,
+            &next_day,
+        );
+        // Only the first hours of tomorrow, daily files of tomorrow are skipped
+        let hours: Vec<String> = (0..options.spillover_hours)
+            .map(|hour| format!("{hour:02}"))
+            .collect();
+        let first_hours = Regex::new(&format!(r"{next_day}[-T]({})[-.]", hours.join("|"))).unwrap();
+        let mut paths_of_next_day: Vec<PathBuf> =
+            list_input_files(&glob_pattern, options.input_files.as_deref())
+                .into_iter()
+                .filter(|path| first_hours.is_match(path.file_name().unwrap().to_str().unwrap()))
+                .collect();
+        paths.append(&mut paths_of_next_day);
+    }
+    for path in paths.iter() {
+        if let Some(format) = InputFormat::detect(path) {
+            let expected = InputFormat::from_extension(path);
+            if format != expected {
+                warn!(
+                    "{} is {:?} by its content rather than {:?} by its extension",
+                    path.display(),
+                    format,
+                    expected
+                );
+            }
+        }
+    }
+    info!(
+        "Started split {} {} {} {}, on error {:?}",
+        exchange, market_type, msg_type, day, options.on_error
+    );
+    if options.output.parsed() {
+        for (_, skipped_market_type, _) in options.skip_parse.iter().filter(|(e, m, t)| {
+            e == exchange
+                && *t == msg_type
+                && (*m == market_type || market_type == MarketType::Unknown)
+        }) {
+            warn!(
+                "Skipping parse of {} {} {} per --skip-parse, only raw messages are written",
+                exchange, skipped_market_type, msg_type
+            );
+        }
+    }
+    // The first element is true if it comes from split_file_parsed(), followed by the input
+    // file, its size and the duration of the job
+    #[allow(clippy::type_complexity)]
+    let (tx, rx): (
+        SyncSender<(bool, PathBuf, u64, Duration, (i64, i64, i64, i64, i64))>,
+        Receiver<(bool, PathBuf, u64, Duration, (i64, i64, i64, i64, i64))>,
+    ) = mpsc::sync_channel(options.channel_capacity);
+    let start_timstamp = Instant::now();
+    if options.deterministic {
+        paths.sort();
+    } else {
+        // Larger files get processed first
+        paths.sort_by_cached_key(|path| Reverse(std::fs::metadata(path).unwrap().len()));
+    }
+    let compressed_bytes: u64 = paths
+        .iter()
+        .map(|path| std::fs::metadata(path).unwrap().len())
+        .sum();
+    // splitted files alone take about the size of input files
+    for output_dir in options.output_dirs(output_dir_raw, output_dir_parsed) {
+        if let Some(available) = available_space(Path::new(output_dir)) {
+            if available < compressed_bytes {
+                warn!(
+                    "Only {} bytes are available on the disk of {}, less than {} bytes of input files of {} {} {} {}",
+                    available, output_dir, compressed_bytes, exchange, market_type, msg_type, day
+                );
+            }
+        }
+    }
+
+    let dedup_files = options.dedup_dir.as_ref().map(|dedup_dir| {
+        (
+            dedup_file(dedup_dir, exchange, market_type, msg_type, day, "raw"),
+            dedup_file(dedup_dir, exchange, market_type, msg_type, day, "parsed"),
+        )
+    });
+    // resume from hashes of messages written by a previous run
+    let resuming = dedup_files
+        .as_ref()
+        .is_some_and(|(raw, parsed)| raw.exists() || parsed.exists());
+    let (written_to_raw, written_to_parsed): (Arc<DashSet<u64>>, Arc<DashSet<u64>>) =
+        if let Some((raw, parsed)) = dedup_files.as_ref().filter(|_| resuming) {
+            let written_to_raw = load_dedup(raw);
+            let written_to_parsed = load_dedup(parsed);
+            info!(
+                "Loaded {} raw and {} parsed hashes of {} {} {} {} from {}",
+                written_to_raw.len(),
+                written_to_parsed.len(),
+                exchange,
+                market_type,
+                msg_type,
+                day,
+                options.dedup_dir.as_ref().unwrap()
+            );
+            (Arc::new(written_to_raw), Arc::new(written_to_parsed))
+        } else {
+            (Arc::new(DashSet::new()), Arc::new(DashSet::new()))
+        };
+    let previous_day = options.bloom_dir.as_ref().and_then(|bloom_dir| {
+        let previous_day = NaiveDate::parse_from_str(day, "%Y-%m-%d")
+            .unwrap()
+            .pred_opt()
+            .unwrap()
+            .format("%Y-%m-%d")
+            .to_string();
+        let bloom_file = bloom_file(bloom_dir, exchange, market_type, msg_type, &previous_day);
+        match BloomFilter::load(&bloom_file) {
+            Ok(bloom) => {
+                info!(
+                    "Loaded the Bloom filter of {} {} {} {} from {}, messages in it are duplicates",
+                    exchange,
+                    market_type,
+                    msg_type,
+                    previous_day,
+                    bloom_file.display()
+                );
+                Some(bloom)
+            }
+            Err(err) => {
+                info!(
+                    "No Bloom filter of the previous day {}, {}",
+                    bloom_file.display(),
+                    err
+                );
+                None
+            }
+        }
+    });
+    // visited sets only grow until the end of the stage
+    let mut visited_reservation = memory_budget.reserve(0);
+    visited_reservation
+        .grow((written_to_raw.len() + written_to_parsed.len()) as u64 * VISITED_ENTRY_BYTES);
+    // raw and parsed files share the budget of open files
+    let max_open_files = options.max_open_files.unwrap_or_else(|| {
+        (getrlimit(Resource::NOFILE).unwrap().0 as usize).saturating_sub(RESERVED_OPEN_FILES)
+    });
+    let splitted_files_raw = Arc::new(SplittedFiles::new(
+        (max_open_files / 2).max(1),
+        options.buffer_small_files,
+        resuming,
+        memory_budget.clone(),
+        output_budget.clone(),
+    ));
+    let splitted_files_parsed = Arc::new(SplittedFiles::new(
+        (max_open_files / 2).max(1),
+        options.buffer_small_files,
+        resuming,
+        memory_budget.clone(),
+        output_budget.clone(),
+    ));
+    let rejects_of = |kind: &str| {
+        Rejects::new(options.rejected_lines.then(|| {
+            format!("rejected_lines.{exchange}.{market_type}.{msg_type}.{day}.{kind}.json.gz")
+        }))
+    };
+    let options = Arc::new(options.clone());
+    let failures = Arc::new(Failures::new(options.on_error));
+    let ctx = Arc::new(SplitContext {
+        day: day.to_string(),
+        options: options.clone(),
+        profile: Profile::new(options.profile),
+        decompressed_bytes: AtomicU64::new(0),
+        lines_read: AtomicU64::new(0),
+        quarantined: options.quarantine_dir.as_ref().map(|_| DashMap::new()),
+        symbols: DashSet::new(),
+        unmapped_symbols: DashSet::new(),
+        pair_cache: PairCache::new(),
+        rejects_raw: rejects_of("raw"),
+        rejects_parsed: rejects_of("parsed"),
+        latencies: Latencies::new(),
+        previous_day,
+        rejected: AtomicU64::new(0),
+        parse_errors: AtomicU64::new(0),
+        parsed_empty: AtomicU64::new(0),
+        day_disagreements: AtomicU64::new(0),
+        excluded: AtomicU64::new(0),
+    });
+
+    // Validate all file names before launching any thread
+    let mut files: Vec<(PathBuf, FileNameParts)> = Vec::with_capacity(paths.len());
+    for input_file in paths {
+        let file_name_parts = if options.reparse {
+            // sorted raw files have symbols instead of hours in their names
+            Ok(FileNameParts {
+                exchange: exchange.to_string(),
+                market_type,
+                msg_type,
+                day: day.to_string(),
+            })
+        } else {
+            parse_input_file_name(input_file.as_path(), options.input_granularity)
+                .map(|(parts, _)| parts)
+        };
+        match file_name_parts {
+            Ok(file_name_parts) => files.push((input_file, file_name_parts)),
+            Err(err) => {
+                error!("Malformed input file name, {}", err);
+                return StageStats {
+                    ran: true,
+                    ..Default::default()
+                };
+            }
+        }
+    }
+
+    if options.verify_checksums || options.verify_gzip {
+        let (tx, rx) = mpsc::channel();
+        for (input_file, _) in files.iter() {
+            let input_file = input_file.clone();
+            let tx_clone = tx.clone();
+            let verify_checksums = options.verify_checksums;
+            let verify_gzip_trailer = options.verify_gzip;
+            thread_pool.execute(move || {
+                let result = if verify_checksums {
+                    verify_checksum(&input_file)
+                } else {
+                    Ok(true)
+                };
+                let result = result.and_then(|has_checksum| {
+                    if verify_gzip_trailer {
+                        verify_gzip(&input_file)?;
+                    }
+                    Ok(has_checksum)
+                });
+                tx_clone.send((input_file, result)).unwrap();
+            });
+        }
+        drop(tx);
+        let mut mismatched: HashMap<PathBuf, String> = HashMap::new();
+        for (input_file, result) in rx {
+            match result {
+                Ok(true) => {}
+                Ok(false) => warn!("{} has no checksum file", input_file.display()),
+                Err(err) => {
+                    error!("{}, {}", input_file.display(), err);
+                    mismatched.insert(input_file, err);
+                }
+            }
+        }
+        thread_pool.join();
+        if !mismatched.is_empty() {
+            if let Some(quarantined) = &ctx.quarantined {
+                files.retain(|(input_file, _)| !mismatched.contains_key(input_file));
+                for (input_file, reason) in mismatched {
+                    quarantined.insert(input_file, reason);
+                }
+            } else {
+                error!(
+                    "Failed to split {} {} {} {}, because {} files are corrupted or mismatch their checksums",
+                    exchange,
+                    market_type,
+                    msg_type,
+                    day,
+                    mismatched.len()
+                );
+                return StageStats {
+                    ran: true,
+                    ..Default::default()
+                };
+            }
+        }
+    }
+
+    let total_jobs =
+        files.len() * (options.output.raw() as usize + options.output.parsed() as usize);
+    // larger files are processed first, so the ETA is estimated by bytes instead of jobs
+    let total_job_bytes = files
+        .iter()
+        .map(|(input_file, _)| std::fs::metadata(input_file).map_or(0, |m| m.len()))
+        .sum::<u64>()
+        * (total_jobs / files.len().max(1)) as u64;
+    let deadline = options
+        .max_stage_secs
+        .map(|max_stage_secs| start_timstamp + max_stage_secs);
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let input_semaphore = options
+        .input_concurrency
+        .map(|permits| Arc::new(Semaphore::new(permits)));
+    for (input_file, file_name_parts) in files {
+        // held by the raw and parsed jobs of the file, released after both finish
+        let permit = input_semaphore
+            .as_ref()
+            .map(|semaphore| Arc::new(semaphore.acquire_owned()));
+        assert_eq!(exchange, file_name_parts.exchange);
+        if market_type != MarketType::Unknown {
+            assert_eq!(market_type, file_name_parts.market_type);
+        }
+        assert_eq!(msg_type, file_name_parts.msg_type);
+        if file_name_parts.day != day {
+            debug!(
+                "{} belongs to {}",
+                input_file.display(),
+                file_name_parts.day
+            );
+        }
+        let msg_type_str = msg_type.to_string();
+        let input_bytes = std::fs::metadata(&input_file).map_or(0, |m| m.len());
+
+        if options.output.raw() {
+            let input_file_clone = input_file.clone();
+            let file_name_parts_clone = file_name_parts.clone();
+            let exchange_output_dir_raw = Path::new(output_dir_raw)
+                .join(msg_type_str.as_str())
+                .join(exchange);
+            let splitted_files_raw_clone = splitted_files_raw.clone();
+            let written_to_raw_clone = if options.dedup_scope == DedupScope::File {
+                Arc::new(DashSet::new())
+            } else {
+                written_to_raw.clone()
+            };
+            let ctx_clone = ctx.clone();
+            let failures_clone = failures.clone();
+            let permit_clone = permit.clone();
+            let timed_out_clone = timed_out.clone();
+            let tx_clone = tx.clone();
+            thread_pool.execute(move || {
+                if past_deadline(deadline, &timed_out_clone)
+                    || splitted_files_raw_clone.output_budget.exceeded()
+                {
+                    return;
+                }
+                let input_file = input_file_clone.clone();
+                let started = Instant::now();
+                let t = failures_clone.run(&input_file, || {
+                    split_file_raw(
+                        input_file_clone,
+                        file_name_parts_clone,
+                        exchange_output_dir_raw,
+                        splitted_files_raw_clone,
+                        written_to_raw_clone,
+                        ctx_clone,
+                    )
+                });
+                // released before blocking on a full channel, which is drained only after
+                // all files are submitted
+                drop(permit_clone);
+                if let Some(t) = t {
+                    tx_clone
+                        .send((false, input_file, input_bytes, started.elapsed(), t))
+                        .unwrap();
+                }
+            });
+        }
+
+        if options.output.parsed() {
+            let input_file_clone = input_file.clone();
+            // --combined writes parsed messages to the files of raw messages
+            let splitted_files_parsed_clone = if options.combined {
+                splitted_files_raw.clone()
+            } else {
+                splitted_files_parsed.clone()
+            };
+            let exchange_output_dir_parsed = Path::new(if options.combined {
+                output_dir_raw
+            } else {
+                output_dir_parsed
+            })
+            .join(msg_type_str.as_str())
+            .join(exchange);
+            let written_to_parsed_clone = if options.dedup_scope == DedupScope::File {
+                Arc::new(DashSet::new())
+            } else {
+                written_to_parsed.clone()
+            };
+            let ctx_clone = ctx.clone();
+            let failures_clone = failures.clone();
+            let timed_out_clone = timed_out.clone();
+            let tx_clone = tx.clone();
+            thread_pool.execute(move || {
+                if past_deadline(deadline, &timed_out_clone)
+                    || splitted_files_parsed_clone.output_budget.exceeded()
+                {
+                    return;
+                }
+                let started = Instant::now();
+                let t = failures_clone.run(&input_file, || {
+                    split_file_parsed(
+                        input_file_clone,
+                        file_name_parts,
+                        exchange_output_dir_parsed,
+                        splitted_files_parsed_clone,
+                        written_to_parsed_clone,
+                        ctx_clone,
+                    )
+                });
+                drop(permit);
+                if let Some(t) = t {
+                    tx_clone
+                        .send((true, input_file, input_bytes, started.elapsed(), t))
+                        .unwrap();
+                }
+            });
+        }
+    }
+    // Receive while worker threads are still running, otherwise they would block on a full channel
+    drop(tx); // drop the sender to unblock receiver
+    let mut stats_raw = (0, 0, 0, 0, 0);
+    let mut stats_parsed = (0, 0, 0, 0, 0);
+    let mut finished_jobs = 0;
+    let mut finished_job_bytes = 0;
+    let mut timings: Vec<JobTiming> = Vec::new();
+    let mut last_progress = Instant::now();
+    loop {
+        // wake up for progress logs even if no job finishes
+        let received = match options.progress_interval {
+            Some(interval) => rx.recv_timeout(interval.saturating_sub(last_progress.elapsed())),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok((is_parsed, input_file, input_bytes, elapsed, t)) => {
+                if options.verbose_timing {
+                    info!(
+                        "Split {} of {} bytes, {} lines to {} files, in {:.3} seconds",
+                        input_file.display(),
+                        input_bytes,
+                        t.0,
+                        if is_parsed { "parsed" } else { "raw" },
+                        elapsed.as_secs_f64()
+                    );
+                    timings.push((input_file, input_bytes, t.0, elapsed));
+                }
+                let stats = if is_parsed {
+                    &mut stats_parsed
+                } else {
+                    &mut stats_raw
+                };
+                stats.0 += t.0;
+                stats.1 += t.1;
+                stats.2 += t.2;
+                stats.3 += t.3;
+                stats.4 += t.4;
+                if options.dedup_scope == DedupScope::Combo {
+                    // sets of --dedup-scope file are dropped when their jobs finish
+                    visited_reservation.grow(t.1 as u64 * VISITED_ENTRY_BYTES);
+                }
+                finished_jobs += 1;
+                finished_job_bytes += input_bytes;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if let Some(interval) = options.progress_interval {
+            if last_progress.elapsed() >= interval {
+                let eta = estimate_remaining(
+                    start_timstamp.elapsed(),
+                    finished_job_bytes,
+                    total_job_bytes,
+                )
+                .map(|remaining| format!(", done in ~{}", format_duration(remaining)))
+                .unwrap_or_default();
+                info!(
+                    "Splitting {} {} {} {}, {}/{} jobs finished, {} lines read, {} distinct symbols so far, time elapsed {} seconds{}",
+                    exchange,
+                    market_type,
+                    msg_type,
+                    day,
+                    finished_jobs,
+                    total_jobs,
+                    ctx.lines_read.load(Ordering::Relaxed),
+                    ctx.symbols.len(),
+                    start_timstamp.elapsed().as_secs(),
+                    eta
+                );
+                last_progress = Instant::now();
+            }
+        }
+    }
+    thread_pool.join();
+    if options.verbose_timing {
+        log_slowest_jobs("split", exchange, market_type, msg_type, day, timings);
+    }
+    if memory_budget.exceeded() {
+        warn!(
+            "Split {} {} {} {} exceeded --memory-limit, {} bytes are in use, {} visited hashes take about {} bytes",
+            exchange,
+            market_type,
+            msg_type,
+            day,
+            memory_budget.used(),
+            written_to_raw.len() + written_to_parsed.len(),
+            visited_reservation.bytes
+        );
+    }
+    if let Some((raw, parsed)) = dedup_files.as_ref() {
+        // saved even if the stage fails, so that a rerun does not write the same messages again
+        save_dedup(raw, &written_to_raw);
+        save_dedup(parsed, &written_to_parsed);
+    }
+    // raw and parsed jobs read the same input lines
+    let input_stats = if options.output.raw() {
+        stats_raw
+    } else {
+        stats_parsed
+    };
+    let stage_stats = |success: bool| StageStats {
+        ran: true,
+        success,
+        total_lines: input_stats.0,
+        error_lines: input_stats.3,
+        elapsed_secs: start_timstamp.elapsed().as_secs(),
+        ..Default::default()
+    };
+    if options.profile {
+        ctx.profile
+            .report("split", exchange, market_type, msg_type, day);
+    }
+    debug!(
+        "Hit rate of the pair cache of {} {} {} {} is {:.4}, {} symbols",
+        exchange,
+        market_type,
+        msg_type,
+        day,
+        ctx.pair_cache.hit_rate(),
+        ctx.pair_cache.len()
+    );
+    if let (Some(quarantine_dir), Some(quarantined)) = (&options.quarantine_dir, &ctx.quarantined) {
+        move_to_quarantine(input_dir, quarantine_dir, quarantined);
+    }
+    let decompressed_bytes = ctx.decompressed_bytes.load(Ordering::SeqCst);
+    info!(
+        "Decompressed {} bytes from {} bytes of {} {} {} {}, ratio {:.2}",
+        decompressed_bytes,
+        compressed_bytes,
+        exchange,
+        market_type,
+        msg_type,
+        day,
+        decompressed_bytes as f64 / compressed_bytes.max(1) as f64
+    );
+    if !failures.report("split", exchange, market_type, msg_type, day) {
+        splitted_files_raw.close_all();
+        splitted_files_parsed.close_all();
+        return stage_stats(false);
+    }
+    if timed_out.load(Ordering::SeqCst) {
+        error!(
+            "Stopped split {} {} {} {} after --max-stage-secs {}, {}/{} jobs finished, rerun to continue{}",
+            exchange,
+            market_type,
+            msg_type,
+            day,
+            options.max_stage_secs.unwrap().as_secs(),
+            finished_jobs,
+            total_jobs,
+            if options.dedup_dir.is_some() {
+                ", written messages are not written again"
+            } else {
+                ""
+            }
+        );
+        splitted_files_raw.close_all();
+        splitted_files_parsed.close_all();
+        return StageStats {
+            timed_out: true,
+            ..stage_stats(false)
+        };
+    }
+    if output_budget.exceeded() {
+        error!(
+            "Stopped split {} {} {} {} after writing {} bytes, --max-output-bytes {}, {}/{} jobs finished, free disk space and rerun to continue",
+            exchange,
+            market_type,
+            msg_type,
+            day,
+            output_budget.written(),
+            options.max_output_bytes.unwrap(),
+            finished_jobs,
+            total_jobs
+        );
+        splitted_files_raw.close_all();
+        splitted_files_parsed.close_all();
+        return StageStats {
+            output_full: true,
+            ..stage_stats(false)
+        };
+    }
+    info!(
+        "{} distinct symbols of {} {} {} {}",
+        ctx.symbols.len(),
+        exchange,
+        market_type,
+        msg_type,
+        day
+    );
+    let symbols_of_day: Vec<String> = ctx.symbols.iter().map(|s| s.key().clone()).collect();
+    for group in symbol_collisions(&symbols_of_day) {
+        warn!(
+            "Symbols {:?} of {} {} {} {} have file names differing only by case, they are merged on case-insensitive filesystems",
+            group, exchange, market_type, msg_type, day
+        );
+    }
+    if let Some(max_symbols) = options.max_symbols {
+        if ctx.symbols.len() > max_symbols {
+            error!(
+                "Aborted split {} {} {} {}, because there are more than {} distinct symbols, check extract_symbol() or the input files",
+                exchange, market_type, msg_type, day, max_symbols
+            );
+            splitted_files_raw.close_all();
+            splitted_files_parsed.close_all();
+            return stage_stats(false);
+        }
+    }
+    let allow_empty = options.allow_empty;
+    let dedup_scope = options.dedup_scope.name();
+    let finishing = move |stats: (i64, i64, i64, i64, i64),
+                          splitted_files: Arc<SplittedFiles>,
+                          is_parsed: bool|
+          -> bool {
+        let (total_lines, unique_lines, duplicated_lines, error_lines, expired_lines) = stats;
+        if is_parsed {
+            assert_eq!(total_lines, unique_lines + duplicated_lines + error_lines);
+        } else {
+            assert_eq!(
+                total_lines,
+                unique_lines + duplicated_lines + error_lines + expired_lines
+            );
+        }
+
+        splitted_files.close_all();
+        if total_lines == 0 {
+            // the error ratio below would be NaN
+            if allow_empty {
+                warn!(
+                    "Input files of {} {} {} {} have no lines, succeeded because of --allow-empty",
+                    exchange, market_type, msg_type, day
+                );
+            } else {
+                error!(
+                    "Failed to split {} {} {} {}, because input files have no lines, the data is likely missing, use --allow-empty to accept it",
+                    exchange, market_type, msg_type, day
+                );
+            }
+            return allow_empty;
+        }
+        let error_ratio = (error_lines as f64) / (total_lines as f64);
+        if error_ratio > 0.01 && !EXEMPTED_EXCHANGES.contains(&exchange) {
+            // error ratio > 1%
+            error!(
+                "Failed to split {} {} {} {}, because error ratio {}/{}={}% is higher than 1% !",
+                exchange,
+                market_type,
+                msg_type,
+                day,
+                error_lines,
+                total_lines,
+                error_ratio * 100.0
+            );
+            false
+        } else {
+            info!("Finished split {} {} {} {}, total {} lines, {} unique lines, {} duplicated lines (dedup scope {}), {} expired lines,  {} malformed lines, time elapsed {} seconds", exchange, market_type, msg_type, day, total_lines, unique_lines, duplicated_lines, dedup_scope, expired_lines, error_lines, start_timstamp.elapsed().as_secs());
+            true
+        }
+    };
+    let parse_errors = ctx.parse_errors.load(Ordering::SeqCst);
+    let parsed_empty = ctx.parsed_empty.load(Ordering::SeqCst);
+    if parse_errors > 0 || parsed_empty > 0 {
+        if options.keep_parsed_empty && parsed_empty > 0 {
+            warn!(
+                "{} messages of {} {} {} {} failed to parse, {} messages parsed to nothing, see {}/{}/{}/parsed_empty.*.json.gz",
+                parse_errors,
+                exchange,
+                market_type,
+                msg_type,
+                day,
+                parsed_empty,
+                output_dir_parsed,
+                msg_type,
+                exchange
+            );
+        } else {
+            warn!(
+                "{} messages of {} {} {} {} failed to parse, {} messages parsed to nothing",
+                parse_errors, exchange, market_type, msg_type, day, parsed_empty
+            );
+        }
+    }
+    if options.rejected_lines {
+        for (kind, rejects, output_dir) in [
+            ("raw", &ctx.rejects_raw, output_dir_raw),
+            ("parsed", &ctx.rejects_parsed, output_dir_parsed),
+        ] {
+            let summary = rejects.summary();
+            if !summary.is_empty() {
+                info!(
+                    "Rejected {} lines of {} {} {} {} by reason: {}, see {}/{}/{}/{}",
+                    kind,
+                    exchange,
+                    market_type,
+                    msg_type,
+                    day,
+                    summary,
+                    if options.combined {
+                        output_dir_raw
+                    } else {
+                        output_dir
+                    },
+                    msg_type,
+                    exchange,
+                    rejects.file_name.as_ref().unwrap()
+                );
+            }
+        }
+    }
+    let rejected = ctx.rejected.load(Ordering::SeqCst);
+    if rejected > 0 {
+        warn!(
+            "{} parsed messages of {} {} {} {} violate the schema version {}, see {}/{}/{}/rejects.*.json.gz",
+            rejected,
+            exchange,
+            market_type,
+            msg_type,
+            day,
+            PARSED_SCHEMA_VERSION,
+            output_dir_parsed,
+            msg_type,
+            exchange
+        );
+    }
+    let excluded = ctx.excluded.load(Ordering::Relaxed);
+    if excluded > 0 {
+        info!(
+            "Excluded {} lines of {} {} {} {} by --exclude-symbols",
+            excluded, exchange, market_type, msg_type, day
+        );
+    }
+    let day_disagreements = ctx.day_disagreements.load(Ordering::Relaxed);
+    if day_disagreements > 0 {
+        info!(
+            "{} lines of {} {} {} {} have received_at and event timestamps on different days, filtered by --day-filter-basis {}",
+            day_disagreements,
+            exchange,
+            market_type,
+            msg_type,
+            day,
+            options.day_filter_basis.name()
+        );
+    }
+    if options.latency_stats {
+        let stats = ctx.latencies.stats();
+        let latency_file = Path::new(output_dir_parsed).join(format!(
+            "latency.{exchange}.{market_type}.{msg_type}.{day}.json"
+        ));
+        std::fs::write(
+            latency_file.as_path(),
+            serde_json::to_string_pretty(&stats).unwrap(),
+        )
+        .unwrap();
+        if let Some(all) = stats.get("*") {
+            info!(
+                "Latency of {} {} {} {}, p50 {} ms, p99 {} ms of {} messages, see {}",
+                exchange,
+                market_type,
+                msg_type,
+                day,
+                all.p50_ms,
+                all.p99_ms,
+                all.messages,
+                latency_file.display()
+            );
+        }
+    }
+    if !ctx.unmapped_symbols.is_empty() {
+        let mut symbols: Vec<String> = ctx
+            .unmapped_symbols
+            .iter()
+            .map(|s| s.key().clone())
+            .collect();
+        symbols.sort();
+        let unmapped_symbols_file = Path::new(output_dir_parsed).join(format!(
+            "unmapped_symbols.{exchange}.{market_type}.{day}.json"
+        ));
+        std::fs::write(
+            unmapped_symbols_file.as_path(),
+            serde_json::to_string_pretty(&symbols).unwrap(),
+        )
+        .unwrap();
+        warn!(
+            "{} symbols of {} {} {} {} can NOT be normalized, see {}",
+            symbols.len(),
+            exchange,
+            market_type,
+            msg_type,
+            day,
+            unmapped_symbols_file.display()
+        );
+    }
+    let all_splitted_files = [splitted_files_raw.clone(), splitted_files_parsed.clone()];
+    let success = (!options.output.raw() || finishing(stats_raw, splitted_files_raw, false))
+        && (!options.output.parsed() || finishing(stats_parsed, splitted_files_parsed, true));
+    let mut split_stats = stage_stats(success);
+    for splitted_files in all_splitted_files.iter() {
+        // finishing() closes them, unless the stage failed before
+        splitted_files.close_all();
+        split_stats.uncompressed_bytes += splitted_files.uncompressed_bytes.load(Ordering::Relaxed);
+        split_stats.compressed_bytes += splitted_files.file_bytes();
+    }
+    log_compression(
+        &format!("split {exchange} {market_type} {msg_type} {day}"),
+        split_stats.uncompressed_bytes,
+        split_stats.compressed_bytes,
+    );
+    if let (true, Some(bloom_dir)) = (success, options.bloom_dir.as_ref()) {
+        // raw files have messages received in the day only, parsed ones include spillover
+        let mut bloom = BloomFilter::new(written_to_raw.len(), options.bloom_fp_rate);
+        for hash in written_to_raw.iter() {
+            bloom.insert(*hash.key());
+        }
+        let bloom_file = bloom_file(bloom_dir, exchange, market_type, msg_type, day);
+        bloom.save(&bloom_file);
+        info!(
+            "Saved {} hashes of {} {} {} {} to the Bloom filter {} of {} bytes",
+            written_to_raw.len(),
+            exchange,
+            market_type,
+            msg_type,
+            day,
+            bloom_file.display(),
+            bloom.bits.len() * 8
+        );
+    }
+    split_stats
+}
+
+/// Sorted files of one day in one output directory.
+#[derive(Serialize)]
+struct Manifest<'a> {
+    exchange: &'a str,
+    market_type: MarketType,
+    msg_type: MessageType,
+    day: &'a str,
+    /// The shard which produced the files, absent without `--shard-count`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shard: Option<Shard>,
+    files: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestEntry {
+    /// Relative to the output directory
+    path: String,
+    /// Number of lines
+    lines: i64,
+    compressor: Compressor,
+    /// The original symbol, it is percent-encoded in the file name
+    symbol: String,
+}
+
+/// The original symbol of a sorted file, i.e., the component before the day in
+/// `exchange.market_type.msg_type[.base.quote].symbol.day[.partN].extension`.
+fn symbol_of_sorted_file(path: &Path, extension: &str) -> String {
+    let file_name = path.file_name().unwrap().to_str().unwrap();
+    let stem = &file_name[..file_name.len() - extension.len() - 1];
+    let v: Vec<&str> = stem.split('.').collect();
+    let index = if v.last().unwrap().starts_with("part") {
+        v.len() - 3
+    } else {
+        v.len() - 2
+    };
+    match urlencoding::decode(v[index]) {
+        Ok(symbol) => symbol.into_owned(),
+        Err(_) => v[index].to_string(),
+    }
+}
+
+/// Rename parsed files whose pair in the file name differs from what `crypto_pair` returns
+/// now for their symbol, along with their `.count` files and paths in manifests.
+///
+/// Returns the number of files which are renamed, or would be renamed if `dry_run` is true.
+fn repair_pairs(output_dir_parsed: &str, dry_run: bool) -> usize {
+    let pair_cache = PairCache::new();
+    // old path to new path, relative to output_dir_parsed
+    let mut renamed: HashMap<String, String> = HashMap::new();
+    let paths = glob(&format!(
+        "{}/*/*/*/*",
+        glob::Pattern::escape(output_dir_parsed)
+    ))
+    .unwrap()
+    .filter_map(Result::ok)
+    .filter(|path| path.is_file());
+    for path in paths {
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        if file_name.ends_with(".count") || file_name.ends_with(".tmp") {
+            continue;
+        }
+        // exchange.market_type.msg_type.base.quote.symbol.day.extension
+        let v: Vec<&str> = file_name.split('.').collect();
+        if v.len() < 8
+            || v[6]
+                .get(..10)
+                .is_none_or(|day| NaiveDate::parse_from_str(day, "%Y-%m-%d").is_err())
+        {
+            debug!("{} is not a parsed file, skipped", path.display());
+            continue;
+        }
+        let exchange = v[0];
+        let symbol = match urlencoding::decode(v[5]) {
+            Ok(symbol) => symbol.into_owned(),
+            Err(_) => {
+                warn!("{} has a malformed symbol, skipped", path.display());
+                continue;
+            }
+        };
+        let pair = match pair_cache.normalize_pair(&symbol, exchange) {
+            Some(pair) => pair,
+            None => {
+                warn!("Failed to normalize symbol {} of {}", symbol, exchange);
+                continue;
+            }
+        };
+        let (base, quote) = match pair.split_once('/') {
+            Some((base, quote)) => (encode_symbol(base), encode_symbol(quote)),
+            None => continue,
+        };
+        if base == v[3] && quote == v[4] {
+            continue;
+        }
+        let new_file_name = [v[0], v[1], v[2], &base, &quote, v[5]]
+            .into_iter()
+            .chain(v[6..].iter().copied())
+            .collect::<Vec<&str>>()
+            .join(".");
+        let new_path = path.with_file_name(&new_file_name);
+        if new_path.exists() {
+            warn!(
+                "{} already exists, {} is not renamed",
+                new_path.display(),
+                path.display()
+            );
+            continue;
+        }
+        info!("{} -> {}", path.display(), new_file_name);
+        if !dry_run {
+            std::fs::rename(path.as_path(), new_path.as_path()).unwrap();
+            let mut count_file = path.as_os_str().to_os_string();
+            count_file.push(".count");
+            if Path::new(&count_file).exists() {
+                let mut new_count_file = new_path.as_os_str().to_os_string();
+                new_count_file.push(".count");
+                std::fs::rename(count_file, new_count_file).unwrap();
+            }
+        }
+        let relative = |path: &Path| {
+            path.strip_prefix(output_dir_parsed)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        renamed.insert(relative(path.as_path()), relative(new_path.as_path()));
+    }
+    if dry_run || renamed.is_empty() {
+        return renamed.len();
+    }
+    let manifests = glob(&format!(
+        "{}/manifest.*.json",
+        glob::Pattern::escape(output_dir_parsed)
+    ))
+    .unwrap()
+    .filter_map(Result::ok);
+    for manifest_file in manifests {
+        let mut manifest: Value =
+            serde_json::from_str(&std::fs::read_to_string(manifest_file.as_path()).unwrap())
+                .unwrap();
+        let mut changed = false;
+        if let Some(files) = manifest.get_mut("files").and_then(Value::as_array_mut) {
+            for file in files.iter_mut() {
+                let new_path = file
+                    .get("path")
+                    .and_then(Value::as_str)
+                    .and_then(|path| renamed.get(path));
+                if let Some(new_path) = new_path {
+                    file["path"] = Value::String(new_path.clone());
+                    changed = true;
+                }
+            }
+        }
+        if changed {
+            let tmp_file = manifest_file.with_extension("json.tmp");
+            std::fs::write(
+                tmp_file.as_path(),
+                serde_json::to_string_pretty(&manifest).unwrap(),
+            )
+            .unwrap();
+            std::fs::rename(tmp_file.as_path(), manifest_file.as_path()).unwrap();
+        }
+    }
+    renamed.len()
+}
+
+/// Copy lines from `reader` to `writer` as they are, returns the number of lines,
+/// including a last line without a newline.
+fn copy_lines(mut reader: impl BufRead, writer: &mut dyn Write) -> std::io::Result<u64> {
+    let mut lines = 0;
+    let mut last = b'\n';
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        lines += buf.iter().filter(|b| **b == b'\n').count() as u64;
+        last = *buf.last().unwrap();
+        writer.write_all(buf)?;
+        let len = buf.len();
+        reader.consume(len);
+    }
+    Ok(if last == b'\n' { lines } else { lines + 1 })
+}
+
+/// Convert a `.json.xz` file to a `.json.gz` file next to it, with the same content, and
+/// check that the `.json.gz` file has as many lines.
+///
+/// Returns the `.json.gz` file and its number of lines, the `.json.xz` file is kept.
+fn recompress_file(xz_file: &Path) -> Result<(PathBuf, u64), String> {
+    let file_name = xz_file.to_str().unwrap();
+    let gz_file = PathBuf::from(format!("{}.gz", &file_name[..file_name.len() - 3]));
+    let tmp_file = PathBuf::from(format!("{}.gz.tmp", &file_name[..file_name.len() - 3]));
+    let f_in = std::fs::File::open(xz_file).map_err(|err| err.to_string())?;
+    let reader = std::io::BufReader::new(xz2::read::XzDecoder::new_multi_decoder(f_in));
+    let f_out = std::fs::File::create(tmp_file.as_path()).map_err(|err| err.to_string())?;
+    let mut writer = std::io::BufWriter::new(GzEncoder::new(f_out, Compression::default()));
+    let result = copy_lines(reader, &mut writer)
+        .map_err(|err| format!("malformed file, {err}"))
+        .and_then(|lines| {
+            let encoder = writer.into_inner().map_err(|err| err.to_string())?;
+            encoder.finish().map_err(|err| err.to_string())?;
+            let f_in = std::fs::File::open(tmp_file.as_path()).map_err(|err| err.to_string())?;
+            let written = copy_lines(
+                std::io::BufReader::new(MultiGzDecoder::new(f_in)),
+                &mut std::io::sink(),
+            )
+            .map_err(|err| err.to_string())?;
+            if written == lines {
+                Ok(lines)
+            } else {
+                Err(format!("{written} lines are written out of {lines} lines"))
+            }
+        });
+    match result {
+        Ok(lines) => {
+            std::fs::File::open(tmp_file.as_path())
+                .and_then(|f| f.sync_all())
+                .map_err(|err| err.to_string())?;
+            std::fs::rename(tmp_file.as_path(), gz_file.as_path())
+                .map_err(|err| err.to_string())?;
+            Ok((gz_file, lines))
+        }
+        Err(err) => {
+            std::fs::remove_file(tmp_file.as_path()).unwrap();
+            Err(err)
+        }
+    }
+}
+
+/// Convert `.json.xz` files, given directly or found under directories, to `.json.gz`.
+///
+/// Returns the number of files which failed.
+fn recompress(inputs: &[String]) -> usize {
+    let mut xz_files: Vec<PathBuf> = Vec::new();
+    for input in inputs {
+        if Path::new(input).is_dir() {
+            xz_files.extend(
+                glob(&format!("{}/**/*.json.xz", glob::Pattern::escape(input)))
+                    .unwrap()
+                    .filter_map(Result::ok),
+            );
+        } else {
+            xz_files.push(PathBuf::from(input));
+        }
+    }
+    let thread_pool = ThreadPool::new(num_cpus::get());
+    let (tx, rx) = mpsc::channel();
+    for xz_file in xz_files {
+        let tx_clone = tx.clone();
+        thread_pool.execute(move || {
+            let result = recompress_file(&xz_file);
+            tx_clone.send((xz_file, result)).unwrap();
+        });
+    }
+    drop(tx);
+    let mut total_files = 0;
+    let mut total_lines = 0;
+    let mut failed = 0;
+    for (xz_file, result) in rx {
+        total_files += 1;
+        match result {
+            Ok((gz_file, lines)) => {
+                info!(
+                    "{} -> {}, {} lines",
+                    xz_file.display(),
+                    gz_file.display(),
+                    lines
+                );
+                total_lines += lines;
+            }
+            Err(err) => {
+                error!("Failed to recompress {}, {}", xz_file.display(), err);
+                failed += 1;
+            }
+        }
+    }
+    thread_pool.join();
+    info!(
+        "Recompressed {} files to .json.gz, total {} lines, {} files failed",
+        total_files - failed,
+        total_lines,
+        failed
+    );
+    failed
+}
+
+/// Groups of distinct symbols whose file names differ only by case, they end up in the same
+/// files on case-insensitive filesystems.
+fn symbol_collisions(symbols: &[String]) -> Vec<Vec<String>> {
+    let mut groups: HashMap<String, Vec<String>> = HashMap::new();
+    for symbol in symbols {
+        groups
+            .entry(encode_symbol(symbol).to_lowercase())
+            .or_default()
+            .push(symbol.clone());
+    }
+    let mut collisions: Vec<Vec<String>> = groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .map(|mut group| {
+            group.sort();
+            group
+        })
+        .collect();
+    collisions.sort();
+    collisions
+}
+
+/// `manifest.exchange.market_type.msg_type.day.json.partial`, one `ManifestEntry` per line
+/// appended as soon as a sorted file is in place, removed after the manifest is written.
+fn partial_manifest_file(
+    output_dir: &str,
+    exchange: &str,
+    market_type: MarketType,
+    msg_type: MessageType,
+    day: &str,
+    shard: Option<Shard>,
+) -> PathBuf {
+    Path::new(output_dir).join(format!(
+        "manifest.{exchange}.{market_type}.{msg_type}.{day}{}.json.partial",
+        shard_suffix(shard)
+    ))
+}
+
+/// Append `sorted_file` to the partial manifest of the output directory containing it.
+#[allow(clippy::too_many_arguments)]
+fn append_partial_manifest(
+    output_dirs: &[&str],
+    exchange: &str,
+    market_type: MarketType,
+    msg_type: MessageType,
+    day: &str,
+    sorted_file: &Path,
+    lines: i64,
+    compressor: Compressor,
+    extension: &str,
+    shard: Option<Shard>,
+) {
+    // sorted files are always in one of output_dirs
+    let output_dir = output_dirs
+        .iter()
+        .find(|output_dir| sorted_file.starts_with(output_dir))
+        .unwrap();
+    let relative = sorted_file.strip_prefix(output_dir).unwrap();
+    let entry = ManifestEntry {
+        path: relative.to_str().unwrap().to_string(),
+        lines,
+        compressor,
+        symbol: symbol_of_sorted_file(relative, extension),
+    };
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(partial_manifest_file(
+            output_dir,
+            exchange,
+            market_type,
+            msg_type,
+            day,
+            shard,
+        ))
+        .unwrap();
+    writeln!(f, "{}", serde_json::to_string(&entry).unwrap()).unwrap();
+}
+
+/// Sorted files recorded by partial manifests of an interrupted sort stage which still
+/// exist, their splitted files are deleted only after they are recorded, so they are not
+/// sorted again, unless the process was killed in between.
+fn read_partial_manifests(
+    output_dirs: &[&str],
+    exchange: &str,
+    market_type: MarketType,
+    msg_type: MessageType,
+    day: &str,
+    shard: Option<Shard>,
+) -> Vec<(PathBuf, i64, Compressor)> {
+    let mut sorted_files = Vec::new();
+    for output_dir in output_dirs {
+        let partial_file =
+            partial_manifest_file(output_dir, exchange, market_type, msg_type, day, shard);
+        if !partial_file.exists() {
+            continue;
+        }
+        let content = std::fs::read_to_string(partial_file.as_path()).unwrap();
+        // the last line is truncated if the process was killed while appending it
+        for entry in content
+            .lines()
+            .filter_map(|line| serde_json::from_str::<ManifestEntry>(line).ok())
+        {
+            let path = Path::new(output_dir).join(entry.path);
+            if path.exists() {
+                sorted_files.push((path, entry.lines, entry.compressor));
+            }
+        }
+    }
+    sorted_files
+}
+
+/// Write `manifest.exchange.market_type.msg_type.day.json` of `sorted_files` to each output
+/// directory, `manifest.exchange.market_type.msg_type.day.shard-K-of-N.json` of a shard.
+#[allow(clippy::too_many_arguments)]
+fn write_manifests(
+    output_dirs: &[&str],
+    exchange: &str,
+    market_type: MarketType,
+    msg_type: MessageType,
+    day: &str,
+    sorted_files: &[(PathBuf, i64, Compressor)],
+    extension: &str,
+    shard: Option<Shard>,
+) {
+    for output_dir in output_dirs {
+        let mut files: Vec<ManifestEntry> = sorted_files
+            .iter()
+            .filter_map(|(path, lines, compressor)| {
+                path.strip_prefix(output_dir)
+                    .ok()
+                    .map(|relative| ManifestEntry {
+                        path: relative.to_str().unwrap().to_string(),
+                        lines: *lines,
+                        compressor: *compressor,
+                        symbol: symbol_of_sorted_file(relative, extension),
+                    })
+            })
+            .collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        let manifest = Manifest {
+            exchange,
+            market_type,
+            msg_type,
+            day,
+            shard,
+            files,
+        };
+        let manifest_file = Path::new(output_dir).join(format!(
+            "manifest.{exchange}.{market_type}.{msg_type}.{day}{}.json",
+            shard_suffix(shard)
+        ));
+        // rename makes the manifest visible atomically
+        let tmp_file = manifest_file.with_extension("json.tmp");
+        std::fs::write(
+            tmp_file.as_path(),
+            serde_json::to_string_pretty(&manifest).unwrap(),
+        )
+        .unwrap();
+        std::fs::rename(tmp_file.as_path(), manifest_file.as_path()).unwrap();
+    }
+}
+
+/// Remove sorted files of previous runs which are replaced by `sorted_files`, the number
+/// of parts of a file may differ between runs with different `--max-file-size`.
+fn remove_stale_parts(sorted_files: &[(PathBuf, i64, Compressor)], extension: &str) {
+    let current: HashSet<&Path> = sorted_files
+        .iter()
+        .map(|(path, _, _)| path.as_path())
+        .collect();
+    let part_regex = Regex::new(r"\.part\d+$").unwrap();
+    for (path, _, _) in sorted_files {
+        let file_name = path.to_str().unwrap();
+        let stem = &file_name[..file_name.len() - extension.len() - 1];
+        let stem = part_regex.replace(stem, "");
+        let mut candidates = vec![PathBuf::from(format!("{stem}.{extension}"))];
+        candidates.extend(
+            glob(&format!(
+                "{}.part*.{extension}",
+                glob::Pattern::escape(&stem)
+            ))
+            .unwrap()
+            .filter_map(Result::ok),
+        );
+        for candidate in candidates {
+            if !current.contains(candidate.as_path()) && candidate.exists() {
+                std::fs::remove_file(candidate.as_path()).unwrap();
+                let mut count_file = candidate.into_os_string();
+                count_file.push(".count");
+                let _ = std::fs::remove_file(count_file);
+            }
+        }
+    }
+}
+
+/// Directory of sorted files of one day which are not committed yet.
+fn staging_dir(
+    output_dir: &str,
+    exchange: &str,
+    market_type: MarketType,
+    msg_type: MessageType,
+    day: &str,
+    shard: Option<Shard>,
+) -> PathBuf {
+    Path::new(output_dir).join(".staging").join(format!(
+        "{exchange}.{market_type}.{msg_type}.{day}{}",
+        shard_suffix(shard)
+    ))
+}
+
+/// Move staged files and their `.count` files into place, returns their final paths.
+fn commit_staged(
+    output_dirs: &[&str],
+    exchange: &str,
+    market_type: MarketType,
+    msg_type: MessageType,
+    day: &str,
+    staged_files: Vec<(PathBuf, i64, Compressor)>,
+    shard: Option<Shard>,
+) -> Vec<(PathBuf, i64, Compressor)> {
+    let sorted_files = staged_files
+        .into_iter()
+        .map(|(staged_file, lines, compressor)| {
+            let (staging_dir, output_dir) = output_dirs
+                .iter()
+                .map(|output_dir| {
+                    (
+                        staging_dir(output_dir, exchange, market_type, msg_type, day, shard),
+                        output_dir,
+                    )
+                })
+                .find(|(staging_dir, _)| staged_file.starts_with(staging_dir))
+                .unwrap();
+            let output_file =
+                Path::new(output_dir).join(staged_file.strip_prefix(staging_dir).unwrap());
+            std::fs::create_dir_all(output_file.parent().unwrap()).unwrap();
+            std::fs::rename(staged_file.as_path(), output_file.as_path()).unwrap();
+            let mut count_file = staged_file.into_os_string();
+            count_file.push(".count");
+            if Path::new(&count_file).exists() {
+                let mut output_count_file = output_file.as_os_str().to_os_string();
+                output_count_file.push(".count");
+                std::fs::rename(count_file, output_count_file).unwrap();
+            }
+            (output_file, lines, compressor)
+        })
+        .collect();
+    for output_dir in output_dirs {
+        let staging_dir = staging_dir(output_dir, exchange, market_type, msg_type, day, shard);
+        if staging_dir.exists() {
+            std::fs::remove_dir_all(staging_dir.as_path()).unwrap();
+        }
+        // fails if other days are staged
+        let _ = std::fs::remove_dir(staging_dir.parent().unwrap());
+    }
+    sorted_files
+}
+
+/// Sort `.json.gz` files generated by `split_files_of_day()` and merge them into `.json.xz` files.
+#[allow(clippy::too_many_arguments)]
+fn sort_files_of_day(
+    exchange: &str,
+    msg_type: MessageType,
+    market_type: MarketType,
+    day: &str,
+    output_dir_raw: &str,
+    output_dir_parsed: &str,
+    options: &Options,
+    thread_pool: &ThreadPool,
+    memory_budget: &Arc<MemoryBudget>,
+    output_budget: &Arc<OutputBudget>,
+) -> StageStats {
+    let extension = options.intermediate_compression.extension();
+    let glob_pattern = if market_type == MarketType::Unknown {
+        // MarketType::Unknown means all markets
+        format!("/{msg_type}/{exchange}/*/{exchange}.*.{msg_type}.*.{day}-??.{extension}")
+    } else if exchange == "deribit"
+        && market_type == MarketType::InverseFuture
+        && msg_type == MessageType::Trade
+    {
+        format!(
+            "/{msg_type}/{exchange}/{{invere_future,inverse_swap}}/{exchange}.*.{msg_type}.*.{day}-??.{extension}"
+        )
+    } else {
+        format!(
+            "/{msg_type}/{exchange}/{market_type}/{exchange}.{market_type}.{msg_type}.*.{day}-??.{extension}"
+        )
+    };
+
+    let output_dirs = options.output_dirs(output_dir_raw, output_dir_parsed);
+    let paths: Vec<PathBuf> = output_dirs
+        .iter()
+        .flat_map(|output_dir| {
+            glob(format!("{output_dir}{glob_pattern}").as_str())
+                .unwrap()
+                .filter_map(Result::ok)
+        })
+        // splitted files of other shards sharing the output directories
+        .filter(|path| is_in_shard(&symbol_of_sorted_file(path, extension), options.shard))
+        .collect();
+    // sorted by an interrupted run
+    let resumed = read_partial_manifests(
+        &output_dirs,
+        exchange,
+        market_type,
+        msg_type,
+        day,
+        options.shard,
+    );
+    if paths.is_empty() && resumed.is_empty() {
+        warn!("There are no files to sort, pattern: {}", glob_pattern);
+        return StageStats::skipped();
+    }
+    if !resumed.is_empty() {
+        info!(
+            "Resumed {} sorted files of {} {} {} {} from partial manifests",
+            resumed.len(),
+            exchange,
+            market_type,
+            msg_type,
+            day
+        );
+    }
+    for path in paths.iter() {
+        assert!(!path.as_path().to_str().unwrap().contains(".unknown."));
+    }
+    let total_files = paths.len();
+
+    // Hourly files are named as exchange.market_type.msg_type.symbol.yyyy-MM-dd-HH.json.gz
+    let suffix_len = match options.partition {
+        Partition::Day => "-??.".len() + extension.len(),
+        Partition::Hour => ".".len() + extension.len(),
+    };
+    let paths_by_day = {
+        // group by day, or by hour
+        let mut groups: HashMap<String, Vec<PathBuf>> = HashMap::new();
+        for path in paths {
+            let file_name = path.as_path().file_name().unwrap().to_str().unwrap();
+            let key = &file_name[0..(file_name.len() - suffix_len)];
+            if !groups.contains_key(key) {
+                groups.insert(key.to_string(), vec![]);
+            }
+            groups.get_mut(key).unwrap().push(path);
+        }
+        let mut groups: Vec<Vec<PathBuf>> = groups.values().cloned().collect();
+        // Smaller groups get processed first
+        groups.sort_by_cached_key(|group| {
+            group
+                .iter()
+                .map(|file| std::fs::metadata(file).unwrap().len())
+                .sum::<u64>()
+        });
+        groups
+    };
+
+    if options.count_only {
+        return count_files_of_day(
+            exchange,
+            market_type,
+            msg_type,
+            day,
+            paths_by_day,
+            suffix_len,
+            options,
+            thread_pool,
+        );
+    }
+
+    info!(
+        "Started sort {} {} {} {}, on error {:?}",
+        exchange, market_type, msg_type, day, options.on_error
+    );
+    // The first element is the sorted file, followed by its splitted files, their size and
+    // the duration of the job
+    #[allow(clippy::type_complexity)]
+    let (tx, rx): (
+        SyncSender<(
+            PathBuf,
+            Vec<PathBuf>,
+            u64,
+            Duration,
+            (i64, i64, Compressor, Vec<(PathBuf, i64)>, u64),
+        )>,
+        Receiver<(
+            PathBuf,
+            Vec<PathBuf>,
+            u64,
+            Duration,
+            (i64, i64, Compressor, Vec<(PathBuf, i64)>, u64),
+        )>,
+    ) = mpsc::sync_channel(options.channel_capacity);
+    // remove stale manifests, they are rewritten after the sort stage succeeds
+    for output_dir in output_dirs.iter() {
+        let manifest_file = Path::new(output_dir).join(format!(
+            "manifest.{exchange}.{market_type}.{msg_type}.{day}{}.json",
+            shard_suffix(options.shard)
+        ));
+        if manifest_file.exists() {
+            std::fs::remove_file(manifest_file).unwrap();
+        }
+        if options.staging {
+            // leftover of a failed run, unless it has files of the partial manifest
+            let staging_dir = staging_dir(
+                output_dir,
+                exchange,
+                market_type,
+                msg_type,
+                day,
+                options.shard,
+            );
+            if staging_dir.exists()
+                && !resumed
+                    .iter()
+                    .any(|(path, _, _)| path.starts_with(staging_dir.as_path()))
+            {
+                std::fs::remove_dir_all(staging_dir).unwrap();
+            }
+        }
+    }
+    let start_timstamp = Instant::now();
+    // the largest files are compressed by the external xz
+    let pixz_cutoff = ((paths_by_day.len() as f64) * options.pixz_percentile) as usize;
+    let xz_exists = Path::new("/usr/bin/xz").exists();
+    let semaphore = Arc::new(Semaphore::new(MAX_PIXZ));
+    let profile = Arc::new(Profile::new(options.profile));
+    let failures = Arc::new(Failures::new(options.on_error));
+    let deadline = options
+        .max_stage_secs
+        .map(|max_stage_secs| start_timstamp + max_stage_secs);
+    let timed_out = Arc::new(AtomicBool::new(false));
+    for (index, input_files) in paths_by_day.into_iter().enumerate() {
+        let file_name = input_files[0]
+            .as_path()
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let output_file_name = format!(
+            "{}.{}",
+            &file_name[0..(file_name.len() - suffix_len)],
+            options.output_extension(msg_type)
+        );
+        let output_file = Path::new(input_files[0].parent().unwrap()).join(output_file_name);
+        let output_file = if options.staging {
+            // the same relative path in the staging directory
+            let output_dir = output_dirs
+                .iter()
+                .find(|output_dir| output_file.starts_with(output_dir))
+                .unwrap();
+            let staged_file = staging_dir(
+                output_dir,
+                exchange,
+                market_type,
+                msg_type,
+                day,
+                options.shard,
+            )
+            .join(output_file.strip_prefix(output_dir).unwrap());
+            std::fs::create_dir_all(staged_file.parent().unwrap()).unwrap();
+            staged_file
+        } else {
+            output_file
+        };
+        let tx_clone = tx.clone();
+        let semaphore_clone = semaphore.clone();
+        let profile_clone = profile.clone();
+        let failures_clone = failures.clone();
+        let compress_threads = options.compress_threads;
+        let raw_sort_key = options.raw_sort_key;
+        let sort_order = options.sort_order;
+        let max_file_size = options.max_file_size;
+        let json_style = options.output_json_style;
+        let extension = options.output_extension(msg_type).to_string();
+        let memory_budget_clone = memory_budget.clone();
+        let output_budget_clone = output_budget.clone();
+        let timed_out_clone = timed_out.clone();
+        let sizes: Vec<u64> = input_files
+            .iter()
+            .map(|file| std::fs::metadata(file).unwrap().len())
+            .collect();
+        let input_bytes: u64 = sizes.iter().sum();
+        // all lines of a sorted file are in memory at the same time
+        let estimated_bytes: u64 = input_files
+            .iter()
+            .zip(sizes.iter())
+            .map(|(file, len)| {
+                if file.extension().is_some_and(|ext| ext == "gz") {
+                    len * ESTIMATED_GZIP_RATIO
+                } else {
+                    *len
+                }
+            })
+            .sum();
+        let expected_files = match options.partition {
+            Partition::Day => 24,
+            Partition::Hour => 1,
+        };
+        // the external xz can not roll over
+        let use_xz = xz_exists
+            && max_file_size.is_none()
+            && index >= pixz_cutoff
+            && input_bytes >= options.pixz_min_size;
+        thread_pool.execute(move || {
+            // splitted files of skipped jobs are sorted by the next run
+            if past_deadline(deadline, &timed_out_clone) || output_budget_clone.exceeded() {
+                return;
+            }
+            let output_file_clone = output_file.clone();
+            let input_files_clone = input_files.clone();
+            let _reservation = memory_budget_clone.reserve(estimated_bytes);
+            let started = Instant::now();
+            let t = failures_clone.run(&output_file_clone, || {
+                sort_files(
+                    input_files,
+                    output_file,
+                    expected_files,
+                    use_xz,
+                    semaphore_clone,
+                    profile_clone,
+                    compress_threads,
+                    raw_sort_key,
+                    sort_order,
+                    max_file_size,
+                    &extension,
+                    json_style,
+                )
+            });
+            if let Some(t) = t {
+                tx_clone
+                    .send((
+                        output_file_clone,
+                        input_files_clone,
+                        input_bytes,
+                        started.elapsed(),
+                        t,
+                    ))
+                    .unwrap();
+            }
+        });
+    }
+    drop(tx); // drop the sender
+    let mut total_lines = 0;
+    let mut error_lines = 0;
+    // sorted files and their numbers of lines
+    let mut sorted_files: Vec<(PathBuf, i64, Compressor)> = Vec::new();
+    let mut timings: Vec<JobTiming> = Vec::new();
+    let mut sorted_bytes = 0;
+    let mut splitted_bytes = 0;
+    let mut output_bytes = 0;
+    for (output_file, input_files, input_bytes, elapsed, t) in rx {
+        if options.verbose_timing {
+            info!(
+                "Sorted {} from {} bytes of splitted files, {} lines, in {:.3} seconds",
+                output_file.display(),
+                input_bytes,
+                t.1,
+                elapsed.as_secs_f64()
+            );
+            timings.push((output_file, input_bytes, t.1, elapsed));
+        }
+        error_lines += t.0;
+        total_lines += t.1;
+        sorted_bytes += t.4;
+        splitted_bytes += input_bytes;
+        // parts are written only if there are no malformed lines
+        for (part_file, lines) in t.3 {
+            let part_bytes = std::fs::metadata(&part_file).map_or(0, |m| m.len());
+            output_budget.add(part_bytes);
+            output_bytes += part_bytes;
+            if options.count_files {
+                let mut count_file = part_file.as_os_str().to_os_string();
+                count_file.push(".count");
+                std::fs::write(count_file, format!("{lines}\n")).unwrap();
+            }
+            append_partial_manifest(
+                &output_dirs,
+                exchange,
+                market_type,
+                msg_type,
+                day,
+                part_file.as_path(),
+                lines,
+                t.2,
+                options.output_extension(msg_type),
+                options.shard,
+            );
+            sorted_files.push((part_file, lines, t.2));
+        }
+        // only after the partial manifest has the sorted file, so that a resumed run either
+        // sorts the splitted files again or finds the sorted file
+        for input_file in input_files {
+            std::fs::remove_file(input_file).unwrap();
+        }
+    }
+    thread_pool.join();
+    if options.verbose_timing {
+        log_slowest_jobs("sort", exchange, market_type, msg_type, day, timings);
+    }
+    for resumed_file in resumed {
+        if !sorted_files
+            .iter()
+            .any(|(path, _, _)| *path == resumed_file.0)
+        {
+            sorted_files.push(resumed_file);
+        }
+    }
+    if options.profile {
+        profile.report("sort", exchange, market_type, msg_type, day);
+    }
+    let stage_stats = |success: bool| StageStats {
+        ran: true,
+        success,
+        total_lines,
+        error_lines,
+        elapsed_secs: start_timstamp.elapsed().as_secs(),
+        uncompressed_bytes: sorted_bytes,
+        compressed_bytes: output_bytes,
+        ..Default::default()
+    };
+    let commit = |sorted_files: Vec<(PathBuf, i64, Compressor)>| {
+        let sorted_files = if options.staging {
+            commit_staged(
+                &output_dirs,
+                exchange,
+                market_type,
+                msg_type,
+                day,
+                sorted_files,
+                options.shard,
+            )
+        } else {
+            sorted_files
+        };
+        remove_stale_parts(&sorted_files, options.output_extension(msg_type));
+        // the manifest is the commit marker, written after all files are in place
+        write_manifests(
+            &output_dirs,
+            exchange,
+            market_type,
+            msg_type,
+            day,
+            &sorted_files,
+            options.output_extension(msg_type),
+            options.shard,
+        );
+        for output_dir in output_dirs.iter() {
+            let partial_file = partial_manifest_file(
+                output_dir,
+                exchange,
+                market_type,
+                msg_type,
+                day,
+                options.shard,
+            );
+            if partial_file.exists() {
+                std::fs::remove_file(partial_file).unwrap();
+            }
+        }
+    };
+    let abort = || {
+        if options.staging {
+            for output_dir in output_dirs.iter() {
+                let staging_dir = staging_dir(
+                    output_dir,
+                    exchange,
+                    market_type,
+                    msg_type,
+                    day,
+                    options.shard,
+                );
+                if !staging_dir.exists() {
+                    continue;
+                }
+                if options.clean_staging {
+                    std::fs::remove_dir_all(staging_dir).unwrap();
+                } else {
+                    warn!("Sorted files are left in {}", staging_dir.display());
+                }
+            }
+        }
+    };
+    if !failures.report("sort", exchange, market_type, msg_type, day) {
+        abort();
+        return stage_stats(false);
+    }
+    if timed_out.load(Ordering::SeqCst) {
+        // sorted files are kept, even staged ones, the partial manifest lists them
+        error!(
+            "Stopped sort {} {} {} {} after --max-stage-secs {}, {} files sorted, rerun with --stage sort to continue",
+            exchange,
+            market_type,
+            msg_type,
+            day,
+            options.max_stage_secs.unwrap().as_secs(),
+            sorted_files.len()
+        );
+        return StageStats {
+            timed_out: true,
+            ..stage_stats(false)
+        };
+    }
+    if output_budget.exceeded() {
+        // the same as a timeout, sorted files are kept
+        error!(
+            "Stopped sort {} {} {} {} after writing {} bytes, --max-output-bytes {}, {} files sorted, free disk space and rerun with --stage sort to continue",
+            exchange,
+            market_type,
+            msg_type,
+            day,
+            output_budget.written(),
+            options.max_output_bytes.unwrap(),
+            sorted_files.len()
+        );
+        return StageStats {
+            output_full: true,
+            ..stage_stats(false)
+        };
+    }
+    if output_bytes > 0 {
+        log_compression(
+            &format!("sort {exchange} {market_type} {msg_type} {day}"),
+            sorted_bytes,
+            output_bytes,
+        );
+        // splitted files are gzip unless --intermediate-compression none
+        info!(
+            "Sorted files of {} {} {} {} are {:.2} times smaller than their {} bytes of splitted files",
+            exchange,
+            market_type,
+            msg_type,
+            day,
+            splitted_bytes as f64 / output_bytes as f64,
+            splitted_bytes
+        );
+    }
+    if error_lines == 0 {
+        commit(sorted_files);
+        info!(
+            "Finished sort {} {} {} {}, {} files, total {} lines, time elapsed {} seconds",
+            exchange,
+            market_type,
+            msg_type,
+            day,
+            total_files,
+            total_lines,
+            start_timstamp.elapsed().as_secs()
+        );
+        stage_stats(true)
+    } else {
+        error!(
+            "Failed to sort {} {} {} {}, found {} malformed lines out of total {} lines, time elapsed {} seconds",
+            exchange,
+            market_type,
+            msg_type,
+            day,
+            error_lines, total_lines,
+            start_timstamp.elapsed().as_secs()
+        );
+        // if error ratio is less than 0.00001, the function is considered successful
+        let success = (error_lines as f64) / (total_lines as f64) < 0.00001;
+        if success {
+            commit(sorted_files);
+        } else {
+            abort();
+        }
+        stage_stats(success)
+    }
+}
+
+/// Check that sorted files of one day are in order, without writing any file.
+fn check_sorted_files_of_day(
+    exchange: &str,
+    msg_type: MessageType,
+    market_type: MarketType,
+    day: &str,
+    output_dirs: &[&str],
+    options: &Options,
+    thread_pool: &ThreadPool,
+) -> StageStats {
+    let start_timstamp = Instant::now();
+    let market = if market_type == MarketType::Unknown {
+        "*".to_string()
+    } else {
+        market_type.to_string()
+    };
+    let paths: Vec<PathBuf> = output_dirs
+        .iter()
+        .flat_map(|output_dir| {
+            glob(&format!(
+                "{output_dir}/{msg_type}/{exchange}/{market}/{exchange}.*.{msg_type}.*.{day}*.{}",
+                options.output_extension(msg_type)
+            ))
+            .unwrap()
+            .filter_map(Result::ok)
+        })
+        .collect();
+    if paths.is_empty() {
+        warn!(
+            "There are no sorted files of {} {} {} {}",
+            exchange, market_type, msg_type, day
+        );
+        return StageStats::skipped();
+    }
+    let (tx, rx) = mpsc::sync_channel(options.channel_capacity);
+    for path in paths {
+        let tx_clone = tx.clone();
+        let raw_sort_key = options.raw_sort_key;
+        let sort_order = options.sort_order;
+        let max_gap_ms = options.max_gap_ms;
+        thread_pool.execute(move || {
+            let result = check_sorted(&path, raw_sort_key, sort_order, max_gap_ms);
+            tx_clone.send((path, result)).unwrap();
+        });
+    }
+    drop(tx);
+    let mut total_files = 0;
+    let mut total_lines = 0;
+    let mut error_lines = 0;
+    let mut anomalies_of_files: Vec<(PathBuf, Vec<ClockAnomaly>)> = Vec::new();
+    for (path, result) in rx {
+        total_files += 1;
+        match result {
+            Ok((lines, anomalies)) => {
+                total_lines += lines;
+                if !anomalies.is_empty() {
+                    anomalies_of_files.push((path, anomalies));
+                }
+            }
+            Err(err) => {
+                error!("{} is not sorted, {}", path.display(), err);
+                error_lines += 1;
+            }
+        }
+    }
+    thread_pool.join();
+    // the report goes to stdout, one anomaly per line, in the order of files and lines
+    anomalies_of_files.sort_by(|a, b| a.0.cmp(&b.0));
+    for (path, anomalies) in anomalies_of_files.iter() {
+        let longest = anomalies
+            .iter()
+            .map(ClockAnomaly::interval_ms)
+            .max()
+            .unwrap();
+        warn!(
+            "{} has {} clock anomalies, the longest interval is {:.3} seconds",
+            path.display(),
+            anomalies.len(),
+            longest as f64 / 1000.0
+        );
+        for anomaly in anomalies {
+            println!(
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                anomaly.kind.name(),
+                path.display(),
+                anomaly.line,
+                anomaly.previous,
+                anomaly.current,
+                anomaly.interval_ms()
+            );
+        }
+    }
+    info!(
+        "Checked {} sorted files of {} {} {} {}, total {} lines, {} files are not sorted",
+        total_files, exchange, market_type, msg_type, day, total_lines, error_lines
+    );
+    if options.max_gap_ms.is_some() {
+        info!(
+            "Found {} clock anomalies in {} sorted files of {} {} {} {}",
+            anomalies_of_files
+                .iter()
+                .map(|(_, a)| a.len())
+                .sum::<usize>(),
+            anomalies_of_files.len(),
+            exchange,
+            market_type,
+            msg_type,
+            day
+        );
+    }
+    StageStats {
+        ran: true,
+        success: error_lines == 0,
+        total_lines,
+        error_lines,
+        elapsed_secs: start_timstamp.elapsed().as_secs(),
+        ..Default::default()
+    }
+}
+
+/// Print the number of lines of each sorted file that the sort stage would write from
+/// `groups` of splitted files to stdout, splitted files are kept.
+///
+/// `suffix_len` is the length of the hour and the extension of splitted file names.
+#[allow(clippy::too_many_arguments)]
+fn count_files_of_day(
+    exchange: &str,
+    market_type: MarketType,
+    msg_type: MessageType,
+    day: &str,
+    groups: Vec<Vec<PathBuf>>,
+    suffix_len: usize,
+    options: &Options,
+    thread_pool: &ThreadPool,
+) -> StageStats {
+    let start_timstamp = Instant::now();
+    let (tx, rx) = mpsc::sync_channel(options.channel_capacity);
+    for input_files in groups {
+        let file_name = input_files[0].file_name().unwrap().to_str().unwrap();
+        let output_file = input_files[0].parent().unwrap().join(format!(
+            "{}.{}",
+            &file_name[0..(file_name.len() - suffix_len)],
+            options.output_extension(msg_type)
+        ));
+        let tx_clone = tx.clone();
+        let raw_sort_key = options.raw_sort_key;
+        thread_pool.execute(move || {
+            let result = count_files(&input_files, raw_sort_key);
+            tx_clone.send((output_file, result)).unwrap();
+        });
+    }
+    drop(tx);
+    let mut counts: Vec<(PathBuf, (i64, i64))> = rx.iter().collect();
+    thread_pool.join();
+    counts.sort();
+    let mut total_lines = 0;
+    let mut error_lines = 0;
+    for (output_file, (e, t)) in counts.iter() {
+        println!("{}\t{}", t, output_file.display());
+        error_lines += e;
+        total_lines += t;
+    }
+    info!(
+        "Counted {} files of {} {} {} {} with --count-only, total {} lines, {} malformed lines, nothing is sorted",
+        counts.len(),
+        exchange,
+        market_type,
+        msg_type,
+        day,
+        total_lines,
+        error_lines
+    );
+    StageStats {
+        ran: true,
+        success: error_lines == 0,
+        total_lines,
+        error_lines,
+        elapsed_secs: start_timstamp.elapsed().as_secs(),
+        ..Default::default()
+    }
+}
+
+/// Rebuild order books from a sorted parsed `l2_event` file and write their snapshots to
+/// `book_file`, returns the numbers of snapshots and ignored updates.
+fn reconstruct_l2_file(
+    sorted_file: &Path,
+    book_file: &Path,
+    interval_ms: i64,
+) -> Result<(u64, u64), String> {
+    let f_in = std::fs::File::open(sorted_file).map_err(|err| err.to_string())?;
+    let lines = std::io::BufReader::new(xz2::read::XzDecoder::new_multi_decoder(f_in))
+        .lines()
+        .map_while(Result::ok);
+    std::fs::create_dir_all(book_file.parent().unwrap()).map_err(|err| err.to_string())?;
+    let tmp_file = book_file.with_extension("xz.tmp");
+    let f_out = std::fs::File::create(tmp_file.as_path()).map_err(|err| err.to_string())?;
+    let mut writer = std::io::BufWriter::new(xz2::write::XzEncoder::new(f_out, 6));
+    let mut snapshots = 0;
+    let ignored = reconstruct_l2(lines, interval_ms, |snapshot| {
+        snapshots += 1;
+        writeln!(writer, "{}", serde_json::to_string(&snapshot).unwrap()).unwrap();
+    });
+    let ignored = match ignored {
+        Ok(ignored) => ignored,
+        Err(err) => {
+            drop(writer);
+            std::fs::remove_file(tmp_file.as_path()).unwrap();
+            return Err(err);
+        }
+    };
+    writer
+        .into_inner()
+        .map_err(|err| err.to_string())?
+        .finish()
+        .map_err(|err| err.to_string())?;
+    std::fs::rename(tmp_file.as_path(), book_file).map_err(|err| err.to_string())?;
+    Ok((snapshots, ignored))
+}
+
+/// Rebuild order books of sorted parsed `l2_event` files of one day, to `l2_book` files.
+fn reconstruct_l2_files_of_day(
+    exchange: &str,
+    market_type: MarketType,
+    day: &str,
+    output_dir_parsed: &str,
+    interval_ms: i64,
+    options: &Options,
+    thread_pool: &ThreadPool,
+) -> bool {
+    let start_timstamp = Instant::now();
+    let market = if market_type == MarketType::Unknown {
+        "*".to_string()
+    } else {
+        market_type.to_string()
+    };
+    let paths: Vec<PathBuf> = glob(&format!(
+        "{output_dir_parsed}/l2_event/{exchange}/{market}/{exchange}.*.l2_event.*.{day}*.{}",
+        options.output_extension(MessageType::L2Event)
+    ))
+    .unwrap()
+    .filter_map(Result::ok)
+    .collect();
+    let (tx, rx) = mpsc::sync_channel(options.channel_capacity);
+    let failures = Arc::new(Failures::new(options.on_error));
+    for path in paths {
+        // the same relative path with l2_event replaced by l2_book
+        let book_file = {
+            let relative = path.strip_prefix(output_dir_parsed).unwrap();
+            let mut components = relative.iter();
+            components.next(); // l2_event
+            let file_name = path.file_name().unwrap().to_str().unwrap();
+            let book_file = Path::new(output_dir_parsed)
+                .join("l2_book")
+                .join(components.as_path());
+            book_file.with_file_name(file_name.replacen(".l2_event.", ".l2_book.", 1))
+        };
+        let tx_clone = tx.clone();
+        let failures_clone = failures.clone();
+        thread_pool.execute(move || {
+            if let Some(result) = failures_clone.run(&path, || {
+                reconstruct_l2_file(&path, &book_file, interval_ms)
+            }) {
+                tx_clone.send((path, result)).unwrap();
+            }
+        });
+    }
+    drop(tx);
+    let mut total_files = 0;
+    let mut total_snapshots = 0;
+    let mut error_files = 0;
+    for (path, result) in rx {
+        total_files += 1;
+        match result {
+            Ok((snapshots, ignored)) => {
+                if snapshots == 0 {
+                    warn!(
+                        "No order book of {} was reconstructed, because it has no snapshot",
+                        path.display()
+                    );
+                } else if ignored > 0 {
+                    debug!(
+                        "{} updates before the first snapshot of {} are ignored",
+                        ignored,
+                        path.display()
+                    );
+                }
+                total_snapshots += snapshots;
+            }
+            Err(err) => {
+                error!(
+                    "Failed to reconstruct order books of {}, {}",
+                    path.display(),
+                    err
+                );
+                error_files += 1;
+            }
+        }
+    }
+    thread_pool.join();
+    if !failures.report(
+        "l2-reconstruct",
+        exchange,
+        market_type,
+        MessageType::L2Event,
+        day,
+    ) {
+        return false;
+    }
+    info!(
+        "Reconstructed {} order book snapshots from {} files of {} {} {} {}, {} files failed, time elapsed {} seconds",
+        total_snapshots,
+        total_files,
+        exchange,
+        market_type,
+        MessageType::L2Event,
+        day,
+        error_files,
+        start_timstamp.elapsed().as_secs()
+    );
+    error_files == 0
+}
+
+/// Columns of the SQLite table of `msg_type` except `data`, which is the whole message.
+#[cfg(feature = "sqlite")]
+fn sqlite_columns(msg_type: MessageType) -> Option<Vec<(&'static str, &'static str)>> {
+    let mut columns = vec![
+        ("exchange", "TEXT NOT NULL"),
+        ("market_type", "TEXT NOT NULL"),
+        ("symbol", "TEXT NOT NULL"),
+        ("pair", "TEXT NOT NULL"),
+        ("timestamp", "INTEGER NOT NULL"),
+    ];
+    match msg_type {
+        MessageType::Trade => columns.extend([
+            ("side", "TEXT"),
+            ("price", "REAL"),
+            ("quantity_base", "REAL"),
+            ("quantity_quote", "REAL"),
+            ("trade_id", "TEXT"),
+        ]),
+        // price levels are JSON arrays, queryable with SQLite JSON functions
+        MessageType::L2Event => {
+            columns.extend([("snapshot", "INTEGER"), ("asks", "TEXT"), ("bids", "TEXT")])
+        }
+        _ => return None,
+    }
+    Some(columns)
+}
+
+/// Insert parsed messages of `sorted_files` into a new `db_file` in one transaction, the
+/// table is named after `msg_type` and indexed by symbol and timestamp.
+///
+/// Returns the number of inserted rows.
+#[cfg(feature = "sqlite")]
+fn export_sqlite(
+    sorted_files: &[PathBuf],
+    msg_type: MessageType,
+    db_file: &Path,
+) -> Result<u64, String> {
+    use rusqlite::types::Value as SqlValue;
+
+    let columns = sqlite_columns(msg_type)
+        .ok_or_else(|| format!("{msg_type} can NOT be exported to SQLite"))?;
+    // rename makes the database visible atomically
+    let tmp_file = db_file.with_extension("sqlite.tmp");
+    if tmp_file.exists() {
+        std::fs::remove_file(tmp_file.as_path()).map_err(|err| err.to_string())?;
+    }
+    let mut conn = rusqlite::Connection::open(tmp_file.as_path()).map_err(|err| err.to_string())?;
+    let definitions: Vec<String> = columns
+        .iter()
+        .map(|(name, sql_type)| format!("{name} {sql_type}"))
+        .chain(std::iter::once("data TEXT NOT NULL".to_string()))
+        .collect();
+    conn.execute_batch(&format!(
+        "CREATE TABLE {msg_type} ({})",
+        definitions.join(", ")
+    ))
+    .map_err(|err| err.to_string())?;
+    let mut rows = 0;
+    let tx = conn.transaction().map_err(|err| err.to_string())?;
+    {
+        let placeholders = vec!["?"; columns.len() + 1].join(", ");
+        let mut stmt = tx
+            .prepare(&format!("INSERT INTO {msg_type} VALUES ({placeholders})"))
+            .map_err(|err| err.to_string())?;
+        for sorted_file in sorted_files {
+            let f_in = std::fs::File::open(sorted_file).map_err(|err| err.to_string())?;
+            let reader = std::io::BufReader::new(xz2::read::XzDecoder::new(f_in));
+            for line in reader.lines() {
+                let line = line.map_err(|err| format!("{}, {}", sorted_file.display(), err))?;
+                let message: Value = serde_json::from_str(&line)
+                    .map_err(|err| format!("{}, {}", sorted_file.display(), err))?;
+                let mut values: Vec<SqlValue> = columns
+                    .iter()
+                    .map(|(name, _)| match message.get(name) {
+                        Some(Value::String(s)) => SqlValue::Text(s.clone()),
+                        Some(Value::Bool(b)) => SqlValue::Integer(*b as i64),
+                        Some(Value::Number(n)) => match n.as_i64() {
+                            Some(n) => SqlValue::Integer(n),
+                            None => SqlValue::Real(n.as_f64().unwrap_or(f64::NAN)),
+                        },
+                        Some(Value::Null) | None => SqlValue::Null,
+                        Some(value) => SqlValue::Text(value.to_string()),
+                    })
+                    .collect();
+                values.push(SqlValue::Text(line));
+                stmt.execute(rusqlite::params_from_iter(values))
+                    .map_err(|err| err.to_string())?;
+                rows += 1;
+            }
+        }
+    }
+    tx.commit().map_err(|err| err.to_string())?;
+    // building the index after inserting is faster than maintaining it
+    conn.execute_batch(&format!(
+        "CREATE INDEX {msg_type}_symbol_timestamp ON {msg_type} (symbol, timestamp)"
+    ))
+    .map_err(|err| err.to_string())?;
+    conn.close().map_err(|(_, err)| err.to_string())?;
+    std::fs::rename(tmp_file.as_path(), db_file).map_err(|err| err.to_string())?;
+    Ok(rows)
+}
+
+/// Export sorted parsed files of one day to
+/// `output_dir_parsed/exchange.market_type.msg_type.day.sqlite`, returns false on failure.
+#[cfg(feature = "sqlite")]
+fn export_sqlite_of_day(
+    exchange: &str,
+    market_type: MarketType,
+    msg_type: MessageType,
+    day: &str,
+    output_dir_parsed: &str,
+    options: &Options,
+) -> bool {
+    let start_timstamp = Instant::now();
+    let market = if market_type == MarketType::Unknown {
+        "*".to_string()
+    } else {
+        market_type.to_string()
+    };
+    let mut sorted_files: Vec<PathBuf> = glob(&format!(
+        "{output_dir_parsed}/{msg_type}/{exchange}/{market}/{exchange}.*.{msg_type}.*.{day}*.{}",
+        options.output_extension(msg_type)
+    ))
+    .unwrap()
+    .filter_map(Result::ok)
+    .collect();
+    sorted_files.sort();
+    let db_file = Path::new(output_dir_parsed)
+        .join(format!("{exchange}.{market_type}.{msg_type}.{day}.sqlite"));
+    match export_sqlite(&sorted_files, msg_type, db_file.as_path()) {
+        Ok(rows) => {
+            info!(
+                "Exported {} messages of {} files to {}, time elapsed {} seconds",
+                rows,
+                sorted_files.len(),
+                db_file.display(),
+                start_timstamp.elapsed().as_secs()
+            );
+            true
+        }
+        Err(err) => {
+            error!("Failed to export {}, {}", db_file.display(), err);
+            false
+        }
+    }
+}
+
+/// Process files of one day of the same exchange, msg_type, market_type.
+///
+/// Each `(exchange, msg_type, market_type, day)` will launch a process.
+///
+/// A zero-byte `_SUCCESS.exchange.market_type.msg_type.day` file is written to
+/// both output directories after both stages succeed, `--stage sort` assumes the
+/// split stage has succeeded before. With `--only-new` a day having all its markers
+/// is not processed again.
+#[allow(clippy::too_many_arguments)]
+fn process_files_of_day(
+    exchange: &str,
+    msg_type: MessageType,
+    market_type: MarketType,
+    day: &str,
+    input_dir: &str,
+    output_dir_raw: &str,
+    output_dir_parsed: &str,
+    options: &Options,
+    thread_pool: &ThreadPool,
+    memory_budget: &Arc<MemoryBudget>,
+    output_budget: &Arc<OutputBudget>,
+) -> DayResult {
+    let output_dirs = options.output_dirs(output_dir_raw, output_dir_parsed);
+    if options.stats_only_sort {
+        // markers are left as they are
+        return DayResult {
+            split: StageStats::skipped(),
+            sort: check_sorted_files_of_day(
+                exchange,
+                msg_type,
+                market_type,
+                day,
+                &output_dirs,
+                options,
+                thread_pool,
+            ),
+        };
+    }
+    if options.output == OutputKind::Parsed
+        && ((msg_type == MessageType::L2Event && is_blocked_market(market_type))
+            || options.skips_parse(exchange, market_type, msg_type))
+    {
+        warn!(
+            "{} {} {} has no parsed output, nothing to do with --output parsed",
+            exchange, market_type, msg_type
+        );
+        return DayResult {
+            split: StageStats::skipped(),
+            sort: StageStats::skipped(),
+        };
+    }
+    let success_files: Vec<PathBuf> = output_dirs
+        .iter()
+        .map(|output_dir| {
+            Path::new(output_dir).join(format!(
+                "_SUCCESS.{exchange}.{market_type}.{msg_type}.{day}{}",
+                shard_suffix(options.shard)
+            ))
+        })
+        .collect();
+    if options.only_new
+        && success_files
+            .iter()
+            .all(|success_file| success_file.exists())
+    {
+        info!(
+            "{} {} {} {} has been processed before, skipped with --only-new",
+            exchange, market_type, msg_type, day
+        );
+        return DayResult {
+            split: StageStats::skipped(),
+            sort: StageStats::skipped(),
+        };
+    }
+    // remove stale markers
+    for success_file in success_files.iter() {
+        if success_file.exists() {
+            std::fs::remove_file(success_file).unwrap();
+        }
+    }
+    if options.stage != Stage::Sort {
+        // files sorted by an interrupted run are sorted again from new splitted files
+        for output_dir in output_dirs.iter() {
+            let partial_file = partial_manifest_file(
+                output_dir,
+                exchange,
+                market_type,
+                msg_type,
+                day,
+                options.shard,
+            );
+            if partial_file.exists() {
+                std::fs::remove_file(partial_file).unwrap();
+            }
+        }
+    }
+
+    let split = if options.stage == Stage::Sort {
+        StageStats::skipped()
+    } else {
+        split_files_of_day(
+            exchange,
+            msg_type,
+            market_type,
+            day,
+            input_dir,
+            output_dir_raw,
+            output_dir_parsed,
+            options,
+            thread_pool,
+            memory_budget,
+            output_budget,
+        )
+    };
+    let sort = if options.stage == Stage::Split || !split.success {
+        StageStats::skipped()
+    } else {
+        sort_files_of_day(
+            exchange,
+            msg_type,
+            market_type,
+            day,
+            output_dir_raw,
+            output_dir_parsed,
+            options,
+            thread_pool,
+            memory_budget,
+            output_budget,
+        )
+    };
+    let mut result = DayResult { split, sort };
+    if let Some(interval_ms) = options.l2_reconstruct {
+        if result.sort.ran
+            && result.sort.success
+            && msg_type == MessageType::L2Event
+            && options.output.parsed()
+        {
+            result.sort.success = reconstruct_l2_files_of_day(
+                exchange,
+                market_type,
+                day,
+                output_dir_parsed,
+                interval_ms,
+                options,
+                thread_pool,
+            );
+        }
+    }
+    #[cfg(feature = "sqlite")]
+    if options.output_format == OutputFormat::Sqlite && result.sort.ran && result.sort.success {
+        result.sort.success = export_sqlite_of_day(
+            exchange,
+            market_type,
+            msg_type,
+            day,
+            output_dir_parsed,
+            options,
+        );
+    }
+    // the day is completed only if the sort stage has run
+    if result.success() && options.stage != Stage::Split && !options.count_only {
+        for success_file in success_files.iter() {
+            std::fs::File::create(success_file).unwrap();
+        }
+        // splitted files have been sorted, a rerun starts from scratch
+        if let Some(dedup_dir) = options.dedup_dir.as_ref() {
+            for kind in ["raw", "parsed"] {
+                let dedup_file = dedup_file(dedup_dir, exchange, market_type, msg_type, day, kind);
+                if dedup_file.exists() {
+                    std::fs::remove_file(dedup_file).unwrap();
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Write the current Unix timestamp to `heartbeat_file` every `HEARTBEAT_INTERVAL_SECS`
+/// seconds in a background thread, which lives as long as the process. A stale mtime
+/// means the process is stuck or gone rather than slow.
+fn start_heartbeat(heartbeat_file: PathBuf) {
+    std::thread::spawn(move || {
+        let mut failed = false;
+        loop {
+            let beat = format!("{}\n", Utc::now().timestamp());
+            match std::fs::write(heartbeat_file.as_path(), beat) {
+                Ok(()) => failed = false,
+                Err(err) if !failed => {
+                    // logged once until it succeeds again
+                    warn!(
+                        "Failed to touch heartbeat file {}, {}",
+                        heartbeat_file.display(),
+                        err
+                    );
+                    failed = true;
+                }
+                Err(_) => {}
+            }
+            std::thread::sleep(Duration::from_secs(HEARTBEAT_INTERVAL_SECS));
+        }
+    });
+}
+
+/// Log like `env_logger` does by default, with `run_id` after the target, so that lines of
+/// concurrent runs in a shared log sink can be told apart.
+fn init_logger(run_id: &str) {
+    let run_id = run_id.to_string();
+    env_logger::Builder::from_default_env()
+        .format(move |buf, record| {
+            writeln!(
+                buf,
+                "[{} {:<5} {} {}] {}",
+                buf.timestamp(),
+                buf.default_styled_level(record.level()),
+                record.target(),
+                run_id,
+                record.args()
+            )
+        })
+        .init();
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    let reparse = args.get(1).map(String::as_str) == Some("reparse");
+    if reparse {
+        if args.len() < 8 {
+            eprintln!("{USAGE}");
+            std::process::exit(EXIT_USAGE);
+        }
+        // sorted raw files in output_dir_raw are the input files
+        args.remove(1);
+        let output_dir_raw = args[5].clone();
+        args.insert(5, output_dir_raw);
+    }
+    if args.get(1).map(String::as_str) == Some("repair-pairs") {
+        let dry_run = match args[2..] {
+            [_] => false,
+            [_, ref flag] if flag == "--dry-run" => true,
+            _ => {
+                eprintln!("{USAGE}");
+                std::process::exit(EXIT_USAGE);
+            }
+        };
+        init_logger(&format!("{:08x}", rand::thread_rng().gen::<u32>()));
+        let output_dir_parsed = args[2].as_str();
+        if !Path::new(output_dir_parsed).is_dir() {
+            eprintln!("{output_dir_parsed} does NOT exist");
+            std::process::exit(EXIT_INPUT_NOT_FOUND);
+        }
+        let renamed = repair_pairs(output_dir_parsed, dry_run);
+        info!(
+            "{} {} files whose pairs changed in {}",
+            if dry_run { "Found" } else { "Renamed" },
+            renamed,
+            output_dir_parsed
+        );
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("recompress") {
+        if args.len() < 3 {
+            eprintln!("{USAGE}");
+            std::process::exit(EXIT_USAGE);
+        }
+        init_logger(&format!("{:08x}", rand::thread_rng().gen::<u32>()));
+        for input in args[2..].iter() {
+            let path = Path::new(input);
+            let is_xz_file = path.is_file() && input.ends_with(".json.xz");
+            if !path.is_dir() && !is_xz_file {
+                eprintln!("{input} is neither a directory nor a .json.xz file");
+                std::process::exit(EXIT_INPUT_NOT_FOUND);
+            }
+        }
+        if recompress(&args[2..]) > 0 {
+            std::process::exit(EXIT_PROCESSING_ERROR);
+        }
+        return;
+    }
+    if args.len() < 8 {
+        eprintln!("{USAGE}");
+        std::process::exit(EXIT_USAGE);
+    }
+    let mut options = match Options::parse(&args[8..]) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{err}");
+            eprintln!("{USAGE}");
+            std::process::exit(EXIT_USAGE);
+        }
     };
+    let run_id = options
+        .run_id
+        .clone()
+        .unwrap_or_else(|| format!("{:08x}", rand::thread_rng().gen::<u32>()));
+    init_logger(&run_id);
+    if getrlimit(Resource::NOFILE).unwrap().0 < MAX_OPEN_FILES {
+        if let Err(err) = setrlimit(Resource::NOFILE, MAX_OPEN_FILES, MAX_OPEN_FILES) {
+            error!("setrlimit() failed, {}", err);
+            error!("getrlimit(): {:?}", getrlimit(Resource::NOFILE).unwrap());
+            std::process::exit(EXIT_PROCESSING_ERROR);
+        }
+    }
 
-    let mut total_lines = 0;
-    let mut error_lines = 0;
-    for input_file in hourly_files.iter() {
-        let (e, t) = sort_file(input_file, writer.as_mut());
-        total_lines += t;
-        error_lines += e;
+    let exchange: &'static str = Box::leak(args[1].clone().into_boxed_str());
+    if exchange == "okex" || exchange == "mxc" {
+        eprintln!("exchange should NOT be okex nor mxc");
+        std::process::exit(EXIT_USAGE);
     }
-    drop(writer);
-    if error_lines == 0 {
-        if use_xz {
-            {
-                // Wait for the semaphore which allows only two xz processes
-                let mut rng = rand::thread_rng();
-                while semaphore.load(Ordering::SeqCst) == 0 {
-                    let millis = rng.gen_range(3000_u64..10000_u64);
-                    debug!("Waiting for semaphore");
-                    std::thread::sleep(Duration::from_millis(millis));
-                }
-                semaphore.fetch_sub(1_usize, Ordering::SeqCst);
+
+    let msg_types = match parse_selector::<MessageType>(&args[2]) {
+        Ok(msg_types) => msg_types,
+        Err(_) => {
+            eprintln!("Unknown msg type: {}", &args[2]);
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+    let market_types = match parse_selector::<MarketType>(&args[3]) {
+        Ok(market_types) => market_types,
+        Err(_) => {
+            eprintln!("Unknown market type: {}", &args[3]);
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+    // a single combo, otherwise combos present in input files are discovered per day
+    let single_combo = match (&msg_types, &market_types) {
+        (Some(msg_types), Some(market_types))
+            if msg_types.len() == 1 && market_types.len() == 1 =>
+        {
+            Some((msg_types[0], market_types[0]))
+        }
+        _ => None,
+    };
+
+    let day: &'static str = Box::leak(args[4].clone().into_boxed_str());
+    let re = Regex::new(r"^\d{4}-\d{2}-\d{2}$").unwrap();
+    if !re.is_match(day) {
+        eprintln!("{day} is invalid, day should be yyyy-MM-dd");
+        std::process::exit(EXIT_USAGE);
+    }
+
+    let input_dir: &'static str = Box::leak(args[5].clone().into_boxed_str());
+    if reparse {
+        let (msg_type, market_type) = match single_combo {
+            Some(combo) => combo,
+            None => {
+                eprintln!("reparse requires one msg_type and one market_type");
+                std::process::exit(EXIT_USAGE);
             }
-            let json_file = {
-                let output_dir = output_file.as_ref().parent().unwrap().to_path_buf();
-                let filename = output_file.as_ref().file_name().unwrap().to_str().unwrap();
-                output_dir.join(&filename[..filename.len() - 3])
-            };
-            match std::process::Command::new("xz")
-                .args(["-9", "-f", "-T0", json_file.as_path().to_str().unwrap()])
-                .output()
-            {
-                Ok(output) => {
-                    if !output.status.success() {
-                        panic!("{}", String::from_utf8_lossy(&output.stderr));
-                    }
-                }
-                Err(err) => panic!("{}", err),
+        };
+        if options.combined || options.files_from.is_some() {
+            eprintln!("reparse can NOT be used with --combined nor --files-from");
+            std::process::exit(EXIT_USAGE);
+        }
+        if !Path::new(input_dir).is_dir() {
+            eprintln!("{input_dir} does NOT exist");
+            std::process::exit(EXIT_INPUT_NOT_FOUND);
+        }
+        // raw files are deduplicated, sorted and bounded by day already
+        let extension = options.output_extension(msg_type).to_string();
+        let pattern = format!(
+            "{input_dir}/{msg_type}/{exchange}/{market_type}/{exchange}.{market_type}.{msg_type}.*.{extension}"
+        );
+        let input_files: Vec<PathBuf> = glob(&pattern).unwrap().filter_map(Result::ok).collect();
+        info!(
+            "Reparse {} sorted raw files of {}",
+            input_files.len(),
+            pattern
+        );
+        options.input_files = Some(input_files);
+        options.input_pattern = format!("{{exchange}}.{{market}}.{{msg}}.*.{{day}}*.{extension}");
+        options.output = OutputKind::Parsed;
+        options.spillover_hours = 0;
+        options.reparse = true;
+    } else if let Some(files_from) = &options.files_from {
+        let list = if files_from == "-" {
+            let mut list = String::new();
+            std::io::stdin().read_to_string(&mut list).map(|_| list)
+        } else {
+            std::fs::read_to_string(files_from)
+        };
+        let list = match list {
+            Ok(list) => list,
+            Err(err) => {
+                eprintln!("Failed to read the list of input files from {files_from}, {err}");
+                std::process::exit(EXIT_INPUT_NOT_FOUND);
             }
-            semaphore.fetch_add(1_usize, Ordering::SeqCst);
+        };
+        let input_files: Vec<PathBuf> = list
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(PathBuf::from)
+            .filter(|path| {
+                let exists = path.is_file();
+                if !exists {
+                    warn!("{} does NOT exist, skipped", path.display());
+                }
+                exists
+            })
+            .collect();
+        info!("Read {} input files from {}", input_files.len(), files_from);
+        options.input_files = Some(input_files);
+    } else if !Path::new(input_dir).is_dir() {
+        eprintln!("{input_dir} does NOT exist");
+        std::process::exit(EXIT_INPUT_NOT_FOUND);
+    }
+    if let Some(n) = options.preview {
+        // input files of the first combo, nothing is written
+        let combo = single_combo.or_else(|| {
+            combos_of_day(
+                exchange,
+                msg_types.as_deref(),
+                market_types.as_deref(),
+                day,
+                input_dir,
+                &options,
+            )
+            .first()
+            .copied()
+        });
+        let input_files = combo
+            .map(|(msg_type, market_type)| {
+                let glob_pattern = input_glob_pattern(
+                    input_dir,
+                    &options.input_pattern,
+                    exchange,
+                    market_type,
+                    msg_type,
+                    day,
+                );
+                let mut input_files =
+                    list_input_files(&glob_pattern, options.input_files.as_deref());
+                input_files.sort();
+                input_files
+            })
+            .unwrap_or_default();
+        if input_files.is_empty() {
+            eprintln!("There are no input files of {exchange} {day}");
+            std::process::exit(EXIT_INPUT_NOT_FOUND);
+        }
+        let printed = preview(&input_files, n, &options);
+        info!("Printed {} parsed messages", printed);
+        return;
+    }
+    let output_dir_raw: &'static str = Box::leak(args[6].clone().into_boxed_str());
+    let output_dir_parsed: &'static str = Box::leak(args[7].clone().into_boxed_str());
+    std::fs::create_dir_all(Path::new(output_dir_raw)).unwrap();
+    std::fs::create_dir_all(Path::new(output_dir_parsed)).unwrap();
+    for output_dir in options.output_dirs(output_dir_raw, output_dir_parsed) {
+        if let Some(available) = available_space(Path::new(output_dir)) {
+            info!(
+                "{} bytes are available on the disk of {}",
+                available, output_dir
+            );
         }
+    }
+    if let Some(heartbeat_file) = options.heartbeat_file.clone() {
+        start_heartbeat(heartbeat_file);
+    }
+
+    let days: Vec<String> = if let Some((from, to)) = options.day_range {
+        from.iter_days()
+            .take_while(|d| *d <= to)
+            .map(|d| d.format("%Y-%m-%d").to_string())
+            .collect()
     } else {
-        error!(
-            "Found {} malformed lines out of total {} total lines for {}",
-            error_lines,
-            total_lines,
-            output_file.as_ref().display()
+        vec![day.to_string()]
+    };
+
+    // all days share the same thread pool
+    let thread_pool = ThreadPool::new(if options.deterministic {
+        1
+    } else {
+        num_cpus::get()
+    });
+    if options.pin_threads {
+        pin_threads(&thread_pool);
+    }
+    // all days share the same memory budget too
+    let memory_budget = Arc::new(MemoryBudget::new(options.memory_limit));
+    let output_budget = Arc::new(OutputBudget::new(options.max_output_bytes));
+    let mut results: Vec<(String, (MessageType, MarketType), DayResult)> = Vec::new();
+    'days: for day in days {
+        let combos = if let Some(combo) = single_combo {
+            vec![combo]
+        } else {
+            let combos = combos_of_day(
+                exchange,
+                msg_types.as_deref(),
+                market_types.as_deref(),
+                &day,
+                input_dir,
+                &options,
+            );
+            if combos.is_empty() {
+                warn!("There are no combos of {} to process on {}", exchange, day);
+            }
+            combos
+        };
+        for (msg_type, market_type) in combos {
+            let result = process_files_of_day(
+                exchange,
+                msg_type,
+                market_type,
+                &day,
+                input_dir,
+                output_dir_raw,
+                output_dir_parsed,
+                &options,
+                &thread_pool,
+                &memory_budget,
+                &output_budget,
+            );
+            let success = result.success();
+            let stopped = result.timed_out() || result.output_full();
+            results.push((day.clone(), (msg_type, market_type), result));
+            if stopped || (!success && !options.keep_going) {
+                break 'days;
+            }
+        }
+    }
+    if options.day_range.is_some() || single_combo.is_none() {
+        println!(
+            "{:<12}{:<16}{:<10}{:<8}{:<8}{:>14}{:>14}{:>14}{:>10}",
+            "day",
+            "market_type",
+            "msg_type",
+            "split",
+            "sort",
+            "split lines",
+            "split errors",
+            "sort lines",
+            "seconds"
         );
-        // cleanup
-        if use_xz {
-            let json_file = {
-                let output_dir = output_file.as_ref().parent().unwrap().to_path_buf();
-                let filename = output_file.as_ref().file_name().unwrap().to_str().unwrap();
-                output_dir.join(&filename[..filename.len() - 3])
+        for (day, (msg_type, market_type), result) in results.iter() {
+            let outcome = |stats: &StageStats| match (stats.ran, stats.success) {
+                (false, _) => "-",
+                (true, true) => "ok",
+                (true, false) if stats.timed_out => "timeout",
+                (true, false) if stats.output_full => "full",
+                (true, false) => "failed",
             };
-            std::fs::remove_file(json_file.as_path()).unwrap();
-        } else {
-            std::fs::remove_file(output_file.as_ref()).unwrap();
+            println!(
+                "{:<12}{:<16}{:<10}{:<8}{:<8}{:>14}{:>14}{:>14}{:>10}",
+                day,
+                market_type.to_string(),
+                msg_type.to_string(),
+                outcome(&result.split),
+                outcome(&result.sort),
+                result.split.total_lines,
+                result.split.error_lines,
+                result.sort.total_lines,
+                result.split.elapsed_secs + result.sort.elapsed_secs
+            );
         }
     }
-    (error_lines, total_lines)
+    if results.len() > 1 {
+        let stages: [(&str, fn(&DayResult) -> StageStats); 2] = [
+            ("split", |result| result.split),
+            ("sort", |result| result.sort),
+        ];
+        for (stage, stats_of) in stages {
+            let (uncompressed_bytes, compressed_bytes) =
+                results.iter().fold((0, 0), |(u, c), (_, _, result)| {
+                    let stats = stats_of(result);
+                    (u + stats.uncompressed_bytes, c + stats.compressed_bytes)
+                });
+            if compressed_bytes > 0 {
+                log_compression(
+                    &format!("{stage} of {} days and combos", results.len()),
+                    uncompressed_bytes,
+                    compressed_bytes,
+                );
+            }
+        }
+    }
+    if results.iter().any(|(_, _, result)| result.output_full()) {
+        std::process::exit(EXIT_OUTPUT_FULL);
+    }
+    if results.iter().any(|(_, _, result)| result.timed_out()) {
+        std::process::exit(EXIT_TIMEOUT);
+    }
+    let failed = results
+        .iter()
+        .filter(|(_, _, result)| !result.success())
+        .count();
+    if failed == results.len() && failed > 0 {
+        std::process::exit(EXIT_PROCESSING_ERROR);
+    } else if failed > 0 {
+        std::process::exit(EXIT_PARTIAL_SUCCESS);
+    }
 }
 
-/// Process files of one day of the same exchange, msg_type, market_type.
+/// Pin each worker thread of `thread_pool` to a distinct CPU core.
 ///
-/// Each `(exchange, msg_type, market_type, day)` will launch a process.
-fn process_files_of_day(
-    exchange: &str,
-    msg_type: MessageType,
-    market_type: MarketType,
-    day: &str,
-    input_dir: &str,
-    output_dir_raw: &str,
-    output_dir_parsed: &str,
-) -> bool {
-    let num_threads = num_cpus::get();
-    let thread_pool = ThreadPool::new(num_threads);
+/// ThreadPool has no hook to run on worker threads, so one job per worker is queued, and a
+/// barrier makes sure that no worker takes two of them.
+fn pin_threads(thread_pool: &ThreadPool) {
+    let core_ids = match core_affinity::get_core_ids() {
+        Some(core_ids) if !core_ids.is_empty() => core_ids,
+        _ => {
+            warn!("Failed to get CPU cores, threads are not pinned");
+            return;
+        }
+    };
+    save_process_affinity();
+    let workers = thread_pool.max_count();
+    let barrier = Arc::new(std::sync::Barrier::new(workers));
+    let pinned = Arc::new(AtomicUsize::new(0));
+    for index in 0..workers {
+        let core_id = core_ids[index % core_ids.len()];
+        let barrier_clone = barrier.clone();
+        let pinned_clone = pinned.clone();
+        thread_pool.execute(move || {
+            if core_affinity::set_for_current(core_id) {
+                pinned_clone.fetch_add(1, Ordering::SeqCst);
+            }
+            barrier_clone.wait();
+        });
+    }
+    thread_pool.join();
+    info!(
+        "Pinned {} of {} worker threads to {} CPU cores",
+        pinned.load(Ordering::SeqCst),
+        workers,
+        core_ids.len()
+    );
+}
 
-    // split
-    {
-        let glob_pattern = if market_type == MarketType::Unknown {
-            // MarketType::Unknown means all markets
-            format!(
-                "{input_dir}/*/{msg_type}/{exchange}/*/{exchange}.*.{msg_type}.{day}-??-??.json.gz"
+/// CPU cores of the process before `--pin-threads` pinned its worker threads.
+#[cfg(target_os = "linux")]
+static PROCESS_AFFINITY: std::sync::OnceLock<libc::cpu_set_t> = std::sync::OnceLock::new();
+
+#[cfg(target_os = "linux")]
+fn save_process_affinity() {
+    // SAFETY: cpu_set_t is a plain bit mask, and the size passed is its own size
+    unsafe {
+        let mut cpu_set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut cpu_set) == 0 {
+            let _ = PROCESS_AFFINITY.set(cpu_set);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn save_process_affinity() {}
+
+/// Run a child process, e.g., `xz -T0`, on the CPU cores of the process, instead of on the one
+/// core of the pinned worker thread which spawns it.
+#[cfg(target_os = "linux")]
+fn unpinned(command: &mut std::process::Command) -> &mut std::process::Command {
+    use std::os::unix::process::CommandExt;
+    if let Some(cpu_set) = PROCESS_AFFINITY.get().copied() {
+        // SAFETY: sched_setaffinity() is a system call which is safe between fork and exec
+        unsafe {
+            command.pre_exec(move || {
+                if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &cpu_set) == 0
+                {
+                    Ok(())
+                } else {
+                    Err(std::io::Error::last_os_error())
+                }
+            });
+        }
+    }
+    command
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unpinned(command: &mut std::process::Command) -> &mut std::process::Command {
+    command
+}
+
+#[cfg(test)]
+mod test {
+    use crypto_market_type::MarketType;
+    use crypto_msg_type::MessageType;
+
+    #[test]
+    fn test_parse_selector() {
+        assert_eq!(
+            Ok(Some(vec![MessageType::Trade, MessageType::L2Event])),
+            super::parse_selector::<MessageType>("trade, l2_event")
+        );
+        assert_eq!(Ok(None), super::parse_selector::<MarketType>("*"));
+        assert!(super::parse_selector::<MarketType>("spot,foo").is_err());
+    }
+
+    #[test]
+    fn test_parse_combo() {
+        assert_eq!(
+            Ok((
+                "binance".to_string(),
+                MarketType::LinearSwap,
+                MessageType::L2Event
+            )),
+            super::parse_combo("binance:linear_swap:l2_event")
+        );
+        assert!(super::parse_combo("binance:linear_swap").is_err());
+        assert!(super::parse_combo(":spot:trade").is_err());
+        assert!(super::parse_combo("binance:spot:foo").is_err());
+    }
+
+    #[test]
+    fn test_parse_output_extension() {
+        assert_eq!(
+            Ok((MessageType::Trade, "trades.jsonl.xz".to_string())),
+            super::parse_output_extension("trade=trades.jsonl.xz")
+        );
+        assert!(super::parse_output_extension("trade=trades.jsonl").is_err());
+        assert!(super::parse_output_extension("trade=.json.xz").is_err());
+        assert!(super::parse_output_extension("trades.json.xz").is_err());
+    }
+
+    #[test]
+    fn test_semaphore() {
+        use std::sync::Arc;
+
+        let semaphore = Arc::new(super::Semaphore::new(1));
+        let permit = semaphore.acquire();
+        let semaphore_clone = semaphore.clone();
+        let waiter = std::thread::spawn(move || {
+            let _permit = semaphore_clone.acquire();
+        });
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert!(!waiter.is_finished());
+        drop(permit);
+        waiter.join().unwrap();
+        assert_eq!(1, *semaphore.permits.lock().unwrap());
+    }
+
+    #[test]
+    fn test_repair_pairs() {
+        let dir = std::env::temp_dir().join(format!("repair_pairs_{}", std::process::id()));
+        let market_dir = dir.join("trade/binance/spot");
+        std::fs::create_dir_all(market_dir.as_path()).unwrap();
+        // BTCUSDT is BTC/USDT, not BTC/USD
+        let stale = "binance.spot.trade.BTC.USD.BTCUSDT.2021-09-02.json.xz";
+        let good = "binance.spot.trade.ETH.USDT.ETHUSDT.2021-09-02.json.xz";
+        for file_name in [stale, good] {
+            std::fs::write(market_dir.join(file_name), "").unwrap();
+        }
+        std::fs::write(market_dir.join(format!("{stale}.count")), "1\n").unwrap();
+        let manifest_file = dir.join("manifest.binance.spot.trade.2021-09-02.json");
+        std::fs::write(
+            manifest_file.as_path(),
+            format!(r#"{{"files":[{{"path":"trade/binance/spot/{stale}","lines":1}}]}}"#),
+        )
+        .unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        assert_eq!(1, super::repair_pairs(dir_str, true));
+        assert!(market_dir.join(stale).exists());
+
+        assert_eq!(1, super::repair_pairs(dir_str, false));
+        let repaired = "binance.spot.trade.BTC.USDT.BTCUSDT.2021-09-02.json.xz";
+        assert!(!market_dir.join(stale).exists());
+        assert!(market_dir.join(repaired).exists());
+        assert!(market_dir.join(format!("{repaired}.count")).exists());
+        assert!(market_dir.join(good).exists());
+        let manifest = std::fs::read_to_string(manifest_file).unwrap();
+        assert!(manifest.contains(repaired) && !manifest.contains(stale));
+        assert_eq!(0, super::repair_pairs(dir_str, false));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rolling_writer() {
+        use std::io::{Read, Write};
+
+        let dir = std::env::temp_dir().join(format!("rolling_writer_{}", std::process::id()));
+        std::fs::create_dir_all(dir.as_path()).unwrap();
+        let output_file = dir.join("a.json.xz");
+        let mut writer = super::RollingWriter::new(output_file.as_path(), 1, None);
+        for line in ["{\"a\":1}\n", "{\"a\":2}\n{\"a\":3}\n"] {
+            writer.write_all(line.as_bytes()).unwrap();
+            writer.flush().unwrap();
+        }
+        let (_, parts) = writer.finish().unwrap();
+        // the flush exceeds the limit, the part is finished at the next line end
+        assert_eq!(vec![2, 1], parts.iter().map(|p| p.1).collect::<Vec<i64>>());
+        let mut content = String::new();
+        for (part, _) in parts.iter() {
+            xz2::read::XzDecoder::new(std::fs::File::open(part).unwrap())
+                .read_to_string(&mut content)
+                .unwrap();
+        }
+        assert_eq!("{\"a\":1}\n{\"a\":2}\n{\"a\":3}\n", content);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sort_file_multi_member() {
+        use std::io::Write;
+        let input_file = std::env::temp_dir().join(format!(
+            "binance.spot.trade.BTCUSDT.2021-09-02-00.{}.json.gz",
+            std::process::id()
+        ));
+        // a splitted file re-opened in append mode, one gzip member per writer
+        let mut f_out = std::fs::File::create(&input_file).unwrap();
+        for lines in [
+            ["{\"timestamp\":3}", "{\"timestamp\":1}"],
+            ["{\"timestamp\":2}", "{\"timestamp\":4}"],
+        ] {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            for line in lines {
+                writeln!(encoder, "{line}").unwrap();
+            }
+            f_out.write_all(&encoder.finish().unwrap()).unwrap();
+        }
+        drop(f_out);
+        let mut sorted: Vec<u8> = Vec::new();
+        let (error_lines, total_lines, written) = super::sort_file(
+            &input_file,
+            &mut sorted,
+            &super::Profile::new(false),
+            super::RawSortKey::ReceivedAt,
+            super::SortOrder::Asc,
+        );
+        assert_eq!((0, 4, 64), (error_lines, total_lines, written));
+        assert_eq!(
+            "{\"timestamp\":1}\n{\"timestamp\":2}\n{\"timestamp\":3}\n{\"timestamp\":4}\n",
+            String::from_utf8(sorted).unwrap()
+        );
+        // removed by sort_files_of_day() after the sorted file is recorded
+        assert!(input_file.exists());
+        std::fs::remove_file(&input_file).unwrap();
+    }
+
+    #[test]
+    fn test_resume_sort_after_kill() {
+        use std::io::Write;
+        use std::sync::Arc;
+
+        let root = std::env::temp_dir().join(format!(
+            "test_resume_sort_after_kill_{}",
+            std::process::id()
+        ));
+        let output_dir_raw = root.join("raw");
+        let output_dir_parsed = root.join("parsed");
+        let splitted_dir = output_dir_raw.join("trade/binance/spot");
+        std::fs::create_dir_all(&splitted_dir).unwrap();
+        let splitted_file = |symbol: &str| {
+            let path =
+                splitted_dir.join(format!("binance.spot.trade.{symbol}.2021-09-02-00.json.gz"));
+            let mut encoder = flate2::write::GzEncoder::new(
+                std::fs::File::create(&path).unwrap(),
+                flate2::Compression::default(),
+            );
+            for received_at in [1630540801000_i64, 1630540800000] {
+                writeln!(encoder, r#"{{"exchange":"binance","market_type":"spot","msg_type":"trade","received_at":{received_at},"json":"{{}}"}}"#).unwrap();
+            }
+            encoder.finish().unwrap();
+            path
+        };
+        let btc_file = splitted_file("BTCUSDT");
+        let eth_file = splitted_file("ETHUSDT");
+
+        let options = super::Options {
+            output: super::OutputKind::Raw,
+            ..Default::default()
+        };
+        // killed after BTCUSDT is sorted and renamed, before it is in the partial manifest
+        let sorted_file = splitted_dir.join("binance.spot.trade.BTCUSDT.2021-09-02.json.xz");
+        let (error_lines, total_lines, _, parts, _) = super::sort_files(
+            vec![btc_file.clone()],
+            sorted_file.clone(),
+            24,
+            false,
+            Arc::new(super::Semaphore::new(1)),
+            Arc::new(super::Profile::new(false)),
+            None,
+            options.raw_sort_key,
+            options.sort_order,
+            None,
+            options.output_extension(MessageType::Trade),
+            options.output_json_style,
+        );
+        assert_eq!((0, 2), (error_lines, total_lines));
+        assert_eq!(vec![(sorted_file, 2)], parts);
+        assert!(btc_file.exists());
+
+        // the resumed --stage sort run sorts BTCUSDT again
+        let stats = super::sort_files_of_day(
+            "binance",
+            MessageType::Trade,
+            MarketType::Spot,
+            "2021-09-02",
+            output_dir_raw.to_str().unwrap(),
+            output_dir_parsed.to_str().unwrap(),
+            &options,
+            &threadpool::ThreadPool::new(2),
+            &Arc::new(super::MemoryBudget::new(None)),
+            &Arc::new(super::OutputBudget::new(None)),
+        );
+        assert!(stats.success);
+        assert_eq!(4, stats.total_lines);
+        assert!(!btc_file.exists() && !eth_file.exists());
+        let manifest: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(
+                output_dir_raw.join("manifest.binance.spot.trade.2021-09-02.json"),
             )
-        } else {
+            .unwrap(),
+        )
+        .unwrap();
+        let symbols: Vec<&str> = manifest["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| file["symbol"].as_str().unwrap())
+            .collect();
+        assert_eq!(vec!["BTCUSDT", "ETHUSDT"], symbols);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_parse_symbol_list() {
+        let symbols: Vec<String> =
+            super::parse_symbol_list("# majors\nBTCUSDT\n\n  ETHUSDT \n#SOLUSDT\n").collect();
+        assert_eq!(vec!["BTCUSDT", "ETHUSDT"], symbols);
+    }
+
+    #[test]
+    fn test_check_sorted_clock_anomalies() {
+        use super::{ClockAnomaly, ClockAnomalyKind, RawSortKey, SortOrder};
+        use std::io::Write;
+        let sorted_file = std::env::temp_dir().join(format!(
+            "test_check_sorted_clock_anomalies_{}.json.xz",
+            std::process::id()
+        ));
+        let line = |received_at: i64, time: i64| {
             format!(
-                "{input_dir}/*/{msg_type}/{exchange}/{market_type}/{exchange}.{market_type}.{msg_type}.{day}-??-??.json.gz"
+                r#"{{"exchange":"binance","market_type":"spot","msg_type":"trade","received_at":{received_at},"json":"{{\"stream\":\"btcusdt@trade\",\"data\":{{\"e\":\"trade\",\"E\":{time},\"s\":\"BTCUSDT\",\"T\":{time}}}}}"}}"#
             )
         };
-        let mut paths: Vec<PathBuf> = glob(&glob_pattern)
-            .unwrap()
-            .filter_map(Result::ok)
-            .collect();
         {
-            // Add addtional files of tomorrow, because there might be some messages belong to today
-            let next_day_first_hour = {
-                let day_timestamp =
-                    DateTime::parse_from_rfc3339(format!("{day}T00:00:00Z").as_str())
-                        .unwrap()
-                        .timestamp_millis()
-                        / 1000;
-                let next_day =
-                    NaiveDateTime::from_timestamp_opt(day_timestamp + 24 * 3600, 0).unwrap();
-                let next_day: DateTime<Utc> = DateTime::from_utc(next_day, Utc);
-                next_day.format("%Y-%m-%d-%H").to_string()
-            };
-            let glob_pattern = if market_type == MarketType::Unknown {
-                // MarketType::Unknown means all markets
-                format!(
-                    "{input_dir}/*/{msg_type}/{exchange}/*/{exchange}.*.{msg_type}.{next_day_first_hour}-??.json.gz"
-                )
-            } else {
-                format!(
-                    "{input_dir}/*/{msg_type}/{exchange}/{market_type}/{exchange}.{market_type}.{msg_type}.{next_day_first_hour}-??.json.gz"
-                )
-            };
-            let mut paths_of_next_day: Vec<PathBuf> = glob(&glob_pattern)
-                .unwrap()
-                .filter_map(Result::ok)
-                .collect();
-            paths.append(&mut paths_of_next_day);
+            let f_out = std::fs::File::create(&sorted_file).unwrap();
+            let mut writer = xz2::write::XzEncoder::new(f_out, 6);
+            // a gap of 2 minutes, and a received_at reset by 90 seconds
+            for (received_at, time) in [
+                (1630540800100, 1630540800000),
+                (1630540920100, 1630540920000),
+                (1630540830000, 1630540920500),
+                (1630540830100, 1630540921000),
+            ] {
+                writeln!(writer, "{}", line(received_at, time)).unwrap();
+            }
+            writer.finish().unwrap();
         }
-        let zero_hour = Regex::new(r"\d{4}-\d{2}-\d{2}-00-\d{2}\.json\.gz$").unwrap();
-        if paths
-            .iter()
-            .filter(|s| !zero_hour.is_match(s.file_name().unwrap().to_str().unwrap()))
-            .count()
-            == 0
+        let (lines, anomalies) = super::check_sorted(
+            &sorted_file,
+            RawSortKey::Timestamp,
+            SortOrder::Asc,
+            Some(60_000),
+        )
+        .unwrap();
+        assert_eq!(4, lines);
+        assert_eq!(
+            vec![
+                ClockAnomaly {
+                    kind: ClockAnomalyKind::Gap,
+                    line: 2,
+                    previous: 1630540800000,
+                    current: 1630540920000,
+                },
+                ClockAnomaly {
+                    kind: ClockAnomalyKind::BackwardJump,
+                    line: 3,
+                    previous: 1630540920100,
+                    current: 1630540830000,
+                },
+            ],
+            anomalies
+        );
+        assert_eq!(90_100, anomalies[1].interval_ms());
+        let (_, anomalies) =
+            super::check_sorted(&sorted_file, RawSortKey::Timestamp, SortOrder::Asc, None).unwrap();
+        assert!(anomalies.is_empty());
+        std::fs::remove_file(&sorted_file).unwrap();
+    }
+
+    #[test]
+    fn test_bounded_lines() {
+        use super::{line_too_long, BoundedLines};
+        let input = format!("short\r\n{}\nlast", "x".repeat(100));
+        // a tiny buffer splits lines across reads
+        let lines: Vec<std::io::Result<String>> = BoundedLines {
+            reader: std::io::BufReader::with_capacity(8, input.as_bytes()),
+            max_bytes: 10,
+        }
+        .collect();
+        assert_eq!(3, lines.len());
+        assert_eq!("short", lines[0].as_ref().unwrap());
+        assert_eq!(Some(100), line_too_long(lines[1].as_ref().unwrap_err()));
+        assert_eq!("last", lines[2].as_ref().unwrap());
+        let lines: Vec<String> = BoundedLines {
+            reader: input.as_bytes(),
+            max_bytes: 100,
+        }
+        .map(Result::unwrap)
+        .collect();
+        assert_eq!(
+            vec!["short".to_string(), "x".repeat(100), "last".to_string()],
+            lines
+        );
+    }
+
+    #[test]
+    fn test_recompress_file() {
+        use std::io::{Read, Write};
+        let xz_file = std::env::temp_dir().join(format!(
+            "test_recompress_file_{}.json.xz",
+            std::process::id()
+        ));
+        // the last line has no newline
+        let content = "{\"a\":2}\n{\"a\":1}\n{\"a\":3}";
         {
-            warn!("There are no files to split, pattern: {}", glob_pattern);
-            return true;
+            let mut encoder =
+                xz2::write::XzEncoder::new(std::fs::File::create(&xz_file).unwrap(), 6);
+            encoder.write_all(content.as_bytes()).unwrap();
+            encoder.finish().unwrap();
         }
-        info!(
-            "Started split {} {} {} {}",
-            exchange, market_type, msg_type, day
-        );
-        let (tx_raw, rx_raw): (
-            Sender<(i64, i64, i64, i64, i64)>,
-            Receiver<(i64, i64, i64, i64, i64)>,
-        ) = mpsc::channel();
-        let (tx_parsed, rx_parsed): (
-            Sender<(i64, i64, i64, i64, i64)>,
-            Receiver<(i64, i64, i64, i64, i64)>,
-        ) = mpsc::channel();
-        let start_timstamp = Instant::now();
-        // Larger files get processed first
-        paths.sort_by_cached_key(|path| Reverse(std::fs::metadata(path).unwrap().len()));
+        let (gz_file, lines) = super::recompress_file(&xz_file).unwrap();
+        assert_eq!(3, lines);
+        assert_eq!(xz_file.with_extension("gz"), gz_file);
+        let mut decompressed = String::new();
+        flate2::read::MultiGzDecoder::new(std::fs::File::open(&gz_file).unwrap())
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(content, decompressed);
+        assert!(xz_file.exists());
+        std::fs::remove_file(&xz_file).unwrap();
+        std::fs::remove_file(&gz_file).unwrap();
+    }
+
+    #[test]
+    fn test_buffer_small_files() {
+        use std::io::BufRead;
+        use std::sync::Arc;
 
-        let written_to_raw: Arc<DashSet<u64>> = Arc::new(DashSet::new());
-        let written_to_parsed: Arc<DashSet<u64>> = Arc::new(DashSet::new());
-        let splitted_files_raw: Arc<DashMap<String, Output>> = Arc::new(DashMap::new());
-        let splitted_files_parsed: Arc<DashMap<String, Output>> = Arc::new(DashMap::new());
+        let output_dir =
+            std::env::temp_dir().join(format!("test_buffer_small_files_{}", std::process::id()));
+        let splitted_files = super::SplittedFiles::new(
+            16,
+            Some(100),
+            false,
+            Arc::new(super::MemoryBudget::new(None)),
+            Arc::new(super::OutputBudget::new(None)),
+        );
+        let small_file = output_dir.join("small.json.gz");
+        let large_file = output_dir.join("large.json.gz");
+        let line = "x".repeat(30);
+        for _ in 0..2 {
+            splitted_files.write_line(small_file.clone(), &line);
+        }
+        for _ in 0..10 {
+            splitted_files.write_line(large_file.clone(), &line);
+        }
+        // the small file is still in memory, the large one is streamed
+        assert!(!small_file.exists());
+        assert!(large_file.exists());
+        assert_eq!(1, splitted_files.open_files.load(super::Ordering::SeqCst));
+        splitted_files.close_all();
+        for (file, lines) in [(small_file, 2), (large_file, 10)] {
+            let read: Vec<String> = super::open_splitted_file(&file)
+                .lines()
+                .map(Result::unwrap)
+                .collect();
+            assert_eq!(vec![line.clone(); lines], read);
+        }
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
 
-        for input_file in paths {
-            let file_name = input_file.as_path().file_name().unwrap();
-            let v: Vec<&str> = file_name.to_str().unwrap().split('.').collect();
-            assert_eq!(exchange, v[0]);
-            if market_type != MarketType::Unknown {
-                assert_eq!(market_type, MarketType::from_str(v[1]).unwrap());
+    #[test]
+    fn test_json_style_writer() {
+        use super::{JsonStyleWriter, OutputJsonStyle};
+        use std::io::Write;
+        let frame = |style, chunks: &[&str]| {
+            let mut writer = JsonStyleWriter::new(Vec::new(), style);
+            for chunk in chunks {
+                writer.write_all(chunk.as_bytes()).unwrap();
             }
-            let msg_type_str = v[2];
-            assert_eq!(msg_type, MessageType::from_str(msg_type_str).unwrap());
+            String::from_utf8(writer.finish().unwrap()).unwrap()
+        };
+        let chunks = ["{\"a\":1}\n{\"a\"", ":2}\n", "{\"a\":3}\n"];
+        assert_eq!(chunks.concat(), frame(OutputJsonStyle::Ndjson, &chunks));
+        let array = frame(OutputJsonStyle::Array, &chunks);
+        assert_eq!("[\n{\"a\":1},\n{\"a\":2},\n{\"a\":3}\n]\n", array);
+        let values: Vec<serde_json::Value> = serde_json::from_str(&array).unwrap();
+        assert_eq!(3, values.len());
+        assert_eq!("[]\n", frame(OutputJsonStyle::Array, &[]));
+    }
 
-            let input_file_clone = input_file.clone();
-            let day_clone = day.to_string();
-            let exchange_output_dir_raw =
-                Path::new(output_dir_raw).join(msg_type_str).join(exchange);
-            let splitted_files_raw_clone = splitted_files_raw.clone();
-            let written_to_raw_clone = written_to_raw.clone();
-            let tx_raw_clone = tx_raw.clone();
-            thread_pool.execute(move || {
-                let t = split_file_raw(
-                    input_file_clone,
-                    day_clone,
-                    exchange_output_dir_raw,
-                    splitted_files_raw_clone,
-                    written_to_raw_clone,
-                );
-                tx_raw_clone.send(t).unwrap();
-            });
+    #[test]
+    fn test_bloom_filter() {
+        let mut bloom = super::BloomFilter::new(1000, 0.01);
+        for i in 0..1000u64 {
+            bloom.insert(super::hash_json(&i.to_string(), false));
+        }
+        assert!((0..1000u64).all(|i| bloom.contains(super::hash_json(&i.to_string(), false))));
+        let false_positives = (1000..11000u64)
+            .filter(|i| bloom.contains(super::hash_json(&i.to_string(), false)))
+            .count();
+        assert!(false_positives < 300, "{false_positives}");
 
-            let input_file_clone = input_file.clone();
-            let day_clone = day.to_string();
-            let splitted_files_parsed_clone = splitted_files_parsed.clone();
-            let exchange_output_dir_parsed = Path::new(output_dir_parsed)
-                .join(msg_type_str)
-                .join(exchange);
-            let written_to_parsed_clone = written_to_parsed.clone();
-            let tx_parsed_clone = tx_parsed.clone();
-            thread_pool.execute(move || {
-                let t = split_file_parsed(
-                    input_file_clone,
-                    day_clone,
-                    exchange_output_dir_parsed,
-                    splitted_files_parsed_clone,
-                    written_to_parsed_clone,
-                );
-                tx_parsed_clone.send(t).unwrap();
-            });
+        let bloom_file = std::env::temp_dir().join(format!(
+            "test_bloom_filter_{}/binance.spot.trade.2021-09-02.bloom",
+            std::process::id()
+        ));
+        bloom.save(&bloom_file);
+        let loaded = super::BloomFilter::load(&bloom_file).unwrap();
+        assert_eq!(bloom.hashes, loaded.hashes);
+        assert_eq!(bloom.bits, loaded.bits);
+        std::fs::remove_dir_all(bloom_file.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_shard() {
+        use super::Shard;
+        let symbols = ["BTCUSDT", "ETHUSDT", "BNBUSDT", "XRPUSDT", "ADAUSDT"];
+        let shards: Vec<Shard> = (0..3).map(|index| Shard { index, count: 3 }).collect();
+        // every symbol belongs to exactly one shard
+        for symbol in symbols {
+            assert_eq!(
+                1,
+                shards.iter().filter(|shard| shard.contains(symbol)).count()
+            );
         }
-        thread_pool.join();
-        let finishing = move |tx: Sender<(i64, i64, i64, i64, i64)>,
-                              rx: Receiver<(i64, i64, i64, i64, i64)>,
-                              splitted_files: Arc<DashMap<String, Output>>,
-                              is_parsed: bool|
-              -> bool {
-            drop(tx); // drop the sender to unblock receiver
-            let mut total_lines = 0;
-            let mut unique_lines = 0;
-            let mut duplicated_lines = 0;
-            let mut error_lines = 0;
-            let mut expired_lines = 0;
-            for t in rx {
-                total_lines += t.0;
-                unique_lines += t.1;
-                duplicated_lines += t.2;
-                error_lines += t.3;
-                expired_lines += t.4;
-            }
-            if is_parsed {
-                assert_eq!(total_lines, unique_lines + duplicated_lines + error_lines);
-            } else {
+        assert!(super::is_in_shard("BTCUSDT", None));
+        assert_eq!("", super::shard_suffix(None));
+        assert_eq!(".shard-1-of-3", super::shard_suffix(Some(shards[1])));
+        assert!(super::Options::parse(&["--shard-index".to_string(), "1".to_string()]).is_err());
+        assert!(super::Options::parse(&[
+            "--shard-index".to_string(),
+            "3".to_string(),
+            "--shard-count".to_string(),
+            "3".to_string(),
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_pin_threads() {
+        assert!(super::Options::parse(&[
+            "--pin-threads".to_string(),
+            "--compress-threads".to_string(),
+            "4".to_string(),
+        ])
+        .is_err());
+        #[cfg(target_os = "linux")]
+        {
+            let allowed_cpus = |command: &mut std::process::Command| {
+                let output = command
+                    .args(["Cpus_allowed_list", "/proc/self/status"])
+                    .output()
+                    .unwrap();
+                String::from_utf8(output.stdout).unwrap()
+            };
+            let process_cpus = allowed_cpus(&mut std::process::Command::new("grep"));
+            std::thread::spawn(move || {
+                super::save_process_affinity();
+                let core_ids = core_affinity::get_core_ids().unwrap();
+                assert!(core_affinity::set_for_current(core_ids[0]));
+                let pinned_cpus = allowed_cpus(&mut std::process::Command::new("grep"));
+                if core_ids.len() > 1 {
+                    assert_ne!(process_cpus, pinned_cpus);
+                }
                 assert_eq!(
-                    total_lines,
-                    unique_lines + duplicated_lines + error_lines + expired_lines
+                    process_cpus,
+                    allowed_cpus(super::unpinned(&mut std::process::Command::new("grep")))
                 );
-            }
+            })
+            .join()
+            .unwrap();
+        }
+    }
 
-            for entry in splitted_files.iter() {
-                let output = entry.value();
-                output.0.lock().unwrap().flush().unwrap();
-            }
-            let error_ratio = (error_lines as f64) / (total_lines as f64);
-            if error_ratio > 0.01 && !EXEMPTED_EXCHANGES.contains(&exchange) {
-                // error ratio > 1%
-                error!(
-                    "Failed to split {} {} {} {}, because error ratio {}/{}={}% is higher than 1% !",
-                    exchange,
-                    market_type,
-                    msg_type,
-                    day,
-                    error_lines,
-                    total_lines,
-                    error_ratio * 100.0
-                );
-                false
-            } else {
-                info!("Finished split {} {} {} {}, total {} lines, {} unique lines, {} duplicated lines, {} expired lines,  {} malformed lines, time elapsed {} seconds", exchange, market_type, msg_type, day, total_lines, unique_lines, duplicated_lines, expired_lines, error_lines, start_timstamp.elapsed().as_secs());
-                true
-            }
+    #[test]
+    fn test_input_format() {
+        use super::InputFormat;
+        use std::path::Path;
+        assert_eq!(
+            InputFormat::Gzip,
+            InputFormat::from_magic(&[0x1f, 0x8b, 0x08, 0x00])
+        );
+        assert_eq!(
+            InputFormat::Xz,
+            InputFormat::from_magic(&[0xfd, b'7', b'z', b'X', b'Z', 0x00])
+        );
+        assert_eq!(
+            InputFormat::Plain,
+            InputFormat::from_magic(b"{\"exchange\"")
+        );
+        assert_eq!(
+            InputFormat::Plain,
+            InputFormat::from_extension(Path::new("binance.spot.trade.2021-09-02-00-00.json"))
+        );
+        assert_eq!(
+            InputFormat::Gzip,
+            InputFormat::from_extension(Path::new("binance.spot.trade.2021-09-02.tgz"))
+        );
+    }
+
+    #[test]
+    fn test_normalize_received_at() {
+        use serde_json::json;
+        assert_eq!(
+            Some(1630540800010),
+            super::normalize_received_at(&json!(1630540800010u64))
+        );
+        assert_eq!(
+            Some(1630540800010),
+            super::normalize_received_at(&json!("1630540800010"))
+        );
+        assert_eq!(
+            Some(1630540800010),
+            super::normalize_received_at(&json!(1630540800010.0))
+        );
+        assert_eq!(
+            Some(1630540800123),
+            super::normalize_received_at(&json!("1630540800.123"))
+        );
+        assert_eq!(None, super::normalize_received_at(&json!("yesterday")));
+
+        let options = super::Options {
+            normalize_received_at: true,
+            ..Default::default()
         };
-        if !finishing(tx_raw, rx_raw, splitted_files_raw, false)
-            || !finishing(tx_parsed, rx_parsed, splitted_files_parsed, true)
+        let line = r#"{"exchange":"binance","market_type":"spot","msg_type":"trade","received_at":"1630540800010","json":"{}"}"#;
+        assert!(super::parse_envelope(line, &Default::default()).is_none());
+        let (msg, normalized) = super::parse_envelope(line, &options).unwrap();
+        assert_eq!(1630540800010, msg.received_at);
+        assert!(normalized);
+    }
+
+    #[test]
+    fn test_day_filter_basis() {
+        use super::DayFilterBasis;
+        let day = "2021-09-02";
+        let before_midnight = 1630627199990; // 2021-09-02T23:59:59.990Z
+        let after_midnight = 1630627200010; // 2021-09-03T00:00:00.010Z
+        let disagree = (after_midnight, Some(before_midnight));
+        assert_eq!(
+            None,
+            DayFilterBasis::Mixed.day_timestamp(day, disagree.0, disagree.1, false)
+        );
+        assert_eq!(
+            Some(before_midnight),
+            DayFilterBasis::Mixed.day_timestamp(day, disagree.0, disagree.1, true)
+        );
+        assert_eq!(
+            None,
+            DayFilterBasis::ReceivedAt.day_timestamp(day, disagree.0, disagree.1, true)
+        );
+        assert_eq!(
+            Some(before_midnight),
+            DayFilterBasis::EventTimestamp.day_timestamp(day, disagree.0, disagree.1, false)
+        );
+        assert_eq!(
+            Some(before_midnight),
+            DayFilterBasis::Either.day_timestamp(day, disagree.0, disagree.1, false)
+        );
+        assert_eq!(
+            None,
+            DayFilterBasis::EventTimestamp.day_timestamp(
+                "2021-09-03",
+                before_midnight,
+                None,
+                false
+            )
+        );
+        assert!(super::is_day_disagreement(day, disagree.0, before_midnight));
+        assert!(!super::is_day_disagreement(
+            day,
+            before_midnight,
+            before_midnight
+        ));
+    }
+
+    #[test]
+    fn test_estimate_remaining() {
+        use std::time::Duration;
+        let elapsed = Duration::from_secs(600);
+        assert_eq!(None, super::estimate_remaining(elapsed, 0, 1000));
+        assert_eq!(None, super::estimate_remaining(elapsed, 10, 1000));
+        assert_eq!(
+            Some(Duration::from_secs(1800)),
+            super::estimate_remaining(elapsed, 250, 1000)
+        );
+        assert_eq!("30 min", super::format_duration(Duration::from_secs(1800)));
+        assert_eq!("1.5 h", super::format_duration(Duration::from_secs(5400)));
+        assert_eq!("40 s", super::format_duration(Duration::from_secs(40)));
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = super::MemoryBudget::new(Some(100));
+        assert!(budget.try_reserve(60));
+        assert!(!budget.try_reserve(60));
         {
-            return false;
+            let mut reservation = budget.reserve(40);
+            reservation.grow(10);
+            assert!(budget.exceeded());
         }
+        assert!(budget.try_reserve(40));
+        let output_budget = super::OutputBudget::new(Some(100));
+        output_budget.add(99);
+        assert!(!output_budget.exceeded());
+        output_budget.add(1);
+        assert!(output_budget.exceeded());
+        assert_eq!(Ok(16 << 30), super::parse_bytes("16G"));
+        assert!(super::parse_bytes("0").is_err());
+        assert!(super::parse_bytes("99999999999G").is_err());
     }
 
-    // sort by timestamp
-    {
-        let glob_pattern = if market_type == MarketType::Unknown {
-            // MarketType::Unknown means all markets
-            format!("/{msg_type}/{exchange}/*/{exchange}.*.{msg_type}.*.{day}-??.json.gz")
-        } else if exchange == "deribit"
-            && market_type == MarketType::InverseFuture
-            && msg_type == MessageType::Trade
-        {
-            format!(
-                "/{msg_type}/{exchange}/{{invere_future,inverse_swap}}/{exchange}.*.{msg_type}.*.{day}-??.json.gz"
-            )
-        } else {
+    #[test]
+    fn test_annotate_source() {
+        assert_eq!(
+            r#"{"price":1.0,"_src":"a.json.gz"}"#,
+            super::annotate_source(r#"{"price":1.0}"#.to_string(), r#""a.json.gz""#)
+        );
+    }
+
+    #[test]
+    fn test_latencies() {
+        let latencies = super::Latencies::new();
+        latencies.add("BTCUSDT".to_string(), (1..=99).map(|ms| (ms, 1)).collect());
+        latencies.add("BTCUSDT".to_string(), [(1000, 1)].into_iter().collect());
+        latencies.add("ETHUSDT".to_string(), [(-5, 3)].into_iter().collect());
+        let stats = latencies.stats();
+        assert_eq!(100, stats["BTCUSDT"].messages);
+        assert_eq!(50, stats["BTCUSDT"].p50_ms);
+        assert_eq!(99, stats["BTCUSDT"].p99_ms);
+        assert_eq!(-5, stats["ETHUSDT"].p99_ms);
+        assert_eq!(103, stats["*"].messages);
+        assert_eq!(49, stats["*"].p50_ms);
+        assert_eq!(99, stats["*"].p99_ms);
+    }
+
+    #[test]
+    fn test_process_files_of_day() {
+        use std::io::{BufRead, Read, Write};
+        use std::sync::Arc;
+
+        let root =
+            std::env::temp_dir().join(format!("test_process_files_of_day_{}", std::process::id()));
+        let input_dir = root.join("input");
+        let output_dir_raw = root.join("raw");
+        let output_dir_parsed = root.join("parsed");
+        let fixture_dir = input_dir.join("host1/trade/binance/spot");
+        std::fs::create_dir_all(&fixture_dir).unwrap();
+        // out of order, with a duplicated line and a trade of the next day
+        let line = |symbol: &str, received_at: i64| {
             format!(
-                "/{msg_type}/{exchange}/{market_type}/{exchange}.{market_type}.{msg_type}.*.{day}-??.json.gz"
+                r#"{{"exchange":"binance","market_type":"spot","msg_type":"trade","received_at":{received_at},"json":"{{\"stream\":\"{}@aggTrade\",\"data\":{{\"e\":\"aggTrade\",\"E\":{received_at},\"s\":\"{symbol}\",\"a\":{received_at},\"p\":\"47000.0\",\"q\":\"0.1\",\"f\":0,\"l\":0,\"T\":{received_at},\"m\":true,\"M\":true}}}}"}}"#,
+                symbol.to_lowercase()
             )
         };
+        let lines = [
+            line("BTCUSDT", 1630540802000),
+            line("ETHUSDT", 1630540801000),
+            line("BTCUSDT", 1630540800000),
+            line("BTCUSDT", 1630540800000),
+            line("BTCUSDT", 1630627200000),
+        ];
+        let mut encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(fixture_dir.join("binance.spot.trade.2021-09-02-05-00.json.gz"))
+                .unwrap(),
+            flate2::Compression::default(),
+        );
+        for line in lines.iter() {
+            writeln!(encoder, "{line}").unwrap();
+        }
+        encoder.finish().unwrap();
 
-        let paths_raw: Vec<PathBuf> = glob(format!("{output_dir_raw}{glob_pattern}").as_str())
-            .unwrap()
-            .filter_map(Result::ok)
+        let options = super::Options {
+            // the in-process encoder only
+            pixz_percentile: 1.0,
+            ..Default::default()
+        };
+        let result = super::process_files_of_day(
+            "binance",
+            MessageType::Trade,
+            MarketType::Spot,
+            "2021-09-02",
+            input_dir.to_str().unwrap(),
+            output_dir_raw.to_str().unwrap(),
+            output_dir_parsed.to_str().unwrap(),
+            &options,
+            &threadpool::ThreadPool::new(2),
+            &Arc::new(super::MemoryBudget::new(None)),
+            &Arc::new(super::OutputBudget::new(None)),
+        );
+        assert!(result.success());
+        assert_eq!(5, result.split.total_lines);
+
+        let read_lines = |path: std::path::PathBuf| -> Vec<serde_json::Value> {
+            let f = std::fs::File::open(&path)
+                .unwrap_or_else(|_| panic!("{} does not exist", path.display()));
+            std::io::BufReader::new(xz2::read::XzDecoder::new(f))
+                .lines()
+                .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+                .collect()
+        };
+        let raw = read_lines(
+            output_dir_raw.join("trade/binance/spot/binance.spot.trade.BTCUSDT.2021-09-02.json.xz"),
+        );
+        let received_at: Vec<i64> = raw
+            .iter()
+            .map(|msg| msg["received_at"].as_i64().unwrap())
             .collect();
-        if paths_raw.is_empty() {
-            warn!("There are no files to sort, pattern: {}", glob_pattern);
-            return true;
+        assert_eq!(vec![1630540800000, 1630540802000], received_at);
+        assert_eq!(
+            1,
+            read_lines(
+                output_dir_raw
+                    .join("trade/binance/spot/binance.spot.trade.ETHUSDT.2021-09-02.json.xz")
+            )
+            .len()
+        );
+        let parsed = read_lines(
+            output_dir_parsed
+                .join("trade/binance/spot/binance.spot.trade.BTC.USDT.BTCUSDT.2021-09-02.json.xz"),
+        );
+        let timestamps: Vec<i64> = parsed
+            .iter()
+            .map(|msg| msg["timestamp"].as_i64().unwrap())
+            .collect();
+        assert_eq!(vec![1630540800000, 1630540802000], timestamps);
+        assert_eq!("BTC/USDT", parsed[0]["pair"]);
+
+        // the same outputs as the in-memory pipeline of the library
+        let input = lines.join("\n");
+        let (outputs, _) = crypto_cli_tools::process_day(
+            vec![input.as_bytes()],
+            "binance",
+            MarketType::Spot,
+            MessageType::Trade,
+            "2021-09-02",
+        )
+        .unwrap();
+        assert_eq!(4, outputs.len());
+        for ((output, path), bytes) in outputs {
+            let output_dir = match output {
+                crypto_cli_tools::Output::Raw => &output_dir_raw,
+                crypto_cli_tools::Output::Parsed => &output_dir_parsed,
+            };
+            let mut sorted = String::new();
+            xz2::read::XzDecoder::new(
+                std::fs::File::open(output_dir.join(format!("{path}.xz"))).unwrap(),
+            )
+            .read_to_string(&mut sorted)
+            .unwrap();
+            assert_eq!(String::from_utf8(bytes).unwrap(), sorted, "{path}");
+        }
+        for output_dir in [&output_dir_raw, &output_dir_parsed] {
+            assert!(output_dir
+                .join("_SUCCESS.binance.spot.trade.2021-09-02")
+                .exists());
+            assert!(output_dir
+                .join("manifest.binance.spot.trade.2021-09-02.json")
+                .exists());
         }
 
-        let paths_parsed = glob(format!("{output_dir_parsed}{glob_pattern}").as_str())
-            .unwrap()
-            .filter_map(Result::ok);
+        // a completed day is not processed again
+        let options = super::Options {
+            only_new: true,
+            ..options
+        };
+        let result = super::process_files_of_day(
+            "binance",
+            MessageType::Trade,
+            MarketType::Spot,
+            "2021-09-02",
+            input_dir.to_str().unwrap(),
+            output_dir_raw.to_str().unwrap(),
+            output_dir_parsed.to_str().unwrap(),
+            &options,
+            &threadpool::ThreadPool::new(2),
+            &Arc::new(super::MemoryBudget::new(None)),
+            &Arc::new(super::OutputBudget::new(None)),
+        );
+        assert!(result.success());
+        assert!(!result.split.ran && !result.sort.ran);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_daily_input_file() {
+        use std::io::{BufRead, Write};
+        use std::sync::Arc;
 
-        let paths: Vec<PathBuf> = paths_raw.into_iter().chain(paths_parsed).collect();
-        for path in paths.iter() {
-            assert!(!path.as_path().to_str().unwrap().contains(".unknown."));
+        let root =
+            std::env::temp_dir().join(format!("test_daily_input_file_{}", std::process::id()));
+        let input_dir = root.join("input");
+        let output_dir_raw = root.join("raw");
+        let output_dir_parsed = root.join("parsed");
+        let fixture_dir = input_dir.join("host1/trade/binance/spot");
+        std::fs::create_dir_all(&fixture_dir).unwrap();
+        // an archive without hourly files
+        let mut encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(fixture_dir.join("binance.spot.trade.2021-09-02.json.gz"))
+                .unwrap(),
+            flate2::Compression::default(),
+        );
+        for received_at in [1630580400000_i64, 1630540800000] {
+            writeln!(
+                encoder,
+                r#"{{"exchange":"binance","market_type":"spot","msg_type":"trade","received_at":{received_at},"json":"{{\"stream\":\"btcusdt@aggTrade\",\"data\":{{\"e\":\"aggTrade\",\"E\":{received_at},\"s\":\"BTCUSDT\",\"a\":{received_at},\"p\":\"47000.0\",\"q\":\"0.1\",\"f\":0,\"l\":0,\"T\":{received_at},\"m\":true,\"M\":true}}}}"}}"#
+            )
+            .unwrap();
         }
-        let total_files = paths.len();
+        encoder.finish().unwrap();
 
-        let paths_by_day = {
-            // group by day
-            let mut groups: HashMap<String, Vec<PathBuf>> = HashMap::new();
-            for path in paths {
-                let file_name = path.as_path().file_name().unwrap().to_str().unwrap();
-                let key = &file_name[0..(file_name.len() - "-??.json.gz".len())];
-                if !groups.contains_key(key) {
-                    groups.insert(key.to_string(), vec![]);
-                }
-                groups.get_mut(key).unwrap().push(path);
-            }
-            let mut groups: Vec<Vec<PathBuf>> = groups.values().cloned().collect();
-            // Smaller groups get processed first
-            groups.sort_by_cached_key(|group| {
-                group
-                    .iter()
-                    .map(|file| std::fs::metadata(file).unwrap().len())
-                    .sum::<u64>()
-            });
-            groups
+        let options = super::Options {
+            pixz_percentile: 1.0,
+            ..Default::default()
         };
-
-        info!(
-            "Started sort {} {} {} {}",
-            exchange, market_type, msg_type, day
+        let result = super::process_files_of_day(
+            "binance",
+            MessageType::Trade,
+            MarketType::Spot,
+            "2021-09-02",
+            input_dir.to_str().unwrap(),
+            output_dir_raw.to_str().unwrap(),
+            output_dir_parsed.to_str().unwrap(),
+            &options,
+            &threadpool::ThreadPool::new(2),
+            &Arc::new(super::MemoryBudget::new(None)),
+            &Arc::new(super::OutputBudget::new(None)),
         );
-        let (tx, rx): (Sender<(i64, i64)>, Receiver<(i64, i64)>) = mpsc::channel();
-        let start_timstamp = Instant::now();
-        let percentile_90 = ((paths_by_day.len() as f64) * 0.9) as usize;
-        let xz_exists = Path::new("/usr/bin/xz").exists();
-        let semaphore = Arc::new(AtomicUsize::new(MAX_PIXZ));
-        for (index, input_files) in paths_by_day.into_iter().enumerate() {
-            let file_name = input_files[0]
-                .as_path()
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap();
-            let output_file_name = format!(
-                "{}.json.xz",
-                &file_name[0..(file_name.len() - "-??.json.gz".len())]
+        assert!(result.success());
+        assert_eq!(2, result.split.total_lines);
+        let sorted =
+            output_dir_raw.join("trade/binance/spot/binance.spot.trade.BTCUSDT.2021-09-02.json.xz");
+        let received_at: Vec<i64> = std::io::BufReader::new(xz2::read::XzDecoder::new(
+            std::fs::File::open(&sorted).unwrap(),
+        ))
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(&line.unwrap()).unwrap())
+        .map(|msg| msg["received_at"].as_i64().unwrap())
+        .collect();
+        assert_eq!(vec![1630540800000, 1630580400000], received_at);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_quarantine_dir() {
+        use std::io::{BufRead, Write};
+        use std::sync::Arc;
+
+        let root = std::env::temp_dir().join(format!("test_quarantine_dir_{}", std::process::id()));
+        let input_dir = root.join("input");
+        let output_dir_raw = root.join("raw");
+        let output_dir_parsed = root.join("parsed");
+        let quarantine_dir = root.join("quarantine");
+        let fixture_dir = input_dir.join("host1/trade/binance/spot");
+        std::fs::create_dir_all(&fixture_dir).unwrap();
+        let line = |symbol: &str, received_at: i64| {
+            format!(
+                r#"{{"exchange":"binance","market_type":"spot","msg_type":"trade","received_at":{received_at},"json":"{{\"stream\":\"{}@aggTrade\",\"data\":{{\"e\":\"aggTrade\",\"E\":{received_at},\"s\":\"{symbol}\",\"a\":{received_at},\"p\":\"47000.0\",\"q\":\"0.1\",\"f\":0,\"l\":0,\"T\":{received_at},\"m\":true,\"M\":true}}}}"}}"#,
+                symbol.to_lowercase()
+            )
+        };
+        let write_input = |file_name: &str, lines: &[String]| {
+            let mut encoder = flate2::write::GzEncoder::new(
+                std::fs::File::create(fixture_dir.join(file_name)).unwrap(),
+                flate2::Compression::default(),
             );
-            let output_file = Path::new(input_files[0].parent().unwrap()).join(output_file_name);
-            let tx_clone = tx.clone();
-            let semaphore_clone = semaphore.clone();
-            if xz_exists && index >= percentile_90 {
-                thread_pool.execute(move || {
-                    let t = sort_files(input_files, output_file, true, semaphore_clone);
-                    tx_clone.send(t).unwrap();
-                });
-            } else {
-                thread_pool.execute(move || {
-                    let t = sort_files(input_files, output_file, false, semaphore_clone);
-                    tx_clone.send(t).unwrap();
-                });
+            for line in lines {
+                writeln!(encoder, "{line}").unwrap();
             }
-        }
-        thread_pool.join();
-        drop(tx); // drop the sender
-        let mut total_lines = 0;
-        let mut error_lines = 0;
-        for t in rx {
-            error_lines += t.0;
-            total_lines += t.1;
-        }
-        if error_lines == 0 {
-            info!(
-                "Finished sort {} {} {} {}, {} files, total {} lines, time elapsed {} seconds",
-                exchange,
-                market_type,
-                msg_type,
-                day,
-                total_files,
-                total_lines,
-                start_timstamp.elapsed().as_secs()
-            );
-            true
-        } else {
-            error!(
-                "Failed to sort {} {} {} {}, found {} malformed lines out of total {} lines, time elapsed {} seconds",
-                exchange,
-                market_type,
-                msg_type,
-                day,
-                error_lines, total_lines,
-                start_timstamp.elapsed().as_secs()
-            );
-            // if error ratio is less than 0.00001, the function is considered successful
-            (error_lines as f64) / (total_lines as f64) < 0.00001
-        }
-    }
-}
+            encoder.finish().unwrap();
+        };
+        write_input(
+            "binance.spot.trade.2021-09-02-05-00.json.gz",
+            &[
+                line("BTCUSDT", 1630540800000),
+                line("BTCUSDT", 1630540801000),
+            ],
+        );
+        // mostly malformed, with a message of the good file and a symbol of its own
+        write_input(
+            "binance.spot.trade.2021-09-02-06-00.json.gz",
+            &[
+                line("BTCUSDT", 1630540800000),
+                line("ETHUSDT", 1630540802000),
+                "{".to_string(),
+                "not json".to_string(),
+            ],
+        );
 
-fn main() {
-    env_logger::init();
-    if getrlimit(Resource::NOFILE).unwrap().0 < MAX_OPEN_FILES {
-        if let Err(err) = setrlimit(Resource::NOFILE, MAX_OPEN_FILES, MAX_OPEN_FILES) {
-            error!("setrlimit() failed, {}", err);
-            error!("getrlimit(): {:?}", getrlimit(Resource::NOFILE).unwrap());
-            std::process::exit(1);
-        }
+        let options = super::Options {
+            pixz_percentile: 1.0,
+            quarantine_dir: Some(quarantine_dir.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        let result = super::process_files_of_day(
+            "binance",
+            MessageType::Trade,
+            MarketType::Spot,
+            "2021-09-02",
+            input_dir.to_str().unwrap(),
+            output_dir_raw.to_str().unwrap(),
+            output_dir_parsed.to_str().unwrap(),
+            &options,
+            &threadpool::ThreadPool::new(2),
+            &Arc::new(super::MemoryBudget::new(None)),
+            &Arc::new(super::OutputBudget::new(None)),
+        );
+        assert!(result.success());
+        assert_eq!(2, result.split.total_lines);
+        assert!(quarantine_dir
+            .join("host1/trade/binance/spot/binance.spot.trade.2021-09-02-06-00.json.gz")
+            .exists());
+        let records = std::fs::read_to_string(quarantine_dir.join("quarantine.jsonl")).unwrap();
+        assert_eq!(1, records.lines().count());
+        assert!(records.starts_with(r#"{"path":""#), "{records}");
+        assert!(records.contains(r#","reason":""#), "{records}");
+        let read_lines = |path: std::path::PathBuf| -> Vec<String> {
+            std::io::BufReader::new(xz2::read::XzDecoder::new(
+                std::fs::File::open(&path).unwrap(),
+            ))
+            .lines()
+            .map(Result::unwrap)
+            .collect()
+        };
+        let raw_dir = output_dir_raw.join("trade/binance/spot");
+        assert_eq!(
+            vec![
+                line("BTCUSDT", 1630540800000),
+                line("BTCUSDT", 1630540801000)
+            ],
+            read_lines(raw_dir.join("binance.spot.trade.BTCUSDT.2021-09-02.json.xz"))
+        );
+        assert_eq!(
+            2,
+            read_lines(
+                output_dir_parsed.join(
+                    "trade/binance/spot/binance.spot.trade.BTC.USDT.BTCUSDT.2021-09-02.json.xz"
+                )
+            )
+            .len()
+        );
+        assert!(!raw_dir
+            .join("binance.spot.trade.ETHUSDT.2021-09-02.json.xz")
+            .exists());
+        // spill files of held back lines are removed
+        assert_eq!(
+            0,
+            glob::glob(&format!("{}/**/.*.pending", root.display()))
+                .unwrap()
+                .count()
+        );
+        std::fs::remove_dir_all(root).unwrap();
     }
 
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 8 {
-        eprintln!("Usage: crypto-daily-processor <exchange> <msg_type> <market_type> <day> <input_dir> <output_dir_raw> <output_dir_parsed>");
-        std::process::exit(1);
+    #[test]
+    fn test_clean_symbol() {
+        let symbol = "a(b)c:d.-_e/f";
+        let encoded_symbol = super::encode_symbol(symbol);
+        assert_eq!("a%28b%29c%3Ad%2E-_e%2Ff", encoded_symbol);
+        // distinct symbols never share a file name
+        assert_ne!(
+            super::encode_symbol("BTC/USD"),
+            super::encode_symbol("BTC:USD")
+        );
+        assert_eq!(
+            vec![vec!["BTCUSDT".to_string(), "btcusdt".to_string()]],
+            super::symbol_collisions(
+                &["btcusdt", "BTC/USD", "BTCUSDT", "BTC:USD"].map(String::from)
+            )
+        );
+        assert_eq!(
+            "BTC/USD",
+            super::symbol_of_sorted_file(
+                std::path::Path::new(
+                    "binance.spot.trade.BTC.USD.BTC%2FUSD.2021-09-02.part2.json.xz"
+                ),
+                "json.xz"
+            )
+        );
     }
 
-    let exchange: &'static str = Box::leak(args[1].clone().into_boxed_str());
-    if exchange == "okex" || exchange == "mxc" {
-        eprintln!("exchange should NOT be okex nor mxc");
-        std::process::exit(1);
-    }
+    #[test]
+    fn test_timestamp_unit() {
+        assert_eq!(
+            super::TimestampUnit::Seconds,
+            super::TimestampUnit::detect(1630540800)
+        );
+        assert_eq!(
+            super::TimestampUnit::Millis,
+            super::TimestampUnit::detect(1630540800123)
+        );
+        assert_eq!(
+            super::TimestampUnit::Micros,
+            super::TimestampUnit::detect(1630540800123456)
+        );
+        assert_eq!(
+            1630540800000,
+            super::TimestampUnit::Seconds.to_millis(1630540800)
+        );
+        assert_eq!(
+            1630540800123,
+            super::TimestampUnit::Micros.to_millis(1630540800123456)
+        );
 
-    let msg_type = MessageType::from_str(&args[2]);
-    if msg_type.is_err() {
-        eprintln!("Unknown msg type: {}", &args[2]);
-        std::process::exit(1);
+        // detected from the first parsed message of a file
+        let input_file = std::path::Path::new("binance.spot.trade.2021-09-02-15-05.json.gz");
+        let mut unit = super::TimestampUnit::Auto;
+        assert_eq!(
+            1630540800000,
+            super::parsed_timestamp_millis(&mut unit, 1630540800, input_file)
+        );
+        assert_eq!(super::TimestampUnit::Seconds, unit);
+        let mut unit = super::TimestampUnit::Auto;
+        assert_eq!(
+            1630540800123,
+            super::parsed_timestamp_millis(&mut unit, 1630540800123, input_file)
+        );
+        assert_eq!(super::TimestampUnit::Millis, unit);
     }
-    let msg_type = msg_type.unwrap();
 
-    let market_type = MarketType::from_str(&args[3]);
-    if market_type.is_err() {
-        eprintln!("Unknown market type: {}", &args[3]);
-        std::process::exit(1);
-    }
-    let market_type = market_type.unwrap();
+    // cargo test --release --bin crypto-daily-processor -- --ignored bench_write_batch --nocapture
+    #[test]
+    #[ignore]
+    fn bench_write_batch() {
+        use std::sync::Arc;
+        use std::time::Instant;
 
-    let day: &'static str = Box::leak(args[4].clone().into_boxed_str());
-    let re = Regex::new(r"^\d{4}-\d{2}-\d{2}$").unwrap();
-    if !re.is_match(day) {
-        eprintln!("{day} is invalid, day should be yyyy-MM-dd");
-        std::process::exit(1);
+        let output_dir = std::env::temp_dir().join("bench_write_batch");
+        let line = "x".repeat(200);
+        // 8 threads write messages of 100 lines to the same hot symbol
+        let run = |batched: bool| {
+            let splitted_files = Arc::new(super::SplittedFiles::new(
+                16,
+                None,
+                false,
+                Arc::new(super::MemoryBudget::new(None)),
+                Arc::new(super::OutputBudget::new(None)),
+            ));
+            let start = Instant::now();
+            let threads: Vec<_> = (0..8)
+                .map(|_| {
+                    let splitted_files = splitted_files.clone();
+                    let output_file = output_dir.join(format!("{batched}.json.gz"));
+                    let line = line.clone();
+                    std::thread::spawn(move || {
+                        for _ in 0..1000 {
+                            if batched {
+                                let batch = format!("{line}\n").repeat(100);
+                                splitted_files.write_batch(output_file.clone(), &batch);
+                            } else {
+                                for _ in 0..100 {
+                                    splitted_files.write_line(output_file.clone(), &line);
+                                }
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            splitted_files.close_all();
+            start.elapsed()
+        };
+        let unbatched = run(false);
+        let batched = run(true);
+        println!("write_line {unbatched:?}, write_batch {batched:?}");
+        std::fs::remove_dir_all(output_dir).unwrap();
     }
 
-    let input_dir: &'static str = Box::leak(args[5].clone().into_boxed_str());
-    if !Path::new(input_dir).is_dir() {
-        eprintln!("{input_dir} does NOT exist");
-        std::process::exit(1);
+    #[test]
+    fn test_hash_json() {
+        let a = r#"{"stream":"btcusdt@trade","data":{"s":"BTCUSDT","p":"1.0"}}"#;
+        let b = r#"{"data":{"p":"1.0","s":"BTCUSDT"},"stream":"btcusdt@trade"}"#;
+        assert_ne!(super::hash_json(a, false), super::hash_json(b, false));
+        assert_eq!(super::hash_json(a, true), super::hash_json(b, true));
+        assert_ne!(
+            super::hash_json(r#"[[1],2]"#, true),
+            super::hash_json(r#"[[1,2]]"#, true)
+        );
     }
-    let output_dir_raw: &'static str = Box::leak(args[6].clone().into_boxed_str());
-    let output_dir_parsed: &'static str = Box::leak(args[7].clone().into_boxed_str());
-    std::fs::create_dir_all(Path::new(output_dir_raw)).unwrap();
-    std::fs::create_dir_all(Path::new(output_dir_parsed)).unwrap();
-
-    if !process_files_of_day(
-        exchange,
-        msg_type,
-        market_type,
-        day,
-        input_dir,
-        output_dir_raw,
-        output_dir_parsed,
-    ) {
-        std::process::exit(1);
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "is not valid JSON")]
+    fn test_debug_assert_message() {
+        // the envelope is valid, but json is truncated by the collector
+        let line = r#"{"exchange":"binance","market_type":"spot","msg_type":"trade","received_at":1630540800017,"json":"{\"stream\":\"btcusdt@trade\",\"data\":{\"e\":"}"#;
+        let msg: crypto_cli_tools::Message = serde_json::from_str(line).unwrap();
+        super::debug_assert_message(&msg);
     }
-}
 
-fn encode_symbol(symbol: &str) -> String {
-    let new_symbol = encode(symbol).to_string(); // equivalent to urllib.parse.quote_plus()
-    new_symbol.replace('.', "%2E") // escape the dot '.'
-}
-
-#[cfg(test)]
-mod test {
     #[test]
-    fn test_clean_symbol() {
-        let symbol = "a(b)c:d.-_e/f";
-        let encoded_symbol = super::encode_symbol(symbol);
-        assert_eq!("a%28b%29c%3Ad%2E-_e%2Ff", encoded_symbol);
+    fn test_event_timestamp() {
+        let line = r#"{"exchange":"binance","market_type":"spot","msg_type":"trade","received_at":1630540800017,"json":"{\"stream\":\"btcusdt@trade\",\"data\":{\"e\":\"trade\",\"E\":1630540800010,\"s\":\"BTCUSDT\",\"t\":1016224794,\"p\":\"47269.93000000\",\"q\":\"0.00100000\",\"b\":7500498357,\"a\":7500498400,\"T\":1630540799999,\"m\":true,\"M\":true}}"}"#;
+        let msg = serde_json::from_str(line).unwrap();
+        assert_eq!(Some(1630540800010), super::event_timestamp(&msg));
+        let msg = serde_json::from_str(&line.replace("binance", "unknown")).unwrap();
+        assert_eq!(None, super::event_timestamp(&msg));
     }
 }
@@ -0,0 +1,1309 @@
+//! Building blocks of the CLI tools, mostly usable without touching the filesystem.
+
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashSet},
+    fmt,
+    hash::{Hash, Hasher},
+    io::{BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use chrono::{NaiveDate, TimeZone, Utc};
+use crypto_market_type::MarketType;
+use crypto_message::{Order, OrderBookMsg, TradeMsg};
+use crypto_msg_parser::{extract_symbol, parse_l2, parse_trade};
+use crypto_msg_type::MessageType;
+use flate2::read::MultiGzDecoder;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A raw message, one line in input files.
+#[derive(Serialize, Deserialize)]
+pub struct Message {
+    /// The exchange name, unique for each exchage
+    pub exchange: String,
+    /// Market type
+    pub market_type: MarketType,
+    /// Message type
+    pub msg_type: MessageType,
+    /// Unix timestamp in milliseconds, zero if absent
+    #[serde(default)]
+    pub received_at: u64,
+    /// the original message
+    pub json: String,
+}
+
+/// A message parsed by `crypto_msg_parser`.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum ParsedMsg {
+    OrderBook(OrderBookMsg),
+    Trade(TradeMsg),
+}
+
+impl ParsedMsg {
+    pub fn market_type(&self) -> MarketType {
+        match self {
+            ParsedMsg::OrderBook(msg) => msg.market_type,
+            ParsedMsg::Trade(msg) => msg.market_type,
+        }
+    }
+
+    /// The exchange timestamp in milliseconds.
+    pub fn timestamp(&self) -> i64 {
+        match self {
+            ParsedMsg::OrderBook(msg) => msg.timestamp,
+            ParsedMsg::Trade(msg) => msg.timestamp,
+        }
+    }
+
+    pub fn set_timestamp(&mut self, timestamp: i64) {
+        match self {
+            ParsedMsg::OrderBook(msg) => msg.timestamp = timestamp,
+            ParsedMsg::Trade(msg) => msg.timestamp = timestamp,
+        }
+    }
+}
+
+/// The current name of a renamed exchange, i.e., `mexc` of `mxc` and `okx` of `okex`.
+fn rename_exchange(exchange: &str) -> &str {
+    match exchange {
+        "mxc" => "mexc",
+        "okex" => "okx",
+        exchange => exchange,
+    }
+}
+
+/// Check that a message belongs to the combo of its input file, `MarketType::Unknown`
+/// matches all markets.
+pub fn check_combo(
+    msg: &Message,
+    exchange: &str,
+    market_type: MarketType,
+    msg_type: MessageType,
+) -> Result<(), String> {
+    if msg.exchange != exchange
+        || (market_type != MarketType::Unknown && msg.market_type != market_type)
+        || msg.msg_type != msg_type
+    {
+        Err(format!(
+            "Expected {exchange} {market_type} {msg_type}, got {} {} {}",
+            msg.exchange, msg.market_type, msg.msg_type
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// The line of a raw message in output files, i.e., `line` as is, or `msg` serialized again
+/// with its real market type and renamed exchange if they differ or `rewrite` is true,
+/// e.g., after its `received_at` was normalized.
+pub fn raw_line<'a>(
+    msg: &Message,
+    line: &'a str,
+    real_market_type: MarketType,
+    rewrite: bool,
+) -> Cow<'a, str> {
+    let exchange = rename_exchange(&msg.exchange);
+    if rewrite || msg.market_type != real_market_type || exchange != msg.exchange {
+        // the same fields as Message, without copying json
+        #[derive(Serialize)]
+        struct RawMessage<'a> {
+            exchange: &'a str,
+            market_type: MarketType,
+            msg_type: MessageType,
+            received_at: u64,
+            json: &'a str,
+        }
+        Cow::Owned(
+            serde_json::to_string(&RawMessage {
+                exchange,
+                market_type: real_market_type,
+                msg_type: msg.msg_type,
+                received_at: msg.received_at,
+                json: &msg.json,
+            })
+            .unwrap(),
+        )
+    } else {
+        Cow::Borrowed(line)
+    }
+}
+
+/// Parse a raw message, `mxc` and `okex` are renamed to `mexc` and `okx`.
+pub fn parse_message(msg: &Message) -> Result<Vec<ParsedMsg>, String> {
+    let rename = |exchange: &mut String| {
+        *exchange = rename_exchange(exchange).to_string();
+    };
+    match msg.msg_type {
+        MessageType::L2Event => parse_l2(
+            &msg.exchange,
+            msg.market_type,
+            &msg.json,
+            Some(msg.received_at as i64),
+        )
+        .map(|messages| {
+            messages
+                .into_iter()
+                .map(|mut message| {
+                    rename(&mut message.exchange);
+                    ParsedMsg::OrderBook(message)
+                })
+                .collect()
+        })
+        .map_err(|err| err.to_string()),
+        MessageType::Trade => parse_trade(&msg.exchange, msg.market_type, &msg.json)
+            .map(|messages| {
+                messages
+                    .into_iter()
+                    .map(|mut message| {
+                        rename(&mut message.exchange);
+                        ParsedMsg::Trade(message)
+                    })
+                    .collect()
+            })
+            .map_err(|err| err.to_string()),
+        _ => Err(format!("Unsupported message type {}", msg.msg_type)),
+    }
+}
+
+/// JSON types in schemas of parsed messages.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum JsonType {
+    String,
+    Number,
+    Bool,
+    /// An array of orders, each order is an array of 3 or 4 numbers
+    Orders,
+}
+
+/// Version of the schemas below, bump it when `crypto-message` changes the format.
+pub const PARSED_SCHEMA_VERSION: u32 = 1;
+
+/// Required fields of `TradeMsg`.
+pub const TRADE_SCHEMA: &[(&str, JsonType)] = &[
+    ("exchange", JsonType::String),
+    ("market_type", JsonType::String),
+    ("msg_type", JsonType::String),
+    ("pair", JsonType::String),
+    ("symbol", JsonType::String),
+    ("timestamp", JsonType::Number),
+    ("side", JsonType::String),
+    ("price", JsonType::Number),
+    ("quantity_base", JsonType::Number),
+    ("quantity_quote", JsonType::Number),
+    ("trade_id", JsonType::String),
+    ("json", JsonType::String),
+];
+
+/// Required fields of `OrderBookMsg`.
+pub const ORDER_BOOK_SCHEMA: &[(&str, JsonType)] = &[
+    ("exchange", JsonType::String),
+    ("market_type", JsonType::String),
+    ("msg_type", JsonType::String),
+    ("pair", JsonType::String),
+    ("symbol", JsonType::String),
+    ("timestamp", JsonType::Number),
+    ("snapshot", JsonType::Bool),
+    ("asks", JsonType::Orders),
+    ("bids", JsonType::Orders),
+    ("json", JsonType::String),
+];
+
+/// Check a serialized parsed message against the schema of `msg_type`.
+pub fn validate_parsed(msg_type: MessageType, json: &str) -> Result<(), String> {
+    let schema = match msg_type {
+        MessageType::L2Event => ORDER_BOOK_SCHEMA,
+        MessageType::Trade => TRADE_SCHEMA,
+        _ => return Err(format!("No schema for {msg_type}")),
+    };
+    let value = serde_json::from_str::<Value>(json).map_err(|err| err.to_string())?;
+    for (field, json_type) in schema {
+        let valid = match (value.get(field), json_type) {
+            (Some(Value::String(_)), JsonType::String) => true,
+            (Some(Value::Number(_)), JsonType::Number) => true,
+            (Some(Value::Bool(_)), JsonType::Bool) => true,
+            (Some(Value::Array(orders)), JsonType::Orders) => orders.iter().all(|order| {
+                matches!(order, Value::Array(v) if (v.len() == 3 || v.len() == 4) && v.iter().all(Value::is_number))
+            }),
+            _ => false,
+        };
+        if !valid {
+            return Err(format!("{field} should be {json_type:?}"));
+        }
+    }
+    Ok(())
+}
+
+/// Transforms a serialized parsed message in place before it is written, e.g., adds a derived field.
+pub type Transform = fn(&mut Value);
+
+/// Names of built-in transforms.
+pub const BUILTIN_TRANSFORMS: &[&str] = &["mid_price", "venue"];
+
+/// Look up a built-in transform by name.
+///
+/// - `mid_price` adds `mid_price`, the average of the best ask and the best bid, to order books
+///   having both sides
+/// - `venue` adds `venue`, i.e., `exchange.market_type`
+pub fn builtin_transform(name: &str) -> Option<Transform> {
+    match name {
+        "mid_price" => Some(|value: &mut Value| {
+            let best = |side: &str| {
+                value.get(side)?.as_array().map(|orders| {
+                    orders
+                        .iter()
+                        .filter_map(|order| order.get(0).and_then(Value::as_f64))
+                        .collect::<Vec<f64>>()
+                })
+            };
+            let best_ask = best("asks").and_then(|v| v.into_iter().reduce(f64::min));
+            let best_bid = best("bids").and_then(|v| v.into_iter().reduce(f64::max));
+            if let (Some(best_ask), Some(best_bid)) = (best_ask, best_bid) {
+                value["mid_price"] = Value::from((best_ask + best_bid) / 2.0);
+            }
+        }),
+        "venue" => Some(|value: &mut Value| {
+            let venue = match (value.get("exchange"), value.get("market_type")) {
+                (Some(Value::String(exchange)), Some(Value::String(market_type))) => {
+                    format!("{exchange}.{market_type}")
+                }
+                _ => return,
+            };
+            value["venue"] = Value::String(venue);
+        }),
+        _ => None,
+    }
+}
+
+/// Apply `transforms` in order to a serialized parsed message, the message is returned as is
+/// if there are no transforms.
+pub fn apply_transforms(json: String, transforms: &[Transform]) -> String {
+    if transforms.is_empty() {
+        return json;
+    }
+    match serde_json::from_str::<Value>(&json) {
+        Ok(mut value) => {
+            for transform in transforms {
+                transform(&mut value);
+            }
+            value.to_string()
+        }
+        Err(_) => json,
+    }
+}
+
+/// Order book of one symbol, rebuilt from snapshots and updates.
+///
+/// It relies on `OrderBookMsg.snapshot` set by `crypto-msg-parser` per exchange, a snapshot
+/// replaces the whole book, and an update replaces price levels or removes them if
+/// `quantity_base` is zero. Updates before the first snapshot are ignored, because the
+/// book is unknown until then.
+#[derive(Default)]
+pub struct OrderBook {
+    // keyed by bits of prices, which are ordered like positive prices
+    asks: BTreeMap<u64, Order>,
+    bids: BTreeMap<u64, Order>,
+    synced: bool,
+}
+
+impl OrderBook {
+    /// Apply a message, returns false if it is an update before the first snapshot.
+    pub fn apply(&mut self, msg: &OrderBookMsg) -> bool {
+        if msg.snapshot {
+            self.asks.clear();
+            self.bids.clear();
+            self.synced = true;
+        } else if !self.synced {
+            return false;
+        }
+        for (side, orders) in [(&mut self.asks, &msg.asks), (&mut self.bids, &msg.bids)] {
+            for order in orders {
+                if order.quantity_base == 0.0 {
+                    side.remove(&order.price.to_bits());
+                } else {
+                    side.insert(order.price.to_bits(), *order);
+                }
+            }
+        }
+        true
+    }
+
+    /// Whether a snapshot has been applied.
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    /// A full-depth `L2Snapshot` of the book at `timestamp`, other fields are taken from `msg`.
+    pub fn snapshot(&self, msg: &OrderBookMsg, timestamp: i64) -> OrderBookMsg {
+        OrderBookMsg {
+            exchange: msg.exchange.clone(),
+            market_type: msg.market_type,
+            symbol: msg.symbol.clone(),
+            pair: msg.pair.clone(),
+            msg_type: MessageType::L2Snapshot,
+            timestamp,
+            snapshot: true,
+            asks: self.asks.values().copied().collect(),
+            bids: self.bids.values().rev().copied().collect(),
+            seq_id: None,
+            prev_seq_id: None,
+            json: String::new(),
+        }
+    }
+}
+
+/// Rebuild the order book of one symbol from timestamp-sorted `OrderBookMsg` lines, and
+/// pass a snapshot of it to `emit` at every multiple of `interval_ms` since the first line.
+///
+/// A snapshot at time `t` contains all messages before `t`, no snapshot is emitted before
+/// the book is synced or after the last line. Returns the number of updates ignored
+/// before the first snapshot.
+pub fn reconstruct_l2<I: Iterator<Item = String>>(
+    lines: I,
+    interval_ms: i64,
+    mut emit: impl FnMut(OrderBookMsg),
+) -> Result<u64, String> {
+    assert!(interval_ms > 0);
+    let mut book = OrderBook::default();
+    let mut next_timestamp: Option<i64> = None;
+    let mut ignored = 0;
+    for line in lines {
+        let msg = serde_json::from_str::<OrderBookMsg>(&line)
+            .map_err(|err| format!("Not a valid OrderBookMsg: {err}, {line}"))?;
+        let next = next_timestamp.get_or_insert((msg.timestamp / interval_ms + 1) * interval_ms);
+        if msg.timestamp < *next - interval_ms {
+            return Err(format!("Lines are not sorted by timestamp: {line}"));
+        }
+        while *next <= msg.timestamp {
+            if book.is_synced() {
+                emit(book.snapshot(&msg, *next));
+            }
+            *next += interval_ms;
+        }
+        if !book.apply(&msg) {
+            ignored += 1;
+        }
+    }
+    Ok(ignored)
+}
+
+/// Keep only the best `depth` price levels of each side of `msg`, i.e., the lowest asks
+/// and the highest bids, sorted from the best price.
+///
+/// It is lossy by design, updates of deeper levels are dropped, so books replayed from
+/// truncated updates are only correct for the top levels.
+pub fn truncate_depth(msg: &mut OrderBookMsg, depth: usize) {
+    msg.asks.sort_by(|a, b| a.price.total_cmp(&b.price));
+    msg.bids.sort_by(|a, b| b.price.total_cmp(&a.price));
+    msg.asks.truncate(depth);
+    msg.bids.truncate(depth);
+}
+
+/// Hourly files, e.g., binance.spot.trade.2021-09-02-15-05.json.gz
+pub const DEFAULT_INPUT_PATTERN: &str = "{exchange}.{market}.{msg}.{day}-??-??.json.gz";
+/// Daily files of one symbol each, e.g., binance.spot.trade.BTCUSDT.2021-09-02.json.gz
+pub const PER_SYMBOL_INPUT_PATTERN: &str = "{exchange}.{market}.{msg}.*.{day}.json.gz";
+// Patterns of hourly files ending with it match daily files too
+const HOURLY_SUFFIX: &str = "-??-??.json.gz";
+
+/// How messages are distributed over input files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputGranularity {
+    /// Messages of all symbols in each file, e.g., hourly files of a collector
+    Combined,
+    /// One symbol per file, named `exchange.market_type.msg_type.symbol.day.json.gz`
+    PerSymbol,
+}
+
+/// Components of an input file name, e.g., `binance.spot.trade.2021-09-02-15-05.json.gz`.
+#[derive(Clone, Debug)]
+pub struct FileNameParts {
+    pub exchange: String,
+    pub market_type: MarketType,
+    pub msg_type: MessageType,
+    /// `yyyy-MM-dd`
+    pub day: String,
+}
+
+/// Why a file name doesn't match `exchange.market_type.msg_type.day`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FileNameError {
+    /// The path has no (UTF-8) file name
+    NoFileName(String),
+    /// Fewer than 4 dot-separated components
+    TooFewComponents {
+        file_name: String,
+        components: usize,
+    },
+    UnknownMarketType {
+        file_name: String,
+        market_type: String,
+    },
+    UnknownMsgType {
+        file_name: String,
+        msg_type: String,
+    },
+    /// The 4th component doesn't start with `yyyy-MM-dd`
+    NoDay {
+        file_name: String,
+    },
+}
+
+impl fmt::Display for FileNameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileNameError::NoFileName(path) => write!(f, "{path} has no file name"),
+            FileNameError::TooFewComponents {
+                file_name,
+                components,
+            } => write!(
+                f,
+                "file {file_name} has {components} components, expected >= 4 (exchange.market_type.msg_type.day)"
+            ),
+            FileNameError::UnknownMarketType {
+                file_name,
+                market_type,
+            } => write!(f, "file {file_name} has an unknown market type {market_type}"),
+            FileNameError::UnknownMsgType {
+                file_name,
+                msg_type,
+            } => write!(f, "file {file_name} has an unknown msg type {msg_type}"),
+            FileNameError::NoDay { file_name } => {
+                write!(f, "file {file_name} does not contain a yyyy-MM-dd day")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FileNameError {}
+
+impl FileNameParts {
+    pub fn parse(input_file: &Path) -> Result<FileNameParts, FileNameError> {
+        match input_file
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+        {
+            Some(file_name) => FileNameParts::from_str(file_name),
+            None => Err(FileNameError::NoFileName(input_file.display().to_string())),
+        }
+    }
+}
+
+impl FromStr for FileNameParts {
+    type Err = FileNameError;
+
+    fn from_str(file_name: &str) -> Result<Self, Self::Err> {
+        let v: Vec<&str> = file_name.split('.').collect();
+        if v.len() < 4 {
+            return Err(FileNameError::TooFewComponents {
+                file_name: file_name.to_string(),
+                components: v.len(),
+            });
+        }
+        let market_type =
+            MarketType::from_str(v[1]).map_err(|_| FileNameError::UnknownMarketType {
+                file_name: file_name.to_string(),
+                market_type: v[1].to_string(),
+            })?;
+        let msg_type = MessageType::from_str(v[2]).map_err(|_| FileNameError::UnknownMsgType {
+            file_name: file_name.to_string(),
+            msg_type: v[2].to_string(),
+        })?;
+        let day = match v[3].get(..10) {
+            Some(day) if NaiveDate::parse_from_str(day, "%Y-%m-%d").is_ok() => day,
+            _ => {
+                return Err(FileNameError::NoDay {
+                    file_name: file_name.to_string(),
+                })
+            }
+        };
+        Ok(FileNameParts {
+            exchange: v[0].to_string(),
+            market_type,
+            msg_type,
+            day: day.to_string(),
+        })
+    }
+}
+
+/// Build the glob pattern of input files from `input_pattern`.
+///
+/// `{exchange}`, `{market}`, `{msg}` and `{day}` in `input_pattern` are replaced
+/// with actual values, `MarketType::Unknown` matches all markets.
+pub fn input_glob_pattern(
+    input_dir: &str,
+    input_pattern: &str,
+    exchange: &str,
+    market_type: MarketType,
+    msg_type: MessageType,
+    day: &str,
+) -> String {
+    let market = if market_type == MarketType::Unknown {
+        // MarketType::Unknown means all markets
+        "*".to_string()
+    } else {
+        market_type.to_string()
+    };
+    let file_name = input_pattern
+        .replace("{exchange}", exchange)
+        .replace("{market}", &market)
+        .replace("{msg}", &msg_type.to_string())
+        .replace("{day}", day);
+    format!("{input_dir}/*/{msg_type}/{exchange}/{market}/{file_name}")
+}
+
+/// Expand `glob_pattern`, or filter `input_files`, e.g., of `--files-from`, by the file name
+/// part of it.
+///
+/// A pattern of `.json.gz` files matches plain `.json` files too, and a pattern of hourly
+/// files, e.g., the default one, matches the daily file of the same day too.
+pub fn list_input_files(glob_pattern: &str, input_files: Option<&[PathBuf]>) -> Vec<PathBuf> {
+    let mut paths = if let Some(plain_pattern) = glob_pattern.strip_suffix(".json.gz") {
+        let mut paths = list_matching_files(glob_pattern, input_files);
+        paths.extend(list_matching_files(
+            &format!("{plain_pattern}.json"),
+            input_files,
+        ));
+        paths
+    } else {
+        list_matching_files(glob_pattern, input_files)
+    };
+    if let Some(daily_pattern) = glob_pattern.strip_suffix(HOURLY_SUFFIX) {
+        // archives storing a whole day in one file, e.g., binance.spot.trade.2021-09-02.json.gz
+        paths.extend(list_input_files(
+            &format!("{daily_pattern}.json.gz"),
+            input_files,
+        ));
+    }
+    paths
+}
+
+fn list_matching_files(glob_pattern: &str, input_files: Option<&[PathBuf]>) -> Vec<PathBuf> {
+    if let Some(input_files) = input_files {
+        let file_name_pattern =
+            glob::Pattern::new(glob_pattern.rsplit('/').next().unwrap()).unwrap();
+        input_files
+            .iter()
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|file_name| file_name.to_str())
+                    .is_some_and(|file_name| file_name_pattern.matches(file_name))
+            })
+            .cloned()
+            .collect()
+    } else {
+        glob::glob(glob_pattern)
+            .unwrap()
+            .filter_map(Result::ok)
+            .collect()
+    }
+}
+
+/// Parse the name of an input file, and the symbol in it with `InputGranularity::PerSymbol`,
+/// i.e., the components between the msg_type and the one starting with the day.
+pub fn parse_input_file_name(
+    input_file: &Path,
+    granularity: InputGranularity,
+) -> Result<(FileNameParts, Option<String>), String> {
+    if granularity == InputGranularity::Combined {
+        return FileNameParts::parse(input_file)
+            .map(|parts| (parts, None))
+            .map_err(|err| err.to_string());
+    }
+    let file_name = input_file
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .ok_or_else(|| format!("{} has no file name", input_file.display()))?;
+    let v: Vec<&str> = file_name.split('.').collect();
+    let day_index = (4..v.len())
+        .rev()
+        .find(|i| {
+            v[*i]
+                .get(..10)
+                .is_some_and(|day| NaiveDate::parse_from_str(day, "%Y-%m-%d").is_ok())
+        })
+        .ok_or_else(|| format!("{file_name} has no symbol followed by a day"))?;
+    let parts = FileNameParts::from_str(&[&v[..3], &v[day_index..]].concat().join("."))
+        .map_err(|err| err.to_string())?;
+    // symbols with dots span multiple components
+    let symbol = v[3..day_index].join(".");
+    let symbol = match urlencoding::decode(&symbol) {
+        Ok(decoded) => decoded.into_owned(),
+        Err(_) => symbol,
+    };
+    Ok((parts, Some(symbol)))
+}
+
+/// Distinct combos of exchange, market type and message type having input files of `day`
+/// in `input_dir`, sorted, and errors of malformed file names, which are skipped.
+///
+/// Input files are listed like `crypto-daily-processor` does, i.e., `input_pattern` of
+/// `exchange`, which can be `*`, and each of `msg_types` of all markets, or `input_files`
+/// matching it.
+pub fn discover_combos(
+    input_dir: &str,
+    input_pattern: &str,
+    granularity: InputGranularity,
+    input_files: Option<&[PathBuf]>,
+    exchange: &str,
+    msg_types: &[MessageType],
+    day: &str,
+) -> (Vec<(String, MarketType, MessageType)>, Vec<String>) {
+    let mut combos: Vec<(String, MarketType, MessageType)> = Vec::new();
+    let mut errors: Vec<String> = Vec::new();
+    for msg_type in msg_types {
+        let glob_pattern = input_glob_pattern(
+            input_dir,
+            input_pattern,
+            exchange,
+            MarketType::Unknown,
+            *msg_type,
+            day,
+        );
+        for path in list_input_files(&glob_pattern, input_files) {
+            match parse_input_file_name(&path, granularity) {
+                Ok((parts, _)) => {
+                    let combo = (parts.exchange, parts.market_type, parts.msg_type);
+                    if !combos.contains(&combo) {
+                        combos.push(combo);
+                    }
+                }
+                Err(err) => errors.push(err),
+            }
+        }
+    }
+    combos.sort_by_cached_key(|(exchange, market_type, msg_type)| {
+        (
+            exchange.clone(),
+            market_type.to_string(),
+            msg_type.to_string(),
+        )
+    });
+    (combos, errors)
+}
+
+/// Parse messages of a `.json.gz` stream lazily, without splitting them into files.
+///
+/// Messages of other exchanges, market types or message types are returned as errors.
+pub fn parsed_messages<R: Read>(
+    reader: R,
+    exchange: &str,
+    market_type: MarketType,
+    msg_type: MessageType,
+) -> impl Iterator<Item = Result<ParsedMsg, String>> {
+    let exchange = exchange.to_string();
+    BufReader::new(MultiGzDecoder::new(reader))
+        .lines()
+        .flat_map(move |line| {
+            let parsed = line.map_err(|err| err.to_string()).and_then(|line| {
+                let msg = serde_json::from_str::<Message>(&line)
+                    .map_err(|err| format!("Not a valid message: {err}, {line}"))?;
+                check_combo(&msg, &exchange, market_type, msg_type)?;
+                parse_message(&msg)
+            });
+            match parsed {
+                Ok(messages) => messages.into_iter().map(Ok).collect::<Vec<_>>(),
+                Err(err) => vec![Err(err)],
+            }
+        })
+}
+
+/// The real market type of a symbol, for exchanges whose input files mix market types.
+pub fn get_real_market_type(exchange: &str, market_type: MarketType, symbol: &str) -> MarketType {
+    if exchange == "bitmex" && market_type == MarketType::Unknown {
+        crypto_pair::get_market_type(symbol, "bitmex", None)
+    } else if exchange == "deribit" && symbol.ends_with("-PERPETUAL") {
+        MarketType::InverseSwap
+    } else {
+        market_type
+    }
+}
+
+/// Markets whose `l2_event` messages are not parsed.
+pub fn is_blocked_market(market_type: MarketType) -> bool {
+    market_type == MarketType::QuantoFuture
+        || market_type == MarketType::QuantoSwap
+        || market_type == MarketType::EuropeanOption // TODO: need to figure out how to parse option data
+}
+
+/// Escape a symbol for file names.
+pub fn encode_symbol(symbol: &str) -> String {
+    let new_symbol = urlencoding::encode(symbol).to_string(); // equivalent to urllib.parse.quote_plus()
+    new_symbol.replace('.', "%2E") // escape the dot '.'
+}
+
+fn get_day(timestamp_millis: i64) -> String {
+    let dt = Utc.timestamp_opt(timestamp_millis / 1000, 0).unwrap();
+    dt.format("%Y-%m-%d").to_string()
+}
+
+/// Which output directory a sorted file of `process_day_to()` belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Output {
+    Raw,
+    Parsed,
+}
+
+/// Files of a day keyed by their output directory and relative paths.
+pub type DayFiles<T> = BTreeMap<(Output, String), T>;
+
+/// Line counts of `process_day_to()`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DayStats {
+    pub total_lines: u64,
+    pub duplicated_lines: u64,
+    /// Invalid messages, messages of other combos and messages without a symbol
+    pub error_lines: u64,
+}
+
+/// Split messages of `day` from `inputs` by symbol, deduplicate and sort them, and write
+/// each sorted file to the writer returned by `create()` for its path relative to the
+/// output directory, e.g., `trade/binance/spot/binance.spot.trade.BTCUSDT.2021-09-02.json`.
+///
+/// It is the core of `crypto-daily-processor` with default options, without touching the
+/// filesystem: `inputs` are uncompressed lines of input files in order, `received_at` is in
+/// milliseconds, raw messages are filtered by `received_at` and parsed messages by
+/// `timestamp`, and lines of the same sort key keep their input order. Messages of symbols
+/// which `crypto_pair` can NOT normalize, or which fail to parse, are written to raw files
+/// only. Messages are checked, rewritten and parsed by `check_combo()`, `raw_line()` and
+/// `parse_message()` like in `crypto-daily-processor`.
+pub fn process_day_to<R, W, F>(
+    inputs: Vec<R>,
+    exchange: &str,
+    market_type: MarketType,
+    msg_type: MessageType,
+    day: &str,
+    mut create: F,
+) -> Result<DayStats, String>
+where
+    R: BufRead,
+    W: Write,
+    F: FnMut(Output, &str) -> std::io::Result<W>,
+{
+    let (files, stats) = sorted_files_of_day(inputs, exchange, market_type, msg_type, day)?;
+    for ((output, path), lines) in files {
+        let mut writer = create(output, &path).map_err(|err| format!("{path}, {err}"))?;
+        for line in lines {
+            writeln!(writer, "{line}").map_err(|err| format!("{path}, {err}"))?;
+        }
+        writer.flush().map_err(|err| format!("{path}, {err}"))?;
+    }
+    Ok(stats)
+}
+
+/// `process_day_to()` into memory, returns sorted files keyed by their relative paths.
+pub fn process_day<R: BufRead>(
+    inputs: Vec<R>,
+    exchange: &str,
+    market_type: MarketType,
+    msg_type: MessageType,
+    day: &str,
+) -> Result<(DayFiles<Vec<u8>>, DayStats), String> {
+    let (files, stats) = sorted_files_of_day(inputs, exchange, market_type, msg_type, day)?;
+    let outputs = files
+        .into_iter()
+        .map(|(key, lines)| {
+            let mut bytes = Vec::new();
+            for line in lines {
+                bytes.extend_from_slice(line.as_bytes());
+                bytes.push(b'\n');
+            }
+            (key, bytes)
+        })
+        .collect();
+    Ok((outputs, stats))
+}
+
+/// Sorted lines of each output file of `process_day_to()`.
+fn sorted_files_of_day<R: BufRead>(
+    inputs: Vec<R>,
+    exchange: &str,
+    market_type: MarketType,
+    msg_type: MessageType,
+    day: &str,
+) -> Result<(DayFiles<Vec<String>>, DayStats), String> {
+    let mut stats = DayStats::default();
+    // raw and parsed messages are deduplicated separately, like the raw and parsed jobs
+    let mut visited_raw: HashSet<u64> = HashSet::new();
+    let mut visited_parsed: HashSet<u64> = HashSet::new();
+    let mut files: DayFiles<Vec<(i64, String)>> = BTreeMap::new();
+    for input in inputs {
+        for line in input.lines() {
+            let line = line.map_err(|err| err.to_string())?;
+            stats.total_lines += 1;
+            let msg = match serde_json::from_str::<Message>(&line) {
+                Ok(msg) => msg,
+                Err(_) => {
+                    stats.error_lines += 1;
+                    continue;
+                }
+            };
+            if check_combo(&msg, exchange, market_type, msg_type).is_err() {
+                stats.error_lines += 1;
+                continue;
+            }
+            let symbol = match extract_symbol(exchange, market_type, &msg.json) {
+                Ok(symbol) => symbol,
+                Err(_) => {
+                    stats.error_lines += 1;
+                    continue;
+                }
+            };
+            let real_market_type = get_real_market_type(exchange, msg.market_type, &symbol);
+            let hash = {
+                let mut hasher = DefaultHasher::new();
+                msg.json.hash(&mut hasher);
+                hasher.finish()
+            };
+            let unique = visited_parsed.insert(hash);
+
+            if get_day(msg.received_at as i64) == day && visited_raw.insert(hash) {
+                let path = format!(
+                    "{msg_type}/{exchange}/{real_market_type}/{exchange}.{real_market_type}.{msg_type}.{}.{day}.json",
+                    encode_symbol(&symbol)
+                );
+                let json = raw_line(&msg, &line, real_market_type, false).into_owned();
+                files
+                    .entry((Output::Raw, path))
+                    .or_default()
+                    .push((msg.received_at as i64, json));
+            }
+            if !unique {
+                stats.duplicated_lines += 1;
+                continue;
+            }
+            if msg_type == MessageType::L2Event && is_blocked_market(real_market_type) {
+                continue;
+            }
+            let pair = match crypto_pair::normalize_pair(&symbol, exchange) {
+                Some(pair) => pair,
+                None => continue,
+            };
+            let (base, quote) = pair.split_once('/').unwrap();
+            for message in parse_message(&msg).unwrap_or_default() {
+                let (market_type, timestamp) = (message.market_type(), message.timestamp());
+                if get_day(timestamp) == day {
+                    let path = format!(
+                        "{msg_type}/{exchange}/{market_type}/{exchange}.{market_type}.{msg_type}.{}.{}.{}.{day}.json",
+                        encode_symbol(base),
+                        encode_symbol(quote),
+                        encode_symbol(&symbol)
+                    );
+                    files
+                        .entry((Output::Parsed, path))
+                        .or_default()
+                        .push((timestamp, serde_json::to_string(&message).unwrap()));
+                }
+            }
+        }
+    }
+    let files = files
+        .into_iter()
+        .map(|(key, mut lines)| {
+            // stable, so that lines of the same timestamp keep their order
+            lines.sort_by_key(|x| x.0);
+            (key, lines.into_iter().map(|(_, line)| line).collect())
+        })
+        .collect();
+    Ok((files, stats))
+}
+
+/// `process_day_to()` on the filesystem, reads `.json.gz` or `.json` input files and writes
+/// `.json.xz` files to `output_dir_raw` and `output_dir_parsed`.
+///
+/// It is single-threaded and holds all messages of the day in memory, so it suits small
+/// days and embedders, `crypto-daily-processor` is the scalable version.
+pub fn process_day_files(
+    input_files: &[PathBuf],
+    exchange: &str,
+    market_type: MarketType,
+    msg_type: MessageType,
+    day: &str,
+    output_dir_raw: &Path,
+    output_dir_parsed: &Path,
+) -> Result<DayStats, String> {
+    let mut inputs: Vec<Box<dyn BufRead>> = Vec::new();
+    for input_file in input_files {
+        let f = std::fs::File::open(input_file)
+            .map_err(|err| format!("{}, {err}", input_file.display()))?;
+        if input_file
+            .extension()
+            .is_some_and(|extension| extension == "gz")
+        {
+            inputs.push(Box::new(BufReader::new(MultiGzDecoder::new(f))));
+        } else {
+            inputs.push(Box::new(BufReader::new(f)));
+        }
+    }
+    process_day_to(
+        inputs,
+        exchange,
+        market_type,
+        msg_type,
+        day,
+        |output, path| {
+            let output_dir = match output {
+                Output::Raw => output_dir_raw,
+                Output::Parsed => output_dir_parsed,
+            };
+            let path = output_dir.join(format!("{path}.xz"));
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            Ok(xz2::write::XzEncoder::new(std::fs::File::create(path)?, 6))
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use crypto_market_type::MarketType;
+    use crypto_msg_type::MessageType;
+    use flate2::{write::GzEncoder, Compression};
+
+    #[test]
+    fn test_parsed_messages() {
+        let line = r#"{"exchange":"binance","market_type":"spot","msg_type":"trade","received_at":1630540800000,"json":"{\"stream\":\"btcusdt@trade\",\"data\":{\"e\":\"trade\",\"E\":1630540800000,\"s\":\"BTCUSDT\",\"t\":1016224794,\"p\":\"47269.93000000\",\"q\":\"0.00100000\",\"b\":7500498357,\"a\":7500498400,\"T\":1630540799999,\"m\":true,\"M\":true}}"}"#;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        writeln!(encoder, "{line}").unwrap();
+        writeln!(encoder, "not json").unwrap();
+        let bytes = encoder.finish().unwrap();
+
+        let messages = super::parsed_messages(
+            bytes.as_slice(),
+            "binance",
+            MarketType::Spot,
+            MessageType::Trade,
+        )
+        .collect::<Vec<_>>();
+        assert_eq!(2, messages.len());
+        if let Ok(super::ParsedMsg::Trade(trade)) = &messages[0] {
+            assert_eq!("BTC/USDT", trade.pair);
+            assert_eq!(1630540800000, trade.timestamp);
+        } else {
+            panic!("Expected a trade");
+        }
+        assert!(messages[1].is_err());
+    }
+
+    #[test]
+    fn test_process_day() {
+        let line = |symbol: &str, received_at: i64| {
+            format!(
+                r#"{{"exchange":"binance","market_type":"spot","msg_type":"trade","received_at":{received_at},"json":"{{\"stream\":\"{}@aggTrade\",\"data\":{{\"e\":\"aggTrade\",\"E\":{received_at},\"s\":\"{symbol}\",\"a\":{received_at},\"p\":\"47000.0\",\"q\":\"0.1\",\"f\":0,\"l\":0,\"T\":{received_at},\"m\":true,\"M\":true}}}}"}}"#,
+                symbol.to_lowercase()
+            )
+        };
+        // out of order, with a duplicated line, a trade of the next day and an invalid line
+        let first_hour = [
+            line("BTCUSDT", 1630540802000),
+            line("ETHUSDT", 1630540801000),
+            "not json".to_string(),
+        ]
+        .join("\n");
+        let next_hour = [
+            line("BTCUSDT", 1630540800000),
+            line("BTCUSDT", 1630540802000),
+            line("BTCUSDT", 1630627200000),
+        ]
+        .join("\n");
+
+        let (outputs, stats) = super::process_day(
+            vec![first_hour.as_bytes(), next_hour.as_bytes()],
+            "binance",
+            MarketType::Spot,
+            MessageType::Trade,
+            "2021-09-02",
+        )
+        .unwrap();
+        assert_eq!(
+            super::DayStats {
+                total_lines: 6,
+                duplicated_lines: 1,
+                error_lines: 1,
+            },
+            stats
+        );
+        let paths: Vec<&str> = outputs.keys().map(|(_, path)| path.as_str()).collect();
+        assert_eq!(
+            vec![
+                "trade/binance/spot/binance.spot.trade.BTCUSDT.2021-09-02.json",
+                "trade/binance/spot/binance.spot.trade.ETHUSDT.2021-09-02.json",
+                "trade/binance/spot/binance.spot.trade.BTC.USDT.BTCUSDT.2021-09-02.json",
+                "trade/binance/spot/binance.spot.trade.ETH.USDT.ETHUSDT.2021-09-02.json",
+            ],
+            paths
+        );
+        let raw = String::from_utf8(
+            outputs[&(
+                super::Output::Raw,
+                "trade/binance/spot/binance.spot.trade.BTCUSDT.2021-09-02.json".to_string(),
+            )]
+                .clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            format!(
+                "{}\n{}\n",
+                line("BTCUSDT", 1630540800000),
+                line("BTCUSDT", 1630540802000)
+            ),
+            raw
+        );
+    }
+
+    #[test]
+    fn test_validate_parsed() {
+        let trade = r#"{"exchange":"binance","market_type":"spot","msg_type":"trade","pair":"BTC/USDT","symbol":"BTCUSDT","timestamp":1630540800000,"side":"sell","price":47269.93,"quantity_base":0.001,"quantity_quote":47.26993,"trade_id":"1016224794","json":"{}"}"#;
+        assert!(super::validate_parsed(MessageType::Trade, trade).is_ok());
+        let nan_price = trade.replace("47269.93", "null");
+        assert_eq!(
+            Err("price should be Number".to_string()),
+            super::validate_parsed(MessageType::Trade, &nan_price)
+        );
+        let l2 = r#"{"exchange":"binance","market_type":"spot","msg_type":"l2_event","pair":"BTC/USDT","symbol":"BTCUSDT","timestamp":1630540800000,"snapshot":false,"asks":[[47269.93,0.001,47.26993]],"bids":[[47269.92]],"json":"{}"}"#;
+        assert_eq!(
+            Err("bids should be Orders".to_string()),
+            super::validate_parsed(MessageType::L2Event, l2)
+        );
+    }
+
+    #[test]
+    fn test_reconstruct_l2() {
+        let line = |timestamp: i64, snapshot: bool, asks: &str, bids: &str| {
+            format!(
+                r#"{{"exchange":"bitmex","market_type":"inverse_swap","symbol":"XBTUSD","pair":"BTC/USD","msg_type":"l2_event","timestamp":{timestamp},"snapshot":{snapshot},"asks":{asks},"bids":{bids},"json":"{{}}"}}"#
+            )
+        };
+        let lines = vec![
+            line(500, false, "[[3.0,1.0,3.0]]", "[]"),
+            line(
+                1500,
+                true,
+                "[[3.0,1.0,3.0],[4.0,1.0,4.0]]",
+                "[[1.0,1.0,1.0]]",
+            ),
+            line(1600, false, "[[3.0,0.0,0.0]]", "[[2.0,1.0,2.0]]"),
+            line(3500, false, "[[5.0,1.0,5.0]]", "[]"),
+        ];
+        let mut snapshots = Vec::new();
+        let ignored =
+            super::reconstruct_l2(lines.into_iter(), 1000, |msg| snapshots.push(msg)).unwrap();
+        assert_eq!(1, ignored);
+        // no snapshot at 1000 before the book is synced
+        assert_eq!(
+            vec![2000, 3000],
+            snapshots
+                .iter()
+                .map(|msg| msg.timestamp)
+                .collect::<Vec<i64>>()
+        );
+        let prices = |orders: &[crypto_message::Order]| {
+            orders.iter().map(|order| order.price).collect::<Vec<f64>>()
+        };
+        assert_eq!(vec![4.0], prices(&snapshots[0].asks));
+        assert_eq!(vec![2.0, 1.0], prices(&snapshots[0].bids));
+        assert_eq!(MessageType::L2Snapshot, snapshots[1].msg_type);
+    }
+
+    #[test]
+    fn test_truncate_depth() {
+        let line = r#"{"exchange":"bitmex","market_type":"inverse_swap","symbol":"XBTUSD","pair":"BTC/USD","msg_type":"l2_event","timestamp":1000,"snapshot":false,"asks":[[5.0,1.0,5.0],[3.0,1.0,3.0],[4.0,1.0,4.0]],"bids":[[1.0,1.0,1.0],[2.0,1.0,2.0]],"json":"{}"}"#;
+        let mut msg = serde_json::from_str::<crypto_message::OrderBookMsg>(line).unwrap();
+        super::truncate_depth(&mut msg, 2);
+        let prices = |orders: &[crypto_message::Order]| {
+            orders.iter().map(|order| order.price).collect::<Vec<f64>>()
+        };
+        assert_eq!(vec![3.0, 4.0], prices(&msg.asks));
+        assert_eq!(vec![2.0, 1.0], prices(&msg.bids));
+    }
+
+    #[test]
+    fn test_apply_transforms() {
+        let l2 = r#"{"exchange":"binance","market_type":"spot","asks":[[3.0,1.0,3.0],[2.0,1.0,2.0]],"bids":[[1.0,1.0,1.0]]}"#;
+        let transforms = ["mid_price", "venue"]
+            .iter()
+            .map(|name| super::builtin_transform(name).unwrap())
+            .collect::<Vec<super::Transform>>();
+        let value: serde_json::Value =
+            serde_json::from_str(&super::apply_transforms(l2.to_string(), &transforms)).unwrap();
+        assert_eq!(1.5, value["mid_price"].as_f64().unwrap());
+        assert_eq!("binance.spot", value["venue"].as_str().unwrap());
+        assert!(super::builtin_transform("foo").is_none());
+    }
+
+    #[test]
+    fn test_file_name_parts() {
+        let parts: super::FileNameParts = "binance.spot.trade.2021-09-02-15-05.json.gz"
+            .parse()
+            .unwrap();
+        assert_eq!("binance", parts.exchange);
+        assert_eq!(MarketType::Spot, parts.market_type);
+        assert_eq!(MessageType::Trade, parts.msg_type);
+        assert_eq!("2021-09-02", parts.day);
+
+        let err = "binance.spot.json"
+            .parse::<super::FileNameParts>()
+            .unwrap_err();
+        assert_eq!(
+            super::FileNameError::TooFewComponents {
+                file_name: "binance.spot.json".to_string(),
+                components: 3
+            },
+            err
+        );
+        assert!(err.to_string().contains("has 3 components, expected >= 4"));
+        assert!(matches!(
+            "binance.spot.foo.2021-09-02-15-05.json.gz".parse::<super::FileNameParts>(),
+            Err(super::FileNameError::UnknownMsgType { .. })
+        ));
+        assert!(matches!(
+            "binance.spot.trade.2021-09.json.gz".parse::<super::FileNameParts>(),
+            Err(super::FileNameError::NoDay { .. })
+        ));
+    }
+
+    #[test]
+    fn test_discover_combos() {
+        let input_dir =
+            std::env::temp_dir().join(format!("discover_combos_{}", std::process::id()));
+        for (dir, file_name) in [
+            (
+                "trade/binance/spot",
+                "binance.spot.trade.2021-09-02-15-05.json.gz",
+            ),
+            (
+                "trade/binance/spot",
+                "binance.spot.trade.2021-09-02-16-05.json.gz",
+            ),
+            (
+                "l2_event/binance/linear_swap",
+                "binance.linear_swap.l2_event.2021-09-02-00-05.json",
+            ),
+            (
+                "trade/binance/spot",
+                "binance.spot.trade.2021-09-03-00-05.json.gz",
+            ),
+        ] {
+            let dir = input_dir.join("host").join(dir);
+            std::fs::create_dir_all(dir.as_path()).unwrap();
+            std::fs::write(dir.join(file_name), "").unwrap();
+        }
+        let discover_combos = || {
+            super::discover_combos(
+                input_dir.to_str().unwrap(),
+                super::DEFAULT_INPUT_PATTERN,
+                super::InputGranularity::Combined,
+                None,
+                "*",
+                &[MessageType::Trade, MessageType::L2Event],
+                "2021-09-02",
+            )
+        };
+        let combos = vec![
+            (
+                "binance".to_string(),
+                MarketType::LinearSwap,
+                MessageType::L2Event,
+            ),
+            ("binance".to_string(), MarketType::Spot, MessageType::Trade),
+        ];
+        assert_eq!((combos.clone(), Vec::new()), discover_combos());
+
+        // malformed names are skipped
+        let dir = input_dir.join("host/trade/binance/spot");
+        std::fs::write(dir.join("binance.foo.trade.2021-09-02-17-05.json.gz"), "").unwrap();
+        let (discovered, errors) = discover_combos();
+        assert_eq!(combos, discovered);
+        assert_eq!(1, errors.len());
+        std::fs::remove_dir_all(input_dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_input_file_name() {
+        use super::{parse_input_file_name, InputGranularity};
+        use std::path::Path;
+        let (parts, symbol) = parse_input_file_name(
+            Path::new("in/binance.spot.trade.BTCUSDT.2021-09-02.json.gz"),
+            InputGranularity::PerSymbol,
+        )
+        .unwrap();
+        assert_eq!(
+            ("binance", "2021-09-02"),
+            (parts.exchange.as_str(), parts.day.as_str())
+        );
+        assert_eq!(Some("BTCUSDT".to_string()), symbol);
+        // percent-encoded and dotted symbols
+        let (_, symbol) = parse_input_file_name(
+            Path::new("okx.inverse_swap.trade.BTC-USD.SWAP.2021-09-02.json"),
+            InputGranularity::PerSymbol,
+        )
+        .unwrap();
+        assert_eq!(Some("BTC-USD.SWAP".to_string()), symbol);
+        let (_, symbol) = parse_input_file_name(
+            Path::new("deribit.european_option.trade.BTC%2F1.2021-09-02.json.gz"),
+            InputGranularity::PerSymbol,
+        )
+        .unwrap();
+        assert_eq!(Some("BTC/1".to_string()), symbol);
+        // hourly files have no symbol
+        assert!(parse_input_file_name(
+            Path::new("binance.spot.trade.2021-09-02-15-05.json.gz"),
+            InputGranularity::PerSymbol,
+        )
+        .is_err());
+        let (parts, symbol) = parse_input_file_name(
+            Path::new("binance.spot.trade.2021-09-02-15-05.json.gz"),
+            InputGranularity::Combined,
+        )
+        .unwrap();
+        assert_eq!("2021-09-02", parts.day);
+        assert_eq!(None, symbol);
+    }
+
+    #[test]
+    fn test_input_glob_pattern() {
+        assert_eq!(
+            "/data/*/trade/binance/spot/binance.spot.trade.2021-09-02-??-??.json.gz",
+            super::input_glob_pattern(
+                "/data",
+                super::DEFAULT_INPUT_PATTERN,
+                "binance",
+                MarketType::Spot,
+                MessageType::Trade,
+                "2021-09-02"
+            )
+        );
+        assert_eq!(
+            "/data/*/trade/bitmex/*/bitmex.*.trade.2021-09-02.json.gz",
+            super::input_glob_pattern(
+                "/data",
+                "{exchange}.{market}.{msg}.{day}.json.gz",
+                "bitmex",
+                MarketType::Unknown,
+                MessageType::Trade,
+                "2021-09-02"
+            )
+        );
+        // the default pattern matches daily files too
+        let input_files: Vec<std::path::PathBuf> = [
+            "binance.spot.trade.2021-09-02-05-00.json.gz",
+            "binance.spot.trade.2021-09-02.json.gz",
+            "binance.spot.trade.2021-09-03.json.gz",
+        ]
+        .iter()
+        .map(std::path::PathBuf::from)
+        .collect();
+        assert_eq!(
+            2,
+            super::list_input_files(
+                "/data/*/trade/binance/spot/binance.spot.trade.2021-09-02-??-??.json.gz",
+                Some(&input_files)
+            )
+            .len()
+        );
+    }
+}